@@ -0,0 +1,90 @@
+//! Torrent content location on disk
+
+use crate::common::database::DatabaseRow;
+use crate::common::fastresume::Fastresume;
+use crate::common::metadata::Metadata;
+use std::error::Error;
+use std::path::PathBuf;
+
+/// A file of the torrent content, resolved to its location on disk
+#[derive(Debug, Clone)]
+pub struct ContentFile {
+    /// Absolute path of the file on disk
+    pub path: PathBuf,
+    /// Expected file length, in bytes
+    pub length: u64,
+    /// BEP 47 padding file, which is never written to disk
+    pub padding: bool,
+}
+
+/// Torrent data needed to locate its content on disk
+pub struct TorrentContent {
+    /// Decoded libtorrent resume data
+    pub resume_data: Fastresume,
+    /// Decoded torrent metadata
+    pub metadata: Metadata,
+    /// Torrent name, preferring the name given in qB
+    pub name: String,
+    /// Effective save path of the torrent content
+    pub save_path: String,
+    /// Content files, in piece order
+    pub files: Vec<ContentFile>,
+}
+
+impl TorrentContent {
+    /// Decodes a torrent and resolves the location of its content files
+    ///
+    /// The libtorrent `save_path` is used as the effective save path, since it is always defined
+    /// and is the path libtorrent actually reads from. Renamed files in `mapped_files` take
+    /// precedence over the paths in the metadata. qB records content layout changes as renamed
+    /// files, but a "NoSubfolder" layout without `mapped_files` still strips the root folder.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let content = TorrentContent::resolve(&torrent)?;
+    /// for file in content.files {
+    ///     println!("{:?} ({} bytes)", file.path, file.length);
+    /// }
+    /// ```
+    pub fn resolve(torrent: &DatabaseRow) -> Result<TorrentContent, Box<dyn Error>> {
+        let Some(metadata) = &torrent.metadata else {
+            return Err("torrent has no metadata".into());
+        };
+
+        let resume_data: Fastresume = serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?;
+        let metadata = Metadata::from_bytes(metadata)?;
+        let save_path = String::from_utf8(resume_data.save_path.clone())?;
+
+        let mapped_files = resume_data.mapped_files.clone().unwrap_or_default();
+        let strip_root_folder = mapped_files.is_empty() && torrent.content_layout == "NoSubfolder";
+
+        let mut files = Vec::new();
+        for (index, file) in metadata.files()?.into_iter().enumerate() {
+            let mut path = PathBuf::from(&save_path);
+            match mapped_files.get(index).filter(|mapped| !mapped.is_empty()) {
+                Some(mapped) => path.push(mapped),
+                None if strip_root_folder && file.path.len() > 1 => path.extend(&file.path[1..]),
+                None => path.extend(&file.path),
+            }
+
+            files.push(ContentFile {
+                path,
+                length: file.length,
+                padding: file.padding,
+            });
+        }
+
+        let name = torrent
+            .name
+            .clone()
+            .unwrap_or_else(|| metadata.info.name.clone());
+
+        Ok(TorrentContent {
+            resume_data,
+            metadata,
+            name,
+            save_path,
+            files,
+        })
+    }
+}
@@ -1,16 +1,20 @@
 //! SQLite file data structures
 
+use crate::common::fastresume::Fastresume;
 use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
 
 /// qB SQLite database row data
 ///
-/// Each field corresponds to a column in the "torrents" database table
-#[derive(Serialize, Deserialize, Debug)]
+/// Each field corresponds to a column in the "torrents" database table. Columns that are missing
+/// from older schema versions get the value qB fills them with when it upgrades the database.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DatabaseRow {
     id: u64,
     /// Torrent hash
     pub torrent_id: String,
-    queue_position: i64,
+    /// Position in the download queue, -1 if the torrent is not queued
+    pub queue_position: i64,
     /// Torrent name, only used on re-named torrents
     pub name: Option<String>,
     /// Torrent category
@@ -22,6 +26,8 @@ pub struct DatabaseRow {
     /// This is absent if the torrent is in 'AutoTMM' mode
     pub target_save_path: Option<String>,
     /// Download path, used for incomplete download directory
+    ///
+    /// Added in schema version 2
     pub download_path: Option<String>,
     /// Torrent content layout, whether sub-folders are created or not
     pub content_layout: String,
@@ -30,45 +36,198 @@ pub struct DatabaseRow {
     /// Time limit for seeding
     pub seeding_time_limit: i64,
     /// Inactivity time limit for seeding
+    ///
+    /// Added in schema version 5
+    #[serde(default = "default_inactive_seeding_time_limit")]
     pub inactive_seeding_time_limit: i64,
     /// Action to take when share limit is reached
+    ///
+    /// Added in schema version 7
     pub share_limit_action: Option<String>,
     /// Prioritize outer (first and last) pieces
     pub has_outer_pieces_priority: i64,
     /// Torrent is seeding
     pub has_seed_status: i64,
-    operating_mode: String,
-    stopped: i64,
+    /// Torrent operating mode, either "AutoManaged" or "Forced"
+    pub operating_mode: String,
+    /// Torrent is stopped (paused)
+    pub stopped: i64,
     /// Stop condition for torrents
+    ///
+    /// Added in schema version 3
+    #[serde(default = "default_stop_condition")]
     pub stop_condition: String,
     /// Binary blob containing libtorrent fastresume data
     ///
     /// See "common::fastresume::Fastresume" for deserialized contents
+    #[serde(with = "serde_bytes")]
     pub libtorrent_resume_data: Vec<u8>,
     /// Binary blob containing metadata
-    pub metadata: Vec<u8>,
+    ///
+    /// This is NULL for torrents added by magnet link until the metadata is downloaded
+    #[serde(default, with = "serde_bytes")]
+    pub metadata: Option<Vec<u8>>,
 }
 
-/// A subset of data for save path operations
-#[derive(Serialize, Deserialize, Debug)]
-pub struct PathData {
-    /// Torrent database id
-    pub id: u64,
-    /// Torrent hash
-    pub torrent_id: String,
-    /// Save path for torrent content
-    pub target_save_path: Option<String>,
-    /// Binary blob containing fastresume data
-    pub libtorrent_resume_data: Vec<u8>,
+/// Value qB uses for databases without the `inactive_seeding_time_limit` column
+fn default_inactive_seeding_time_limit() -> i64 {
+    -2
 }
 
-/// A subset of data for operations on libtorrent_resume_data
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LibtorrentResumeData {
-    /// Torrent database row id
-    pub id: u64,
-    /// Torrent hash id
-    pub torrent_id: String,
-    ///Binary blob containing fastresume data
-    pub libtorrent_resume_data: Vec<u8>,
+/// Value qB uses for databases without the `stop_condition` column
+fn default_stop_condition() -> String {
+    String::from("None")
+}
+
+impl DatabaseRow {
+    /// Builds a database row from the contents of a .fastresume/.torrent file pair
+    ///
+    /// qB stores its own data in `qBt-*` keys of the .fastresume file, whereas the database keeps
+    /// them in separate columns. The keys are moved into their columns and stripped from the
+    /// fastresume blob, which is what qB would store in `libtorrent_resume_data`.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let resume_data = serde_bencode::from_bytes::<Fastresume>(&fastresume_bytes)?;
+    /// let row = DatabaseRow::from_fastresume(torrent_id, resume_data, torrent_bytes, -1)?;
+    /// ```
+    pub fn from_fastresume(
+        torrent_id: String,
+        mut resume_data: Fastresume,
+        metadata: Option<Vec<u8>>,
+        queue_position: i64,
+    ) -> Result<DatabaseRow, Box<dyn Error>> {
+        // Empty strings are stored as NULL in the database
+        let non_empty = |value: Option<String>| value.filter(|v| !v.is_empty());
+
+        let category = match resume_data.qbt_category.take() {
+            Some(category) => non_empty(Some(String::from_utf8(category)?)),
+            None => None,
+        };
+        let content_layout = match resume_data.qbt_content_layout.take() {
+            Some(content_layout) => String::from_utf8(content_layout)?,
+            None => String::from("Original"),
+        };
+
+        // Tags are a list in the fastresume file, but comma-separated in the database
+        let tags = resume_data
+            .qbt_tags
+            .take()
+            .filter(|tags| !tags.is_empty())
+            .map(|tags| tags.join(","));
+
+        // Paths are absent from the .fastresume if the torrent is in "AutoTMM" mode
+        let target_save_path = non_empty(resume_data.qbt_save_path.take());
+        let download_path = non_empty(resume_data.qbt_download_path.take());
+        let download_path = target_save_path.as_ref().and(download_path);
+
+        // qB derives these from the libtorrent flags when loading .fastresume files
+        let paused = resume_data.paused != 0;
+        let auto_managed = resume_data.auto_managed != 0;
+        let operating_mode = match paused || auto_managed {
+            true => String::from("AutoManaged"),
+            false => String::from("Forced"),
+        };
+        let stopped = (paused && !auto_managed) as i64;
+
+        let row = DatabaseRow {
+            id: 0,
+            torrent_id,
+            queue_position,
+            name: non_empty(resume_data.qbt_name.take()),
+            category,
+            tags,
+            target_save_path,
+            download_path,
+            content_layout,
+            ratio_limit: resume_data.qbt_ratio_limit.take().unwrap_or(-2000),
+            seeding_time_limit: resume_data.qbt_seeding_time_limit.take().unwrap_or(-2),
+            inactive_seeding_time_limit: resume_data
+                .qbt_inactive_seeding_time_limit
+                .take()
+                .unwrap_or(-2),
+            share_limit_action: Some(
+                resume_data
+                    .qbt_share_limit_action
+                    .take()
+                    .unwrap_or_else(|| String::from("Default")),
+            ),
+            has_outer_pieces_priority: resume_data
+                .qbt_first_last_piece_priority
+                .take()
+                .unwrap_or(0),
+            has_seed_status: resume_data.qbt_seed_status.take().unwrap_or(0),
+            operating_mode,
+            stopped,
+            stop_condition: resume_data
+                .qbt_stop_condition
+                .take()
+                .unwrap_or_else(|| String::from("None")),
+            libtorrent_resume_data: serde_bencode::to_bytes(&resume_data)?,
+            metadata,
+        };
+
+        Ok(row)
+    }
+
+    /// Builds the contents of a .fastresume file from a database row
+    ///
+    /// This is the reverse of `DatabaseRow::from_fastresume`. qB-specific data are stored in the
+    /// database columns, so they are added back to the fastresume blob as `qBt-*` keys.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let resume_data = row.to_fastresume()?;
+    /// fs::write(fastresume_file, serde_bencode::to_bytes(&resume_data)?)?;
+    /// ```
+    pub fn to_fastresume(&self) -> Result<Fastresume, Box<dyn Error>> {
+        let mut resume_data =
+            serde_bencode::from_bytes::<Fastresume>(&self.libtorrent_resume_data)?;
+
+        resume_data.qbt_category = Some(self.category.clone().unwrap_or_default().into_bytes());
+        resume_data.qbt_content_layout = Some(self.content_layout.clone().into_bytes());
+        resume_data.qbt_first_last_piece_priority = Some(self.has_outer_pieces_priority);
+        resume_data.qbt_inactive_seeding_time_limit = Some(self.inactive_seeding_time_limit);
+        resume_data.qbt_name = Some(self.name.clone().unwrap_or_default());
+        resume_data.qbt_ratio_limit = Some(self.ratio_limit);
+        resume_data.qbt_seed_status = Some(self.has_seed_status);
+        resume_data.qbt_seeding_time_limit = Some(self.seeding_time_limit);
+        resume_data.qbt_share_limit_action =
+            Some(self.share_limit_action.clone().unwrap_or_default());
+        resume_data.qbt_stop_condition = Some(self.stop_condition.clone());
+
+        // Paths are absent from the .fastresume if the torrent is in "AutoTMM" mode
+        if self.target_save_path.is_some() {
+            resume_data.qbt_download_path = Some(self.download_path.clone().unwrap_or_default());
+            resume_data.qbt_save_path = self.target_save_path.clone();
+        }
+
+        // Tags are comma-separated in the database, but a list in the fastresume file. If there
+        // are no tags we need to set an empty list
+        resume_data.qbt_tags = Some(match &self.tags {
+            Some(tags) => tags.split(',').map(|s| s.to_string()).collect(),
+            None => vec![],
+        });
+
+        Ok(resume_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{self, SEEDING, SEEDING_ID};
+
+    #[test]
+    fn fastresume_round_trip() {
+        let row = test_util::torrent(SEEDING_ID, SEEDING, None);
+
+        assert_eq!(row.category.as_deref(), Some("tv"));
+        assert_eq!(row.tags.as_deref(), Some("hd,keep"));
+        assert_eq!(row.target_save_path.as_deref(), Some("/data/tv"));
+        assert_eq!(row.download_path, None);
+        assert_eq!(
+            serde_bencode::to_bytes(&row.to_fastresume().unwrap()).unwrap(),
+            SEEDING
+        );
+    }
 }
@@ -1,21 +1,30 @@
 //! Fastresume file data structures
 
+use serde_bencode::value::Value;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// Fastresume file data
+///
+/// Keys that are not modelled by a field, such as those added by newer libtorrent or qB versions,
+/// are kept in `extra`. Bencode dictionaries are always sorted by key, so decoding and re-encoding
+/// a fastresume file yields the exact same bytes.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Fastresume {
     active_time: i64,
-    added_time: i64,
+    /// Time the torrent was added, in seconds since the Unix epoch
+    pub added_time: i64,
     #[serde(with = "serde_bytes")]
     allocation: Vec<u8>,
     apply_ip_filter: u8,
-    auto_managed: u8,
+    /// Torrent is managed by the libtorrent queue
+    pub auto_managed: u8,
     #[serde(default, with = "serde_bytes")]
     banned_peers: Option<Vec<u8>>,
     #[serde(default, with = "serde_bytes")]
     banned_peers6: Option<Vec<u8>>,
-    completed_time: i64,
+    /// Time the torrent was completed, in seconds since the Unix epoch, 0 if incomplete
+    pub completed_time: i64,
     disable_dht: u8,
     disable_lsd: u8,
     disable_pex: u8,
@@ -28,16 +37,19 @@ pub struct Fastresume {
     finished_time: i64,
     httpseeds: Option<Vec<String>>,
     i2p: Option<u8>,
+    /// v1 info hash (SHA-1), all zeros for v2-only torrents
     #[serde(with = "serde_bytes", rename = "info-hash")]
-    info_hash: Vec<u8>,
+    pub info_hash: Vec<u8>,
+    /// v2 info hash (SHA-256), absent or all zeros for v1-only torrents
     #[serde(default, with = "serde_bytes", rename = "info-hash2")]
-    info_hash2: Option<Vec<u8>>,
+    pub info_hash2: Option<Vec<u8>>,
     last_download: i64,
     last_seen_complete: i64,
     last_upload: i64,
     #[serde(with = "serde_bytes", rename = "libtorrent-version")]
     libtorrent_version: Vec<u8>,
-    mapped_files: Option<Vec<String>>,
+    /// Renamed file paths, relative to the save path, empty for files that were not renamed
+    pub mapped_files: Option<Vec<String>>,
     max_connections: i64,
     max_uploads: i64,
     #[serde(default, with = "serde_bytes")]
@@ -45,15 +57,17 @@ pub struct Fastresume {
     num_complete: u64,
     num_downloaded: u64,
     num_incomplete: u64,
-    paused: u8,
+    /// Torrent is paused
+    pub paused: u8,
     #[serde(default, with = "serde_bytes")]
     peers: Option<Vec<u8>>,
     #[serde(default, with = "serde_bytes")]
     peers6: Option<Vec<u8>>,
     #[serde(default, with = "serde_bytes")]
     piece_priority: Option<Vec<u8>>,
+    /// Downloaded pieces, one byte per piece with the lowest bit set if the piece is complete
     #[serde(with = "serde_bytes")]
-    pieces: Vec<u8>,
+    pub pieces: Vec<u8>,
     /// Torrent category
     #[serde(default, with = "serde_bytes", rename = "qBt-category")]
     pub qbt_category: Option<Vec<u8>>,
@@ -104,8 +118,10 @@ pub struct Fastresume {
     share_mode: u8,
     stop_when_ready: u8,
     super_seeding: u8,
-    total_downloaded: u64,
-    total_uploaded: u64,
+    /// Total number of bytes downloaded
+    pub total_downloaded: u64,
+    /// Total number of bytes uploaded
+    pub total_uploaded: u64,
     /// Trackers list for torrent
     pub trackers: Vec<Vec<String>>,
     unfinished: Option<Vec<UnfinishedPiece>>,
@@ -113,6 +129,9 @@ pub struct Fastresume {
     upload_rate_limit: i64,
     #[serde(rename = "url-list")]
     url_list: Vec<String>,
+    /// Unmodelled keys, preserved as-is
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
 /// Fastresume unfinished piece data
@@ -121,4 +140,54 @@ pub struct UnfinishedPiece {
     #[serde(with = "serde_bytes")]
     bitmask: Vec<u8>,
     piece: i64,
+    /// Unmodelled keys, preserved as-is
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{DOWNLOADING, MAGNET, SEEDING};
+
+    fn round_trip(bytes: &[u8]) -> Fastresume {
+        let resume_data: Fastresume = serde_bencode::from_bytes(bytes).unwrap();
+        assert_eq!(serde_bencode::to_bytes(&resume_data).unwrap(), bytes);
+
+        resume_data
+    }
+
+    #[test]
+    fn round_trip_keeps_unknown_top_level_keys() {
+        let resume_data = round_trip(SEEDING);
+
+        let keys: Vec<&str> = resume_data.extra.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["trackers_state", "zz_future_list"]);
+        let Value::List(list) = &resume_data.extra["zz_future_list"] else {
+            panic!("zz_future_list is not a list");
+        };
+        assert_eq!(list.len(), 3);
+        assert!(matches!(&list[1], Value::Dict(dict) if dict.contains_key(b"a".as_slice())));
+    }
+
+    #[test]
+    fn round_trip_keeps_unknown_unfinished_keys() {
+        let resume_data = round_trip(DOWNLOADING);
+
+        let unfinished = resume_data.unfinished.as_ref().unwrap();
+        assert_eq!(unfinished.len(), 2);
+        let keys: Vec<&str> = unfinished[0].extra.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["adler32", "blocks"]);
+        assert!(unfinished[1].extra.is_empty());
+        assert!(resume_data.qbt_name.is_none());
+        assert!(resume_data.qbt_share_limit_action.is_none());
+    }
+
+    #[test]
+    fn round_trip_magnet_without_metadata() {
+        let resume_data = round_trip(MAGNET);
+
+        assert!(resume_data.pieces.is_empty());
+        assert_eq!(resume_data.name.as_deref(), Some(b"Some.Magnet".as_slice()));
+    }
 }
@@ -0,0 +1,415 @@
+//! Torrent metadata (.torrent file) data structures
+
+use serde_bencode::value::Value;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// Torrent metadata, as stored in the `metadata` database column or a .torrent file
+///
+/// Keys that are not modelled by a field, such as `piece layers` for v2 torrents, are kept in
+/// `extra`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Metadata {
+    /// Tracker URL
+    pub announce: Option<String>,
+    /// Tracker URLs, grouped in tiers
+    #[serde(rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    /// Free-form comment
+    pub comment: Option<String>,
+    /// Program that created the torrent
+    #[serde(rename = "created by")]
+    pub created_by: Option<String>,
+    /// Creation time, in seconds since the Unix epoch
+    #[serde(rename = "creation date")]
+    pub creation_date: Option<i64>,
+    /// Info dictionary, describing the torrent content
+    pub info: Info,
+    /// Unmodelled keys, preserved as-is
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// Torrent info dictionary
+///
+/// v1 torrents describe their content with `length` (single-file) or `files` (multi-file), v2
+/// torrents with `file_tree`. Hybrid torrents have both.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Info {
+    /// v2 file tree
+    ///
+    /// Nested dictionaries of path components. Each file is a dictionary with an empty key,
+    /// holding its `length` and `pieces root`. See `Metadata::files` for a flattened list.
+    #[serde(rename = "file tree")]
+    pub file_tree: Option<Value>,
+    /// Files of a multi-file v1 torrent
+    pub files: Option<Vec<File>>,
+    /// Length of a single-file v1 torrent, in bytes
+    pub length: Option<u64>,
+    /// Metadata version, 2 for v2 and hybrid torrents
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<u8>,
+    /// Torrent name, used as the file name or root folder name
+    pub name: String,
+    /// Number of bytes per piece
+    #[serde(rename = "piece length")]
+    pub piece_length: u64,
+    /// Concatenated 20-byte SHA-1 hashes of each piece, absent for v2-only torrents
+    #[serde(default, with = "serde_bytes")]
+    pub pieces: Option<Vec<u8>>,
+    /// Private flag, peers are only obtained from the trackers
+    pub private: Option<u8>,
+    /// Source tag, commonly used by private trackers
+    pub source: Option<String>,
+    /// Unmodelled keys, preserved as-is
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// File entry of a multi-file v1 torrent
+#[derive(Serialize, Deserialize, Debug)]
+pub struct File {
+    /// File attributes, `p` marks a BEP 47 padding file
+    pub attr: Option<String>,
+    /// File length, in bytes
+    pub length: u64,
+    /// Path components, relative to the torrent root folder
+    pub path: Vec<String>,
+    /// Unmodelled keys, preserved as-is
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// A file of the torrent content, independent of the metadata version
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentFile {
+    /// Path components, including the torrent root folder for multi-file torrents
+    pub path: Vec<String>,
+    /// File length, in bytes
+    pub length: u64,
+    /// BEP 47 padding file, which is never written to disk
+    pub padding: bool,
+}
+
+impl Metadata {
+    /// Decodes bencoded torrent metadata
+    ///
+    /// ## Example
+    /// ```rs
+    /// let metadata = Metadata::from_bytes(&torrent.metadata)?;
+    /// println!("{} has {} files", metadata.info.name, metadata.files()?.len());
+    /// ```
+    pub fn from_bytes(bytes: &[u8]) -> Result<Metadata, Box<dyn Error>> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    /// Returns the files of the torrent content, in piece order
+    ///
+    /// The v1 file list is preferred since it includes the padding files that v1 piece hashes
+    /// are calculated over. v2-only torrents use the file tree, which is sorted by path.
+    pub fn files(&self) -> Result<Vec<TorrentFile>, Box<dyn Error>> {
+        let info = &self.info;
+
+        if let Some(length) = info.length {
+            return Ok(vec![TorrentFile {
+                path: vec![info.name.clone()],
+                length,
+                padding: false,
+            }]);
+        }
+
+        if let Some(files) = &info.files {
+            let files = files
+                .iter()
+                .map(|file| TorrentFile {
+                    path: [vec![info.name.clone()], file.path.clone()].concat(),
+                    length: file.length,
+                    padding: file.attr.as_deref().is_some_and(|attr| attr.contains('p')),
+                })
+                .collect();
+            return Ok(files);
+        }
+
+        let Some(file_tree) = &info.file_tree else {
+            return Err("metadata has no files".into());
+        };
+
+        // A v2 torrent with a single file has no root folder
+        let mut files = Vec::new();
+        walk_file_tree(file_tree, &mut Vec::new(), &mut files)?;
+        if files.len() > 1 || files.first().is_some_and(|file| file.path.len() > 1) {
+            for file in &mut files {
+                file.path.insert(0, info.name.clone());
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Returns the total size of the torrent content, excluding padding files
+    pub fn total_size(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(self
+            .files()?
+            .iter()
+            .filter(|file| !file.padding)
+            .map(|file| file.length)
+            .sum())
+    }
+
+    /// Returns all tracker URLs, grouped in tiers
+    ///
+    /// `announce-list` takes precedence over `announce`, as described in BEP 12.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        match (&self.announce_list, &self.announce) {
+            (Some(announce_list), _) if !announce_list.is_empty() => announce_list.clone(),
+            (_, Some(announce)) => vec![vec![announce.clone()]],
+            _ => vec![],
+        }
+    }
+
+    /// Returns true if the torrent is private
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+}
+
+/// Flattens a v2 file tree into a list of files, sorted by path
+fn walk_file_tree(
+    node: &Value,
+    path: &mut Vec<String>,
+    files: &mut Vec<TorrentFile>,
+) -> Result<(), Box<dyn Error>> {
+    let Value::Dict(entries) = node else {
+        return Err("invalid file tree".into());
+    };
+
+    let mut entries: Vec<_> = entries.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (name, child) in entries {
+        // Files are marked by a dictionary with an empty key
+        if name.is_empty() {
+            let Value::Dict(properties) = child else {
+                return Err("invalid file tree".into());
+            };
+            let length = match properties.get("length".as_bytes()) {
+                Some(Value::Int(length)) => *length as u64,
+                _ => return Err("file tree entry has no length".into()),
+            };
+            files.push(TorrentFile {
+                path: path.clone(),
+                length,
+                padding: false,
+            });
+            continue;
+        }
+
+        path.push(String::from_utf8(name.clone())?);
+        walk_file_tree(child, path, files)?;
+        path.pop();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    fn bytes(value: &str) -> Value {
+        Value::Bytes(value.as_bytes().to_vec())
+    }
+
+    fn v1_file(path: &[&str], length: i64, attr: Option<&str>) -> Value {
+        let mut entries = vec![
+            ("length", Value::Int(length)),
+            (
+                "path",
+                Value::List(path.iter().map(|name| bytes(name)).collect()),
+            ),
+        ];
+        if let Some(attr) = attr {
+            entries.push(("attr", bytes(attr)));
+        }
+        dict(entries)
+    }
+
+    fn v2_file(length: i64) -> Value {
+        dict(vec![(
+            "",
+            dict(vec![
+                ("length", Value::Int(length)),
+                ("pieces root", Value::Bytes(vec![0; 32])),
+            ]),
+        )])
+    }
+
+    fn encode(info: Vec<(&str, Value)>) -> Vec<u8> {
+        let mut info = info;
+        info.push(("piece length", Value::Int(16384)));
+        let metadata = dict(vec![
+            ("announce", bytes("http://tracker.example.org/announce")),
+            ("info", dict(info)),
+        ]);
+        serde_bencode::to_bytes(&metadata).unwrap()
+    }
+
+    fn paths(metadata: &Metadata) -> Vec<String> {
+        metadata
+            .files()
+            .unwrap()
+            .iter()
+            .map(|file| file.path.join("/"))
+            .collect()
+    }
+
+    #[test]
+    fn single_file_v1() {
+        let metadata = Metadata::from_bytes(&encode(vec![
+            ("name", bytes("Movie.mkv")),
+            ("length", Value::Int(40000)),
+            ("pieces", Value::Bytes(vec![0; 60])),
+            ("private", Value::Int(1)),
+        ]))
+        .unwrap();
+
+        assert_eq!(paths(&metadata), ["Movie.mkv"]);
+        assert_eq!(metadata.total_size().unwrap(), 40000);
+        assert_eq!(
+            metadata.trackers(),
+            [["http://tracker.example.org/announce"]]
+        );
+        assert!(metadata.is_private());
+        assert_eq!(metadata.info.pieces.as_ref().map(Vec::len), Some(60));
+    }
+
+    #[test]
+    fn multi_file_v1_with_padding() {
+        let metadata = Metadata::from_bytes(&encode(vec![
+            ("name", bytes("Show.S01")),
+            (
+                "files",
+                Value::List(vec![
+                    v1_file(&["E01.mkv"], 10000, None),
+                    v1_file(&[".pad", "6384"], 6384, Some("p")),
+                    v1_file(&["Subs", "E01.srt"], 1000, Some("x")),
+                ]),
+            ),
+            ("pieces", Value::Bytes(vec![0; 40])),
+        ]))
+        .unwrap();
+
+        let files = metadata.files().unwrap();
+        assert_eq!(
+            paths(&metadata),
+            [
+                "Show.S01/E01.mkv",
+                "Show.S01/.pad/6384",
+                "Show.S01/Subs/E01.srt"
+            ]
+        );
+        assert_eq!(
+            files.iter().map(|file| file.padding).collect::<Vec<_>>(),
+            [false, true, false]
+        );
+        assert_eq!(metadata.total_size().unwrap(), 11000);
+        assert!(!metadata.is_private());
+    }
+
+    #[test]
+    fn v2_file_tree_is_sorted_by_path() {
+        let metadata = Metadata::from_bytes(&encode(vec![
+            ("name", bytes("Album")),
+            ("meta version", Value::Int(2)),
+            (
+                "file tree",
+                dict(vec![
+                    ("02.flac", v2_file(2000)),
+                    ("01.flac", v2_file(1000)),
+                    ("Art", dict(vec![("cover.jpg", v2_file(50))])),
+                ]),
+            ),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            paths(&metadata),
+            ["Album/01.flac", "Album/02.flac", "Album/Art/cover.jpg"]
+        );
+        assert_eq!(metadata.total_size().unwrap(), 3050);
+        assert_eq!(metadata.info.meta_version, Some(2));
+        assert!(metadata.info.pieces.is_none());
+    }
+
+    #[test]
+    fn v2_single_file_has_no_root_folder() {
+        let metadata = Metadata::from_bytes(&encode(vec![
+            ("name", bytes("Movie.mkv")),
+            ("meta version", Value::Int(2)),
+            ("file tree", dict(vec![("Movie.mkv", v2_file(40000))])),
+        ]))
+        .unwrap();
+
+        assert_eq!(paths(&metadata), ["Movie.mkv"]);
+    }
+
+    #[test]
+    fn hybrid_prefers_the_v1_file_list() {
+        let metadata = Metadata::from_bytes(&encode(vec![
+            ("name", bytes("Show.S01")),
+            ("meta version", Value::Int(2)),
+            (
+                "file tree",
+                dict(vec![
+                    ("E01.mkv", v2_file(10000)),
+                    ("E02.mkv", v2_file(10000)),
+                ]),
+            ),
+            (
+                "files",
+                Value::List(vec![
+                    v1_file(&["E01.mkv"], 10000, None),
+                    v1_file(&[".pad", "6384"], 6384, Some("p")),
+                    v1_file(&["E02.mkv"], 10000, None),
+                ]),
+            ),
+            ("pieces", Value::Bytes(vec![0; 40])),
+        ]))
+        .unwrap();
+
+        assert_eq!(metadata.files().unwrap().len(), 3);
+        assert_eq!(metadata.total_size().unwrap(), 20000);
+        assert!(metadata.info.file_tree.is_some());
+    }
+
+    #[test]
+    fn keeps_unknown_keys() {
+        let bytes = encode(vec![
+            ("name", bytes("Movie.mkv")),
+            ("length", Value::Int(40000)),
+            ("pieces", Value::Bytes(vec![0; 60])),
+            ("x-cross-seed", bytes("abc")),
+        ]);
+        let metadata = Metadata::from_bytes(&bytes).unwrap();
+
+        assert!(metadata.info.extra.contains_key("x-cross-seed"));
+        assert_eq!(serde_bencode::to_bytes(&metadata).unwrap(), bytes);
+    }
+
+    #[test]
+    fn metadata_without_files_is_an_error() {
+        let metadata = Metadata::from_bytes(&encode(vec![("name", bytes("Empty"))])).unwrap();
+
+        assert!(metadata.files().is_err());
+    }
+}
@@ -1,4 +1,8 @@
 //! common structs and functions shared by other modules
 
+pub mod content;
 pub mod database;
 pub mod fastresume;
+pub mod filter;
+pub mod metadata;
+pub mod pattern;
@@ -0,0 +1,117 @@
+//! Regular expression helpers for the save path and tracker rewriting
+
+use regex::{Regex, RegexBuilder};
+
+/// Compiles a regular expression and checks the capture groups its replacement refers to
+///
+/// The regex crate silently replaces unknown groups with nothing, which would turn a typo into
+/// a broken path. `options` are the names of the pattern and replacement options, for errors.
+///
+/// ## Example
+/// ```rs
+/// let regex = pattern::compile_regex("^/mnt/disk[0-9]+/(.*)$", "/pool/$1", false, ("--old-path", "--new-path"))?;
+/// assert_eq!(regex.replace_all("/mnt/disk1/tv", "/pool/$1"), "/pool/tv");
+/// ```
+pub fn compile_regex(
+    pattern: &str,
+    replacement: &str,
+    ignore_case: bool,
+    options: (&str, &str),
+) -> Result<Regex, String> {
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|err| format!("invalid {} regex: {err}", options.0))?;
+
+    let mut rest = replacement;
+    while let Some(index) = rest.find('$') {
+        rest = &rest[index + 1..];
+        // "$$" is a literal dollar sign
+        if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+            continue;
+        }
+
+        let name = match rest.strip_prefix('{') {
+            Some(braced) => braced.split('}').next().unwrap_or_default(),
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                &rest[..end]
+            }
+        };
+        if name.is_empty() {
+            continue;
+        }
+
+        let exists = match name.parse::<usize>() {
+            Ok(index) => index < regex.captures_len(),
+            Err(_) => regex.capture_names().flatten().any(|group| group == name),
+        };
+        if !exists {
+            return Err(format!(
+                "{} refers to the capture group '{name}', which {} does not have. Use ${{1}} \
+                 to separate a group number from the text after it",
+                options.1, options.0
+            ));
+        }
+    }
+
+    Ok(regex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: (&str, &str) = ("--old-path", "--new-path");
+
+    #[test]
+    fn replaces_numbered_and_named_groups() {
+        let regex = compile_regex(
+            r"^/mnt/(?<disk>disk\d+)/(.*)$",
+            "/pool/$2/${disk}",
+            false,
+            OPTIONS,
+        )
+        .unwrap();
+
+        assert_eq!(
+            regex.replace_all("/mnt/disk1/tv", "/pool/$2/${disk}"),
+            "/pool/tv/disk1"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_groups() {
+        let err = compile_regex(r"^/mnt/(disk\d+)", "/pool/$2", false, OPTIONS).unwrap_err();
+        assert!(err.contains("capture group '2'"), "{err}");
+
+        let err = compile_regex(r"^/mnt/(disk\d+)", "/pool/${name}", false, OPTIONS).unwrap_err();
+        assert!(err.contains("capture group 'name'"), "{err}");
+
+        // "$1a" refers to a group named "1a", not to group 1 followed by "a"
+        let err = compile_regex(r"^/mnt/(disk\d+)", "/pool/$1a", false, OPTIONS).unwrap_err();
+        assert!(err.contains("capture group '1a'"), "{err}");
+    }
+
+    #[test]
+    fn accepts_literal_dollar_signs() {
+        let regex = compile_regex(r"^/mnt/(disk\d+)", "/pool/$$1/${1}x", false, OPTIONS).unwrap();
+
+        assert_eq!(
+            regex.replace_all("/mnt/disk1", "/pool/$$1/${1}x"),
+            "/pool/$1/disk1x"
+        );
+    }
+
+    #[test]
+    fn ignores_case_if_requested() {
+        let regex = compile_regex(r"^d:\\media", "/mnt/media", true, OPTIONS).unwrap();
+
+        assert!(regex.is_match("D:\\Media\\tv"));
+        let err = compile_regex("(", "", false, OPTIONS).unwrap_err();
+        assert!(err.starts_with("invalid --old-path regex"), "{err}");
+    }
+}
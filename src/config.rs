@@ -2,11 +2,19 @@
 //!
 //! Configures the application based on the passed command line arguments
 
-use crate::db::save_path::SavePath;
+use crate::common::filter::{parse_date, TorrentFilter};
+use crate::db::list::{Column, OutputFormat};
+use crate::db::mapping::{Mapping, MappingMode};
+use crate::db::migrate_os::{OsMigration, TargetOs, Translation};
+use crate::db::normalize::{PathNormalization, UnicodeForm, WindowsNames};
+use crate::db::rename_files::FileRename;
+use crate::db::save_path::{PathMatch, PathScope, SavePath};
 use crate::db::tracker_url::TrackerUrl;
 use argh::FromArgs;
 use core::panic;
 use directories::BaseDirs;
+use glob::Pattern;
+use std::fs;
 use std::path::{Path, PathBuf, MAIN_SEPARATOR};
 
 /// Returns the OS-specific qB .torrent data directory
@@ -24,9 +32,42 @@ struct CLIOpts {
     /// disable automatic torrents.db backup
     #[argh(switch, short = 'd')]
     disable_backup: bool,
+    /// directory to save backups to
+    #[argh(option)]
+    backup_dir: Option<String>,
+    /// compress the torrents.db backup with gzip
+    #[argh(switch)]
+    compress_backup: bool,
+    /// list torrents.db backups with their size and number of torrents
+    #[argh(switch)]
+    list_backups: bool,
+    /// delete torrents.db backups outside of --keep-last and --keep-days
+    #[argh(switch)]
+    prune_backups: bool,
+    /// number of most recent backups to keep when pruning
+    #[argh(option)]
+    keep_last: Option<usize>,
+    /// keep backups newer than this many days when pruning
+    #[argh(option)]
+    keep_days: Option<u32>,
+    /// restore torrents.db from this backup file
+    #[argh(option)]
+    restore_backup: Option<String>,
+    /// revert the changes of the run with this id
+    #[argh(option)]
+    undo: Option<String>,
+    /// migrate torrents.db to this schema version
+    #[argh(option)]
+    migrate_schema: Option<i64>,
     /// enable verbose output
     #[argh(switch, short = 'v')]
     verbose: bool,
+    /// run even if qBittorrent appears to be running
+    #[argh(switch)]
+    force: bool,
+    /// show what would change without writing anything
+    #[argh(switch, short = 'n')]
+    dry_run: bool,
     /// path string to replace
     #[argh(option)]
     old_path: Option<String>,
@@ -39,18 +80,126 @@ struct CLIOpts {
     /// force using Windows backslash '\' separators
     #[argh(switch)]
     use_win_sep: bool,
+    /// how --old-path is matched: substring (default), prefix, segment or regex
+    #[argh(option, default = "String::from(\"substring\")")]
+    path_match: String,
+    /// match --old-path case-insensitively
+    #[argh(switch)]
+    ignore_case: bool,
+    /// which paths --old-path replaces: save, download (incomplete directory) or both (default)
+    #[argh(option, default = "String::from(\"both\")")]
+    path_scope: String,
     /// tracker string to replace
     #[argh(option)]
     old_tracker: Option<String>,
     /// new tracker string
     #[argh(option)]
     new_tracker: Option<String>,
+    /// how --old-tracker is matched: substring (default) or regex
+    #[argh(option, default = "String::from(\"substring\")")]
+    tracker_match: String,
+    /// migrate all paths to another OS: unix or windows, requires --drive-map
+    #[argh(option)]
+    migrate_to: Option<String>,
+    /// drive or share rule for --migrate-to as FROM=TO, e.g. 'D:\=/mnt/d/', can be repeated
+    #[argh(option)]
+    drive_map: Vec<String>,
+    /// translate paths between Windows and their WSL mounts
+    #[argh(switch)]
+    wsl: bool,
+    /// compose-style list of host:container Docker volumes to translate paths with
+    #[argh(option)]
+    docker_volumes: Option<String>,
+    /// rewrite paths with --wsl or --docker-volumes: to-host or to-guest
+    #[argh(option)]
+    translate: Option<String>,
+    /// convert save paths and renamed files to a Unicode form: nfc or nfd
+    #[argh(option)]
+    normalize: Option<String>,
+    /// names Windows does not allow: check, or sanitize them, checked when targeting Windows
+    #[argh(option)]
+    windows_names: Option<String>,
+    /// file name or path fragment to replace in the renamed files of torrents
+    #[argh(option)]
+    old_file_name: Option<String>,
+    /// new file name or path fragment
+    #[argh(option)]
+    new_file_name: Option<String>,
+    /// how --old-file-name is matched: substring (default) or regex
+    #[argh(option, default = "String::from(\"substring\")")]
+    file_match: String,
+    /// TOML or CSV file with save path and tracker replacements
+    #[argh(option)]
+    mapping_file: Option<String>,
+    /// how the mapping file rules are applied: first-match (default) or ordered
+    #[argh(option, default = "String::from(\"first-match\")")]
+    mapping_mode: String,
     /// extract fastresume files
     #[argh(switch)]
     db_to_fastresume: bool,
     /// output directory for fastresume files
     #[argh(option, short = 'o')]
     output_dir: Option<String>,
+    /// load fastresume files into the database
+    #[argh(switch)]
+    fastresume_to_db: bool,
+    /// input directory for fastresume files
+    #[argh(option, short = 'i')]
+    input_dir: Option<String>,
+    /// modify fastresume files in the BT_backup directory instead of the database
+    #[argh(switch)]
+    use_fastresume: bool,
+    /// check that torrent files exist on disk with the expected size
+    #[argh(switch)]
+    check_files: bool,
+    /// verify torrent content on disk against the piece hashes
+    #[argh(switch)]
+    verify: bool,
+    /// only select the torrent with this v1 or v2 info hash, can be repeated
+    #[argh(option)]
+    hash: Vec<String>,
+    /// only select torrents whose hash is listed in this file, one per line
+    #[argh(option)]
+    hash_file: Option<String>,
+    /// only select torrents whose name matches this glob pattern
+    #[argh(option)]
+    name: Option<String>,
+    /// only select torrents in this category
+    #[argh(option)]
+    category: Option<String>,
+    /// only select torrents with this tag
+    #[argh(option)]
+    tag: Option<String>,
+    /// only select torrents with a tracker on this host
+    #[argh(option)]
+    tracker_host: Option<String>,
+    /// only select torrents whose save path is in this directory
+    #[argh(option)]
+    path_prefix: Option<String>,
+    /// only select torrents added on or after this date (YYYY-MM-DD)
+    #[argh(option)]
+    added_after: Option<String>,
+    /// only select torrents added before this date (YYYY-MM-DD)
+    #[argh(option)]
+    added_before: Option<String>,
+    /// only select torrents completed on or after this date (YYYY-MM-DD)
+    #[argh(option)]
+    completed_after: Option<String>,
+    /// only select torrents completed before this date (YYYY-MM-DD)
+    #[argh(option)]
+    completed_before: Option<String>,
+    /// number of torrents to check or verify in parallel
+    #[argh(option, short = 'j', default = "1")]
+    jobs: usize,
+    /// list torrents without modifying anything
+    #[argh(switch)]
+    list: bool,
+    /// list output format: table, json or csv
+    #[argh(option, default = "String::from(\"table\")")]
+    format: String,
+    /// comma-separated list of columns to list
+    #[argh(option)]
+    columns: Option<String>,
 }
 
 /// Application configuration generated from CLI arguments
@@ -62,14 +211,66 @@ pub struct Config {
     pub db_file: PathBuf,
     /// Disables the automatic torrents.db backup
     pub disable_backup: bool,
+    /// The directory to save backups to
+    pub backup_directory: Option<String>,
+    /// Compresses the torrents.db backup with gzip
+    pub compress_backup: bool,
+    /// Lists the torrents.db backups
+    pub list_backups: bool,
+    /// Deletes the torrents.db backups outside the retention policy
+    pub prune_backups: bool,
+    /// Number of most recent backups to keep when pruning
+    pub keep_last: Option<usize>,
+    /// Keeps backups newer than this many days when pruning
+    pub keep_days: Option<u32>,
+    /// Backup file to restore torrents.db from
+    pub restore_backup: Option<String>,
+    /// Id of the run to revert
+    pub undo: Option<String>,
+    /// Schema version to migrate torrents.db to
+    pub migrate_schema: Option<i64>,
     /// Torrent save path information
     pub save_path: Option<SavePath>,
     /// Torrent tracker url information
     pub tracker_url: Option<TrackerUrl>,
+    /// Cross-OS migration information
+    pub os_migration: Option<OsMigration>,
+    /// Torrent file rename information
+    pub file_rename: Option<FileRename>,
+    /// Unicode and Windows name normalization of torrent paths
+    pub normalization: Option<PathNormalization>,
+    /// Save path and tracker replacements from a mapping file
+    pub mapping: Option<Mapping>,
     /// Dumps the database to fastresume files
     pub db_to_fastresume: bool,
     /// The output directory for fastresume files
     pub output_directory: Option<String>,
+    /// Loads fastresume files into the database
+    pub fastresume_to_db: bool,
+    /// The input directory for fastresume files
+    pub input_directory: Option<String>,
+    /// Modifies fastresume files in the BT_backup directory instead of the database
+    pub use_fastresume: bool,
+    /// Checks that torrent files exist on disk with the expected size
+    pub check_files: bool,
+    /// Translates paths to where this machine finds the files when checking or verifying them
+    pub path_lookup: Option<OsMigration>,
+    /// Verifies torrent content on disk against the piece hashes
+    pub verify: bool,
+    /// Selects the torrents every operation applies to
+    pub filter: TorrentFilter,
+    /// Number of torrents to check or verify in parallel
+    pub jobs: usize,
+    /// Lists torrents without modifying anything
+    pub list: bool,
+    /// Output format for the torrent list
+    pub list_format: OutputFormat,
+    /// Columns for the torrent list, in order
+    pub list_columns: Vec<Column>,
+    /// Shows what would change without writing anything
+    pub dry_run: bool,
+    /// Skips the check for a running qB instance
+    pub force: bool,
     /// Toggles verbose output
     pub verbose: bool,
 }
@@ -85,7 +286,25 @@ impl Config {
     /// });
     /// ```
     pub fn build() -> Result<Config, String> {
-        let args: CLIOpts = argh::from_env();
+        Config::from_opts(argh::from_env())
+    }
+
+    /// Builds application configuration from a list of command line arguments
+    ///
+    /// The arguments are given without the program name. Unlike `Config::build`, `--help` and
+    /// invalid arguments are returned as an error instead of exiting.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let config = Config::from_args(&["--config-dir", "/srv/qBittorrent", "--verbose"])?;
+    /// ```
+    pub fn from_args(args: &[&str]) -> Result<Config, String> {
+        let args = CLIOpts::from_args(&["qbfrt"], args).map_err(|exit| exit.output)?;
+        Config::from_opts(args)
+    }
+
+    /// Builds application configuration from the parsed command line arguments
+    fn from_opts(args: CLIOpts) -> Result<Config, String> {
         let qb_dir = get_qb_dir();
 
         let qb_directory = match args.config_dir {
@@ -95,63 +314,466 @@ impl Config {
 
         let db_file = Path::new(&qb_directory).join("torrents.db");
 
-        let save_path = match (args.old_path, args.new_path) {
-            (Some(old), Some(new)) => {
-                let separator: String;
-                if args.use_unix_sep {
-                    separator = '/'.to_string();
-                } else if args.use_win_sep {
-                    separator = '\\'.to_string();
-                } else {
-                    separator = MAIN_SEPARATOR.to_string();
-                }
+        let match_mode = args.path_match.parse::<PathMatch>()?;
+        let scope = args.path_scope.parse::<PathScope>()?;
+        let separator = if args.use_unix_sep {
+            '/'.to_string()
+        } else if args.use_win_sep {
+            '\\'.to_string()
+        } else {
+            MAIN_SEPARATOR.to_string()
+        };
 
-                // qB saves a Unix-style path irrespective of OS, so we need to use that in some places
-                // Yes, even Windows network paths are saved that way e.g. \\server\folder -> //server/folder
-                let old_unix = old.replace('\\', "/");
-                let new_unix = new.replace('\\', "/");
-
-                // qB saves the path in a second location with OS-specific separators. It will be up to the user
-                // to pick the appropriate separator for the existing path to replace
-                Some(SavePath {
-                    old_unix,
-                    new_unix,
-                    old,
-                    new,
-                    separator,
-                })
-            }
+        // qB saves the path in a second location with OS-specific separators. It will be up to the user
+        // to pick the appropriate separator for the existing path to replace
+        let save_path = match (args.old_path, args.new_path) {
+            (Some(old), Some(new)) => Some(SavePath::new(
+                &old,
+                &new,
+                &separator,
+                match_mode,
+                args.ignore_case,
+                scope,
+            )?),
             (None, None) => None,
             (Some(_old), None) => panic!("--new-path is missing!"),
             (None, Some(_new)) => panic!("--old-path is missing!"),
         };
 
+        if save_path.is_none()
+            && (match_mode != PathMatch::Substring || args.ignore_case || scope != PathScope::Both)
+        {
+            return Err(String::from(
+                "--path-match, --ignore-case and --path-scope require --old-path and --new-path",
+            ));
+        }
+
+        let tracker_regex = match args.tracker_match.as_str() {
+            "substring" => false,
+            "regex" => true,
+            mode => {
+                return Err(format!(
+                    "unknown tracker match mode '{mode}', expected substring or regex"
+                ))
+            }
+        };
         let tracker_url = match (args.old_tracker, args.new_tracker) {
-            (Some(old), Some(new)) => Some(TrackerUrl { old, new }),
+            (Some(old), Some(new)) => Some(TrackerUrl::new(&old, &new, tracker_regex)?),
             (None, None) => None,
             (Some(_old), None) => panic!("--new-tracker is missing!"),
             (None, Some(_new)) => panic!("--old-tracker is missing!"),
         };
 
+        if tracker_url.is_none() && tracker_regex {
+            return Err(String::from(
+                "--tracker-match requires --old-tracker and --new-tracker",
+            ));
+        }
+
+        // WSL and Docker presets translate paths between the host and WSL or a container
+        let preset = |translation| match (args.wsl, &args.docker_volumes) {
+            (true, Some(_)) => Err(String::from(
+                "--wsl can not be combined with --docker-volumes",
+            )),
+            (true, None) => OsMigration::wsl(translation).map(Some),
+            (false, Some(volumes_file)) => {
+                OsMigration::docker(Path::new(volumes_file), translation).map(Some)
+            }
+            (false, None) => Ok(None),
+        };
+
+        let translation = args
+            .translate
+            .as_deref()
+            .map(str::parse::<Translation>)
+            .transpose()?;
+        let os_migration =
+            match (args.migrate_to, translation) {
+                (Some(_), Some(_)) => {
+                    return Err(String::from(
+                        "--migrate-to can not be combined with --translate",
+                    ))
+                }
+                (Some(target), None) => Some(OsMigration::new(
+                    target.parse::<TargetOs>()?,
+                    &args.drive_map,
+                )?),
+                (None, Some(translation)) => Some(preset(translation)?.ok_or_else(|| {
+                    String::from("--translate requires --wsl or --docker-volumes")
+                })?),
+                (None, None) if !args.drive_map.is_empty() => {
+                    return Err(String::from("--drive-map requires --migrate-to"))
+                }
+                (None, None) => None,
+            };
+
+        // The migration picks the separators itself and converts every path on its own
+        if os_migration.is_some()
+            && (save_path.is_some()
+                || args.mapping_file.is_some()
+                || args.use_unix_sep
+                || args.use_win_sep)
+        {
+            return Err(String::from(
+                "--migrate-to and --translate can not be combined with --old-path, \
+                 --mapping-file, --use-unix-sep or --use-win-sep",
+            ));
+        }
+
+        // Files are checked on this machine, so their paths are translated to how it sees them.
+        // Under WSL that is the guest, otherwise the host
+        let path_lookup = match args.check_files || args.verify {
+            true if args.wsl && !cfg!(windows) => preset(Translation::ToGuest)?,
+            true => preset(Translation::ToHost)?,
+            false => None,
+        };
+        if (args.wsl || args.docker_volumes.is_some())
+            && translation.is_none()
+            && path_lookup.is_none()
+        {
+            return Err(String::from(
+                "--wsl and --docker-volumes require --translate, --check-files or --verify",
+            ));
+        }
+
+        // Paths headed for Windows are checked for names it does not allow, unless asked otherwise.
+        // The separator alone rewrites nothing, so there is nothing new to check without a rewrite
+        let rewrites_paths =
+            save_path.is_some() || args.mapping_file.is_some() || args.old_file_name.is_some();
+        let targets_windows = (args.use_win_sep && rewrites_paths)
+            || os_migration
+                .as_ref()
+                .is_some_and(|migration| migration.target == TargetOs::Windows);
+        let windows_names = match args.windows_names {
+            Some(mode) => Some(mode.parse::<WindowsNames>()?),
+            None if targets_windows => Some(WindowsNames::Check),
+            None => None,
+        };
+        let form = args
+            .normalize
+            .as_deref()
+            .map(str::parse::<UnicodeForm>)
+            .transpose()?;
+        let normalization = match form.is_some() || windows_names.is_some() {
+            true => Some(PathNormalization {
+                form,
+                windows_names,
+            }),
+            false => None,
+        };
+
+        let file_regex = match args.file_match.as_str() {
+            "substring" => false,
+            "regex" => true,
+            mode => {
+                return Err(format!(
+                    "unknown file match mode '{mode}', expected substring or regex"
+                ))
+            }
+        };
+        let file_rename = match (args.old_file_name, args.new_file_name) {
+            (Some(old), Some(new)) => Some(FileRename::new(&old, &new, &separator, file_regex)?),
+            (None, None) => None,
+            (Some(_old), None) => panic!("--new-file-name is missing!"),
+            (None, Some(_new)) => panic!("--old-file-name is missing!"),
+        };
+
+        if file_rename.is_none() && file_regex {
+            return Err(String::from(
+                "--file-match requires --old-file-name and --new-file-name",
+            ));
+        }
+
+        let mapping_mode = args.mapping_mode.parse::<MappingMode>()?;
+        let mapping = args
+            .mapping_file
+            .map(|mapping_file| Mapping::load(Path::new(&mapping_file), mapping_mode, &separator))
+            .transpose()?;
+        if mapping.is_none() && mapping_mode != MappingMode::FirstMatch {
+            return Err(String::from("--mapping-mode requires --mapping-file"));
+        }
+
+        if args.use_fastresume && args.fastresume_to_db {
+            return Err(String::from(
+                "--use-fastresume can not be combined with --fastresume-to-db",
+            ));
+        }
+
+        // A dry run keeps all changes in memory, so it can not produce or load fastresume files
+        if args.dry_run && (args.db_to_fastresume || args.fastresume_to_db) {
+            return Err(String::from(
+                "--dry-run can not be combined with --db-to-fastresume or --fastresume-to-db",
+            ));
+        }
+
+        let mut hashes = args.hash;
+        if let Some(hash_file) = args.hash_file {
+            let contents = fs::read_to_string(&hash_file)
+                .map_err(|err| format!("could not read {hash_file}: {err}"))?;
+            hashes.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|hash| !hash.is_empty())
+                    .map(str::to_string),
+            );
+        }
+
+        let parse_date = |date: Option<String>| date.as_deref().map(parse_date).transpose();
+        let filter = TorrentFilter {
+            hashes,
+            name: args
+                .name
+                .map(|name| Pattern::new(&name))
+                .transpose()
+                .map_err(|err| format!("invalid --name pattern: {err}"))?,
+            category: args.category,
+            tag: args.tag,
+            tracker_host: args.tracker_host,
+            path_prefix: args
+                .path_prefix
+                .map(|prefix| {
+                    SavePath::new(
+                        &prefix,
+                        &prefix,
+                        "/",
+                        PathMatch::Prefix,
+                        false,
+                        PathScope::Both,
+                    )
+                })
+                .transpose()?,
+            added_after: parse_date(args.added_after)?,
+            added_before: parse_date(args.added_before)?,
+            completed_after: parse_date(args.completed_after)?,
+            completed_before: parse_date(args.completed_before)?,
+        };
+
+        let list_format = args.format.parse::<OutputFormat>()?;
+        let list_columns = match args.columns {
+            Some(columns) => columns
+                .split(',')
+                .map(|column| column.trim().parse::<Column>())
+                .collect::<Result<Vec<Column>, String>>()?,
+            None => Column::ALL.to_vec(),
+        };
+
+        // Listing is read-only, so it can not be combined with other operations
+        let other_operations = [
+            save_path.is_some(),
+            tracker_url.is_some(),
+            os_migration.is_some(),
+            file_rename.is_some(),
+            normalization.is_some(),
+            mapping.is_some(),
+            args.db_to_fastresume,
+            args.fastresume_to_db,
+            args.check_files,
+            args.verify,
+        ];
+        if args.list && other_operations.contains(&true) {
+            return Err(String::from(
+                "--list can not be combined with other operations",
+            ));
+        }
+
+        if args.undo.is_some() && (args.list || other_operations.contains(&true)) {
+            return Err(String::from(
+                "--undo can not be combined with other operations",
+            ));
+        }
+
+        // Backup management works on the database file itself, outside of a normal run
+        let backup_commands = [
+            args.list_backups,
+            args.prune_backups,
+            args.restore_backup.is_some(),
+        ];
+        if backup_commands.iter().filter(|command| **command).count() > 1 {
+            return Err(String::from(
+                "--list-backups, --prune-backups and --restore-backup can not be combined",
+            ));
+        }
+        if backup_commands.contains(&true)
+            && (args.list || args.undo.is_some() || other_operations.contains(&true))
+        {
+            return Err(String::from(
+                "backup management can not be combined with other operations",
+            ));
+        }
+        if args.dry_run && args.restore_backup.is_some() {
+            return Err(String::from(
+                "--dry-run can not be combined with --restore-backup",
+            ));
+        }
+        // The current database is always backed up first, so a wrong restore can be undone
+        if args.disable_backup && args.restore_backup.is_some() {
+            return Err(String::from(
+                "--disable-backup can not be combined with --restore-backup",
+            ));
+        }
+        if args.prune_backups && args.keep_last.is_none() && args.keep_days.is_none() {
+            return Err(String::from(
+                "--prune-backups requires --keep-last or --keep-days",
+            ));
+        }
+        if !args.prune_backups && (args.keep_last.is_some() || args.keep_days.is_some()) {
+            return Err(String::from(
+                "--keep-last and --keep-days require --prune-backups",
+            ));
+        }
+
+        // A schema migration changes the table layout, so it runs on its own
+        if args.migrate_schema.is_some() {
+            if args.use_fastresume {
+                return Err(String::from(
+                    "--migrate-schema can not be combined with --use-fastresume",
+                ));
+            }
+            if args.list
+                || args.undo.is_some()
+                || backup_commands.contains(&true)
+                || other_operations.contains(&true)
+            {
+                return Err(String::from(
+                    "--migrate-schema can not be combined with other operations",
+                ));
+            }
+        }
+
         let config = Config {
             qb_directory,
             db_file,
             disable_backup: args.disable_backup,
+            backup_directory: args.backup_dir,
+            compress_backup: args.compress_backup,
+            list_backups: args.list_backups,
+            prune_backups: args.prune_backups,
+            keep_last: args.keep_last,
+            keep_days: args.keep_days,
+            restore_backup: args.restore_backup,
+            undo: args.undo,
+            migrate_schema: args.migrate_schema,
             save_path,
             tracker_url,
+            os_migration,
+            file_rename,
+            normalization,
+            mapping,
             db_to_fastresume: args.db_to_fastresume,
             output_directory: args.output_dir,
+            fastresume_to_db: args.fastresume_to_db,
+            input_directory: args.input_dir,
+            use_fastresume: args.use_fastresume,
+            check_files: args.check_files,
+            path_lookup,
+            verify: args.verify,
+            filter,
+            jobs: args.jobs,
+            list: args.list,
+            list_format,
+            list_columns,
+            dry_run: args.dry_run,
+            force: args.force,
             verbose: args.verbose,
         };
 
-        if config.verbose {
+        if config.verbose && !config.list {
             println!("Verbose output enabled");
             println!("Using {:?} as qB directory", config.qb_directory.display());
-            println!("Using {:?} as qB database", config.db_file.display());
+            if config.use_fastresume {
+                println!(
+                    "Using {:?} as qB BT_backup directory",
+                    config.bt_backup_directory().display()
+                );
+            } else {
+                println!("Using {:?} as qB database", config.db_file.display());
+            }
             println!("Save path: {:?}", config.save_path);
             println!("Tracker url: {:?}", config.tracker_url);
+            if let Some(os_migration) = &config.os_migration {
+                println!("OS migration: {os_migration:?}");
+            }
+            if let Some(file_rename) = &config.file_rename {
+                println!("File rename: {file_rename:?}");
+            }
+            if let Some(normalization) = &config.normalization {
+                println!("Normalize: {normalization:?}");
+            }
+            if !config.filter.is_empty() {
+                println!("Filter: {:?}", config.filter);
+            }
         }
 
         Ok(config)
     }
+
+    /// Returns true if any operation writes to the database or BT_backup directory
+    ///
+    /// Otherwise only read-only checks or a dump to the output directory were requested, which
+    /// need no backup, journal or transaction.
+    pub fn modifies(&self) -> bool {
+        self.undo.is_some()
+            || self.fastresume_to_db
+            || self.save_path.is_some()
+            || self.os_migration.is_some()
+            || self.tracker_url.is_some()
+            || self.mapping.is_some()
+            || self.file_rename.is_some()
+            || self
+                .normalization
+                .as_ref()
+                .is_some_and(PathNormalization::modifies)
+    }
+
+    /// Returns the BT_backup directory containing the .fastresume files
+    ///
+    /// Uses the input directory if one was given, otherwise the `BT_backup` directory in the qB
+    /// directory.
+    pub fn bt_backup_directory(&self) -> PathBuf {
+        self.input_directory
+            .as_deref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| self.qb_directory.join("BT_backup"))
+    }
+
+    /// Returns the directory torrents.db backups are saved to
+    ///
+    /// Uses the backup directory if one was given, otherwise the qB directory.
+    pub fn db_backup_directory(&self) -> PathBuf {
+        self.backup_directory
+            .as_deref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| self.qb_directory.clone())
+    }
+
+    /// Returns the directory the undo journals are saved to
+    ///
+    /// This is the `qbfrt_journal` directory in the backup directory, see
+    /// `Config::db_backup_directory`.
+    pub fn journal_directory(&self) -> PathBuf {
+        self.db_backup_directory().join("qbfrt_journal")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_alone_do_not_modify() {
+        for args in [
+            &["--check-files"][..],
+            &["--verify"],
+            &["--check-files", "--verify", "--jobs", "2"],
+            &["--list"],
+            &["--db-to-fastresume"],
+            &["--db-to-fastresume", "--output-dir", "dump", "--verify"],
+        ] {
+            assert!(!Config::from_args(args).unwrap().modifies(), "{args:?}");
+        }
+
+        let config = Config::from_args(&["--verify", "--old-path", "/a", "--new-path", "/b"]);
+        assert!(config.unwrap().modifies());
+    }
 }
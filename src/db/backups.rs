@@ -0,0 +1,402 @@
+//! Tools for managing torrents.db backups
+
+use crate::config::Config;
+use crate::db::{self, instance, DB};
+use chrono::{Local, NaiveDateTime};
+use flate2::read::GzDecoder;
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A torrents.db backup created by `DB::backup`
+#[derive(Debug)]
+pub struct Backup {
+    /// Path to the backup file
+    pub path: PathBuf,
+    /// Time the backup was created, taken from the file name
+    pub created: NaiveDateTime,
+    /// File size, in bytes
+    pub size: u64,
+    /// The backup is compressed with gzip
+    pub compressed: bool,
+}
+
+impl Backup {
+    /// Parses a `torrents.db-YYYYMMDDHHMMSS.bak` or `.bak.gz` file name
+    fn from_path(path: PathBuf) -> Option<Backup> {
+        let file_name = path.file_name()?.to_str()?;
+        let timestamp = file_name.strip_prefix("torrents.db-")?;
+        let (timestamp, compressed) = match timestamp.strip_suffix(".bak.gz") {
+            Some(timestamp) => (timestamp, true),
+            None => (timestamp.strip_suffix(".bak")?, false),
+        };
+        let created = NaiveDateTime::parse_from_str(timestamp, "%Y%m%d%H%M%S").ok()?;
+        let size = path.metadata().ok()?.len();
+
+        Some(Backup {
+            path,
+            created,
+            size,
+            compressed,
+        })
+    }
+
+    /// Runs a function on the uncompressed backup file
+    ///
+    /// Compressed backups are extracted to a temporary file next to the backup, which is removed
+    /// afterwards.
+    fn with_uncompressed<T>(
+        &self,
+        f: impl FnOnce(&Path) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        if !self.compressed {
+            return f(&self.path);
+        }
+
+        let tmp_file = self.path.with_extension("tmp");
+        let result = File::create(&tmp_file)
+            .and_then(|mut tmp| io::copy(&mut GzDecoder::new(File::open(&self.path)?), &mut tmp))
+            .map_err(Box::from)
+            .and_then(|_| f(&tmp_file));
+        fs::remove_file(&tmp_file)?;
+
+        result
+    }
+
+    /// Returns the number of torrents in the backup
+    fn num_torrents(&self) -> Result<i64, Box<dyn Error>> {
+        self.with_uncompressed(|path| {
+            let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            Ok(db.query_row("SELECT COUNT(*) FROM torrents", [], |row| row.get(0))?)
+        })
+    }
+}
+
+/// Returns all torrents.db backups in the backup directory, oldest first
+///
+/// ## Example
+/// ```rs
+/// for backup in backups::find_backups(&config)? {
+///     println!("{:?} was created at {}", backup.path, backup.created);
+/// }
+/// ```
+pub fn find_backups(config: &Config) -> Result<Vec<Backup>, Box<dyn Error>> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(config.db_backup_directory())? {
+        if let Some(backup) = Backup::from_path(entry?.path()) {
+            backups.push(backup);
+        }
+    }
+    backups.sort_by_key(|backup| backup.created);
+
+    Ok(backups)
+}
+
+/// Prints the torrents.db backups with their size and number of torrents
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::backups;
+/// backups::list_backups(&config)?;
+/// ```
+pub fn list_backups(config: &Config) -> Result<(), Box<dyn Error>> {
+    let backups = find_backups(config)?;
+    if backups.is_empty() {
+        println!(
+            "Backups: no backups found in {:?}",
+            config.db_backup_directory().display()
+        );
+        return Ok(());
+    }
+
+    for backup in &backups {
+        // A corrupt backup is listed, but without a torrent count
+        let num_torrents = match backup.num_torrents() {
+            Ok(num_torrents) => format!("{num_torrents} torrents"),
+            Err(err) => format!("unreadable: {err}"),
+        };
+        println!(
+            "{}  {:>12} bytes  {}  {}",
+            backup.created.format("%Y-%m-%d %H:%M:%S"),
+            backup.size,
+            num_torrents,
+            backup.path.display()
+        );
+    }
+
+    match backups.len() {
+        1 => println!("Backups: 1 backup found"),
+        _ => println!("Backups: {} backups found", backups.len()),
+    }
+
+    Ok(())
+}
+
+/// Deletes the torrents.db backups that fall outside the retention policy
+///
+/// A backup is kept if it is one of the newest config.keep_last backups, or if it is newer than
+/// config.keep_days days. If only one of them is set, only that rule applies.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::backups;
+/// let config = Config { keep_last: Some(5), keep_days: Some(30) };
+/// backups::prune_backups(&config)?;
+/// ```
+///
+/// ## Dry run
+/// If config.dry_run is set, the backups that would be deleted are listed but kept.
+pub fn prune_backups(config: &Config) -> Result<(), Box<dyn Error>> {
+    let backups = find_backups(config)?;
+    let cutoff = config
+        .keep_days
+        .map(|days| Local::now().naive_local() - chrono::Duration::days(days as i64));
+
+    let mut num_backups_deleted = 0;
+    for (index, backup) in backups.iter().enumerate() {
+        let newest = config
+            .keep_last
+            .is_some_and(|keep_last| index + keep_last >= backups.len());
+        let recent = cutoff.is_some_and(|cutoff| backup.created >= cutoff);
+        if newest || recent {
+            continue;
+        }
+
+        if config.dry_run {
+            println!("Prune backups: would delete {:?}", backup.path.display());
+        } else {
+            fs::remove_file(&backup.path)?;
+            if config.verbose {
+                println!("Prune backups: deleted {:?}", backup.path.display());
+            }
+        }
+        num_backups_deleted += 1;
+    }
+
+    let verb = db::verb(config, num_backups_deleted);
+    match num_backups_deleted {
+        0 => println!("Prune backups: no backups {verb} deleted"),
+        1 => println!("Prune backups: 1 backup {verb} deleted"),
+        _ => println!("Prune backups: {num_backups_deleted} backups {verb} deleted"),
+    }
+
+    Ok(())
+}
+
+/// Replaces torrents.db with the contents of a backup
+///
+/// The backup is checked with `PRAGMA integrity_check` first. The restore is refused while qB is
+/// running, see `instance::check_not_running`, and a safety backup of the current database is made
+/// with `DB::backup`. That backup can not be disabled, `--disable-backup` is rejected along with
+/// `--restore-backup`. The backup is restored with SQLite's online backup API, so
+/// a `-wal` file of the current database is handled correctly.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::backups;
+/// backups::restore_backup("torrents.db-20240101120000.bak", &config)?;
+/// ```
+///
+/// ## Configuration
+/// - A backup file name without a directory is looked up in the backup directory.
+pub fn restore_backup(backup: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut path = PathBuf::from(backup);
+    if !path.exists() {
+        path = config.db_backup_directory().join(backup);
+    }
+    let backup =
+        Backup::from_path(path).ok_or_else(|| format!("{backup} is not a torrents.db backup"))?;
+
+    println!("Restore backup: restoring {:?}", backup.path.display());
+    backup.with_uncompressed(|path| {
+        // Checking a copy keeps the backup itself untouched
+        let check_file = path.with_extension("check");
+        fs::copy(path, &check_file)?;
+        let result = DB::check_backup(&check_file);
+        fs::remove_file(&check_file)?;
+        result?;
+
+        instance::check_not_running(config)?;
+        DB::backup(config)?;
+
+        let mut db = DB::connect(config)?;
+        db.restore(
+            DatabaseName::Main,
+            path,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+
+        Ok(())
+    })?;
+
+    println!(
+        "Restore backup: restored the backup from {}",
+        backup.created.format("%Y-%m-%d %H:%M:%S")
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, SEEDING_ID};
+    use chrono::Duration;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    /// Copies torrents.db to a backup created the given number of days ago
+    fn write_backup(config: &Config, days_ago: i64, compressed: bool) -> PathBuf {
+        let created = Local::now().naive_local() - Duration::days(days_ago);
+        let backup_file = config.db_backup_directory().join(format!(
+            "torrents.db-{}.bak",
+            created.format("%Y%m%d%H%M%S")
+        ));
+        if !compressed {
+            fs::copy(&config.db_file, &backup_file).unwrap();
+            return backup_file;
+        }
+
+        let compressed_file = backup_file.with_extension("bak.gz");
+        let mut encoder = GzEncoder::new(
+            File::create(&compressed_file).unwrap(),
+            Compression::default(),
+        );
+        io::copy(&mut File::open(&config.db_file).unwrap(), &mut encoder).unwrap();
+        encoder.finish().unwrap();
+        compressed_file
+    }
+
+    fn remaining_backups(config: &Config) -> Vec<PathBuf> {
+        find_backups(config)
+            .unwrap()
+            .into_iter()
+            .map(|backup| backup.path)
+            .collect()
+    }
+
+    #[test]
+    fn finds_backups_oldest_first() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &["--list-backups"]);
+        let newest = write_backup(&config, 1, true);
+        let oldest = write_backup(&config, 3, false);
+        fs::write(dir.path().join("torrents.db-notes.bak"), "").unwrap();
+
+        let backups = find_backups(&config).unwrap();
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[0].path, oldest);
+        assert!(!backups[0].compressed);
+        assert_eq!(backups[1].path, newest);
+        assert!(backups[1].compressed);
+
+        // Compressed backups are extracted to count their torrents
+        let num_torrents = backups[0].num_torrents().unwrap();
+        assert!(num_torrents > 0);
+        assert_eq!(backups[1].num_torrents().unwrap(), num_torrents);
+        assert!(!newest.with_extension("tmp").exists());
+        list_backups(&config).unwrap();
+    }
+
+    #[test]
+    fn prune_keeps_the_newest_backups() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &["--prune-backups", "--keep-last", "2"]);
+        let backups: Vec<PathBuf> = (1..=4)
+            .rev()
+            .map(|days_ago| write_backup(&config, days_ago, false))
+            .collect();
+
+        prune_backups(&config).unwrap();
+
+        assert_eq!(remaining_backups(&config), backups[2..]);
+    }
+
+    #[test]
+    fn prune_keeps_recent_or_newest_backups() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(
+            &dir,
+            &["--prune-backups", "--keep-days", "7", "--keep-last", "1"],
+        );
+        let old = write_backup(&config, 30, false);
+        let recent = write_backup(&config, 2, false);
+        let newest = write_backup(&config, 1, true);
+
+        let dry_run =
+            test_util::qb_config(&dir, &["--prune-backups", "--keep-days", "7", "--dry-run"]);
+        prune_backups(&dry_run).unwrap();
+        assert_eq!(
+            remaining_backups(&config),
+            [old, recent.clone(), newest.clone()]
+        );
+
+        prune_backups(&config).unwrap();
+        assert_eq!(remaining_backups(&config), [recent, newest]);
+    }
+
+    #[test]
+    fn prune_requires_a_retention_policy() {
+        let error = Config::from_args(&["--prune-backups"]).unwrap_err();
+        assert_eq!(error, "--prune-backups requires --keep-last or --keep-days");
+
+        let error = Config::from_args(&["--keep-last", "3"]).unwrap_err();
+        assert_eq!(error, "--keep-last and --keep-days require --prune-backups");
+        let error = Config::from_args(&["--list-backups", "--keep-days", "7"]).unwrap_err();
+        assert_eq!(error, "--keep-last and --keep-days require --prune-backups");
+    }
+
+    #[test]
+    fn restore_requires_a_safety_backup() {
+        let error =
+            Config::from_args(&["--restore-backup", "torrents.db-20240101120000.bak", "-d"])
+                .unwrap_err();
+        assert_eq!(
+            error,
+            "--disable-backup can not be combined with --restore-backup"
+        );
+    }
+
+    #[test]
+    fn restore_round_trip() {
+        let dir = test_util::qb_dir();
+        let backup_dir = dir.path().join("backups");
+        let config = test_util::qb_config(&dir, &["--backup-dir", backup_dir.to_str().unwrap()]);
+        DB::backup(&config).unwrap();
+        let backup = remaining_backups(&config).remove(0);
+
+        let db = Connection::open(&config.db_file).unwrap();
+        db.execute("DELETE FROM torrents", []).unwrap();
+        drop(db);
+
+        // The safety backup goes to the qB directory, apart from the backup being restored
+        let restore_config = test_util::qb_config(&dir, &["--force"]);
+        restore_backup(backup.to_str().unwrap(), &restore_config).unwrap();
+
+        let db = Connection::open(&config.db_file).unwrap();
+        let torrent_id: String = db
+            .query_row(
+                "SELECT torrent_id FROM torrents WHERE torrent_id = ?1",
+                [SEEDING_ID],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(torrent_id, SEEDING_ID);
+
+        // The emptied database was backed up before it was replaced
+        let safety_backups = find_backups(&restore_config).unwrap();
+        assert_eq!(safety_backups.len(), 1);
+        assert_eq!(safety_backups[0].num_torrents().unwrap(), 0);
+    }
+
+    #[test]
+    fn restore_rejects_other_files() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+
+        let error = restore_backup("torrents.db", &config).unwrap_err();
+        assert_eq!(error.to_string(), "torrents.db is not a torrents.db backup");
+    }
+}
@@ -0,0 +1,458 @@
+//! Tools for detecting a running qB instance
+
+use crate::config::Config;
+use rusqlite::{Connection, OpenFlags};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Returns an error if qB appears to be running
+///
+/// qB keeps its fastresume data in memory and overwrites any change made while it is running, so
+/// modifying the database or BT_backup directory at the same time only loses data. The following
+/// signs of a running instance are checked:
+/// - a non-empty `torrents.db-wal` file, which qB removes when it shuts down cleanly
+/// - another process holding torrents.db open, detected through the locks SQLite uses
+/// - a lock held on qB's `lockfile` in the qB data or config directory (Unix only)
+/// - a running `qbittorrent` or `qbittorrent-nox` process (Linux only)
+///
+/// ## Example
+/// ```rs
+/// instance::check_not_running(&config).unwrap_or_else(|err| {
+///     println!("{err}");
+///     process::exit(1);
+/// });
+/// ```
+///
+/// ## Configuration
+/// - The check is skipped if config.force is set.
+pub fn check_not_running(config: &Config) -> Result<(), Box<dyn Error>> {
+    if config.force {
+        if config.verbose {
+            println!("Skipping check for a running qBittorrent instance");
+        }
+        return Ok(());
+    }
+
+    let mut signs = Vec::new();
+    if !config.use_fastresume && config.db_file.exists() {
+        signs.extend(database_signs(config));
+    }
+    signs.extend(lockfile_signs(config));
+    signs.extend(process_signs());
+
+    if signs.is_empty() {
+        return Ok(());
+    }
+
+    Err(format!(
+        "qBittorrent appears to be running:\n- {}\nShut it down completely first, or pass --force \
+         if you are sure it is not running",
+        signs.join("\n- ")
+    )
+    .into())
+}
+
+/// Checks torrents.db for a hot WAL file and locks held by other connections
+fn database_signs(config: &Config) -> Vec<String> {
+    let mut signs = Vec::new();
+
+    // A WAL file with changes in it is the clearest sign, so it is checked first
+    let mut wal_file = config.db_file.clone().into_os_string();
+    wal_file.push("-wal");
+    let wal_file = PathBuf::from(wal_file);
+    if fs::metadata(&wal_file).is_ok_and(|metadata| metadata.len() > 0) {
+        signs.push(format!(
+            "{:?} contains changes that were not written to the database yet",
+            wal_file.display()
+        ));
+    }
+
+    if let Some(sign) = shared_memory_sign(config) {
+        signs.push(sign);
+    }
+
+    // The read transaction could briefly block a running qB from writing, so it is only tried if
+    // nothing else did already show that qB is running
+    if signs.is_empty() {
+        if let Err(err) = lock_database(config) {
+            signs.push(format!(
+                "{:?} is in use by another program: {err}",
+                config.db_file.display()
+            ));
+        }
+    }
+
+    signs
+}
+
+/// Checks if another process has torrents.db open in WAL mode
+///
+/// Every connection to a WAL database holds a shared lock on the "DMS" byte of the `-shm` file
+/// for as long as it is open.
+#[cfg(unix)]
+fn shared_memory_sign(config: &Config) -> Option<String> {
+    // Offset of the DMS lock byte in SQLite's unix VFS
+    const SHM_DMS_OFFSET: libc::off_t = 128;
+
+    let mut shm_file = config.db_file.clone().into_os_string();
+    shm_file.push("-shm");
+    let pid = locking_process(shm_file.as_ref(), SHM_DMS_OFFSET, 1)?;
+
+    Some(format!(
+        "{:?} is open in process {pid}",
+        config.db_file.display()
+    ))
+}
+
+/// Checks if another process has torrents.db open in WAL mode, not supported on this OS
+#[cfg(not(unix))]
+fn shared_memory_sign(_config: &Config) -> Option<String> {
+    None
+}
+
+/// Returns the process holding a lock on a range of a file, if any
+///
+/// `F_GETLK` reports such a lock without taking it. A length of 0 covers the whole file.
+#[cfg(unix)]
+fn locking_process(
+    file: &std::path::Path,
+    start: libc::off_t,
+    len: libc::off_t,
+) -> Option<libc::pid_t> {
+    use std::os::fd::AsRawFd;
+
+    let file = fs::File::open(file).ok()?;
+
+    // SAFETY: flock is a plain C struct, for which all zeroes is a valid value
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = start;
+    lock.l_len = len;
+
+    // SAFETY: the file descriptor is open for the duration of the call and lock is a valid flock
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) };
+    if result == -1 || lock.l_type == libc::F_UNLCK as libc::c_short {
+        return None;
+    }
+
+    Some(lock.l_pid)
+}
+
+/// Checks if another connection is reading or writing torrents.db
+///
+/// The database is opened read-only, so the check can not change it, e.g. by rolling back a hot
+/// journal. A read transaction fails if another connection is writing, and on Unix the locks
+/// SQLite takes on the database file also show a connection of another process that is reading.
+/// This covers databases in rollback journal mode, which have no `-shm` file.
+fn lock_database(config: &Config) -> Result<(), Box<dyn Error>> {
+    let db = Connection::open_with_flags(
+        &config.db_file,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    // Fail right away instead of waiting for the lock to be released
+    db.busy_timeout(Duration::ZERO)?;
+    db.execute_batch(
+        "
+        BEGIN;
+        SELECT COUNT(*) FROM sqlite_master;
+        ROLLBACK;
+        ",
+    )?;
+
+    #[cfg(unix)]
+    {
+        // Offset of the pending byte, followed by the reserved byte and the shared range, in
+        // SQLite's unix VFS
+        const PENDING_BYTE: libc::off_t = 0x4000_0000;
+
+        if let Some(pid) = locking_process(&config.db_file, PENDING_BYTE, 512) {
+            return Err(format!("locked by process {pid}").into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks for a locked qB lockfile
+///
+/// qB never deletes its lockfile, so only a lock held on it shows that qB is running. qB locks the
+/// whole file with `fcntl` for as long as it runs.
+#[cfg(unix)]
+fn lockfile_signs(config: &Config) -> Vec<String> {
+    use directories::BaseDirs;
+
+    let mut lockfiles = vec![config.qb_directory.join("lockfile")];
+    if let Some(base_dirs) = BaseDirs::new() {
+        lockfiles.push(base_dirs.config_dir().join("qBittorrent").join("lockfile"));
+    }
+
+    lockfiles
+        .into_iter()
+        .filter_map(|lockfile| {
+            let pid = locking_process(&lockfile, 0, 0)?;
+            Some(format!(
+                "lockfile {:?} is locked by process {pid}",
+                lockfile.display()
+            ))
+        })
+        .collect()
+}
+
+/// Checks for a locked qB lockfile, not supported on this OS
+///
+/// qB locks the lockfile with a named mutex on Windows, which can not be checked from here.
+#[cfg(not(unix))]
+fn lockfile_signs(_config: &Config) -> Vec<String> {
+    Vec::new()
+}
+
+/// Checks for a running qB process
+#[cfg(target_os = "linux")]
+fn process_signs() -> Vec<String> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    let mut signs = Vec::new();
+    for entry in entries.flatten() {
+        let Ok(comm) = fs::read_to_string(entry.path().join("comm")) else {
+            continue;
+        };
+        let name = comm.trim();
+        if name.to_lowercase().starts_with("qbittorrent") {
+            signs.push(format!(
+                "{name} is running with PID {}",
+                entry.file_name().to_string_lossy()
+            ));
+        }
+    }
+
+    signs
+}
+
+/// Checks for a running qB process, only supported on Linux
+#[cfg(not(target_os = "linux"))]
+fn process_signs() -> Vec<String> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[test]
+    fn closed_database_is_not_in_use() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+
+        assert_eq!(database_signs(&config), Vec::<String>::new());
+        assert!(lockfile_signs(&config)
+            .iter()
+            .all(|sign| !sign.contains(dir.path().to_str().unwrap())));
+    }
+
+    #[test]
+    fn non_empty_wal_file_is_a_sign() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+        let wal_file = dir.path().join("torrents.db-wal");
+
+        fs::write(&wal_file, "").unwrap();
+        assert!(database_signs(&config).is_empty());
+
+        fs::write(&wal_file, "not checkpointed").unwrap();
+        let signs = database_signs(&config);
+        assert_eq!(signs.len(), 1);
+        assert!(signs[0].contains("contains changes"), "{signs:?}");
+    }
+
+    #[test]
+    fn write_transaction_is_a_sign() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+
+        let db = Connection::open(&config.db_file).unwrap();
+        db.execute_batch("BEGIN EXCLUSIVE; UPDATE torrents SET queue_position = queue_position;")
+            .unwrap();
+
+        let signs = database_signs(&config);
+        assert_eq!(signs.len(), 1);
+        assert!(
+            signs[0].contains("is in use by another program"),
+            "{signs:?}"
+        );
+    }
+
+    #[test]
+    fn lock_check_does_not_change_the_database() {
+        let dir = test_util::qb_dir();
+        let db = Connection::open(dir.path().join("torrents.db")).unwrap();
+        db.pragma_update(None, "journal_mode", "WAL").unwrap();
+        db.pragma_update(None, "wal_autocheckpoint", 0).unwrap();
+        db.execute(
+            "UPDATE torrents SET queue_position = queue_position + 1",
+            [],
+        )
+        .unwrap();
+
+        // Copy the files while the database is open, like after qB crashed
+        let crashed = tempfile::tempdir().unwrap();
+        for name in ["torrents.db", "torrents.db-wal"] {
+            fs::copy(dir.path().join(name), crashed.path().join(name)).unwrap();
+        }
+        let db_data = fs::read(crashed.path().join("torrents.db")).unwrap();
+        let wal_data = fs::read(crashed.path().join("torrents.db-wal")).unwrap();
+        assert!(!wal_data.is_empty());
+
+        let config = test_util::qb_config(&crashed, &[]);
+        lock_database(&config).unwrap();
+
+        assert_eq!(
+            fs::read(crashed.path().join("torrents.db")).unwrap(),
+            db_data
+        );
+        assert_eq!(
+            fs::read(crashed.path().join("torrents.db-wal")).unwrap(),
+            wal_data
+        );
+        let signs = database_signs(&config);
+        assert_eq!(signs.len(), 1);
+        assert!(signs[0].contains("contains changes"), "{signs:?}");
+    }
+
+    #[test]
+    fn unlocked_lockfile_is_not_a_sign() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+        fs::write(dir.path().join("lockfile"), "").unwrap();
+
+        assert!(lockfile_signs(&config)
+            .iter()
+            .all(|sign| !sign.contains(dir.path().to_str().unwrap())));
+    }
+
+    /// Takes a shared lock on a range of a file in a child process, a length of 0 covers the whole
+    /// file
+    ///
+    /// `F_GETLK` does not report locks of the calling process, so the lock has to be taken by
+    /// another one. The child only makes async-signal-safe calls and waits until it is killed.
+    #[cfg(unix)]
+    fn lock_in_child(file: &std::path::Path, start: libc::off_t, len: libc::off_t) -> libc::pid_t {
+        use std::ffi::CString;
+
+        let path = CString::new(file.to_str().unwrap()).unwrap();
+        let mut pipe = [0; 2];
+        // SAFETY: pipe is a valid array of two file descriptors
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+
+        // SAFETY: the child only calls async-signal-safe functions before it is killed
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            // SAFETY: all pointers are valid for the duration of the calls
+            unsafe {
+                let fd = libc::open(path.as_ptr(), libc::O_RDWR);
+                let mut lock: libc::flock = std::mem::zeroed();
+                lock.l_type = libc::F_RDLCK as libc::c_short;
+                lock.l_whence = libc::SEEK_SET as libc::c_short;
+                lock.l_start = start;
+                lock.l_len = len;
+                libc::fcntl(fd, libc::F_SETLK, &lock);
+                libc::write(pipe[1], b"1".as_ptr().cast(), 1);
+                loop {
+                    libc::pause();
+                }
+            }
+        }
+
+        let mut ready = [0u8; 1];
+        // SAFETY: ready is a valid buffer of one byte
+        unsafe {
+            libc::read(pipe[0], ready.as_mut_ptr().cast(), 1);
+            libc::close(pipe[0]);
+            libc::close(pipe[1]);
+        }
+
+        pid
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn locked_lockfile_is_a_sign() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+        let lockfile = dir.path().join("lockfile");
+        fs::write(&lockfile, "").unwrap();
+
+        let pid = lock_in_child(&lockfile, 0, 0);
+        let signs = lockfile_signs(&config);
+        let running = check_not_running(&config);
+        let forced = check_not_running(&test_util::qb_config(&dir, &["--force"]));
+        // SAFETY: pid is the child process started above
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+
+        assert!(
+            signs
+                .iter()
+                .any(|sign| sign.contains(&format!("is locked by process {pid}"))),
+            "{signs:?}"
+        );
+        assert!(running.is_err());
+        assert!(forced.is_ok());
+    }
+
+    /// Stops a child process started by `lock_in_child`
+    #[cfg(unix)]
+    fn kill_child(pid: libc::pid_t) {
+        // SAFETY: pid is a child process of this one
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reading_process_is_a_sign() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+
+        // The first byte of the shared range, which SQLite locks while reading
+        let pid = lock_in_child(&config.db_file, 0x4000_0002, 1);
+        let signs = database_signs(&config);
+        kill_child(pid);
+
+        assert_eq!(signs.len(), 1);
+        assert!(
+            signs[0].contains(&format!("locked by process {pid}")),
+            "{signs:?}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wal_database_open_in_another_process_is_a_sign() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+        let shm_file = dir.path().join("torrents.db-shm");
+        fs::write(&shm_file, "").unwrap();
+        assert!(database_signs(&config).is_empty());
+
+        // The DMS byte, which every connection to a WAL database locks while it is open
+        let pid = lock_in_child(&shm_file, 128, 1);
+        let signs = database_signs(&config);
+        kill_child(pid);
+
+        assert_eq!(signs.len(), 1);
+        assert!(
+            signs[0].contains(&format!("is open in process {pid}")),
+            "{signs:?}"
+        );
+    }
+}
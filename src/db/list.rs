@@ -0,0 +1,404 @@
+//! Tools for listing torrent information
+
+use crate::common::database::DatabaseRow;
+use crate::common::fastresume::Fastresume;
+use crate::common::metadata::Metadata;
+use crate::config::Config;
+use crate::storage::Storage;
+use chrono::{Local, TimeZone};
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Output format for the torrent list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Aligned plain text table
+    Table,
+    /// JSON array of objects
+    Json,
+    /// CSV with a header row
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<OutputFormat, String> {
+        match format {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!(
+                "unknown format '{format}', expected table, json or csv"
+            )),
+        }
+    }
+}
+
+/// Column of the torrent list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    /// Torrent hash
+    Hash,
+    /// Torrent name
+    Name,
+    /// Torrent category
+    Category,
+    /// Comma-separated list of tags
+    Tags,
+    /// Effective save path
+    SavePath,
+    /// Tracker URLs
+    Trackers,
+    /// Total size of the torrent content, in bytes
+    Size,
+    /// Total number of bytes uploaded
+    Uploaded,
+    /// Total number of bytes downloaded
+    Downloaded,
+    /// Time the torrent was added
+    Added,
+    /// Time the torrent was completed
+    Completed,
+    /// Torrent state, e.g. seeding or stopped
+    State,
+}
+
+impl Column {
+    /// All columns, in their default order
+    pub const ALL: [Column; 12] = [
+        Column::Hash,
+        Column::Name,
+        Column::Category,
+        Column::Tags,
+        Column::SavePath,
+        Column::Trackers,
+        Column::Size,
+        Column::Uploaded,
+        Column::Downloaded,
+        Column::Added,
+        Column::Completed,
+        Column::State,
+    ];
+
+    /// Column name, as used for `--columns` and in the output header
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Hash => "hash",
+            Column::Name => "name",
+            Column::Category => "category",
+            Column::Tags => "tags",
+            Column::SavePath => "save_path",
+            Column::Trackers => "trackers",
+            Column::Size => "size",
+            Column::Uploaded => "uploaded",
+            Column::Downloaded => "downloaded",
+            Column::Added => "added",
+            Column::Completed => "completed",
+            Column::State => "state",
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Column, String> {
+        Column::ALL
+            .into_iter()
+            .find(|column| column.name() == name)
+            .ok_or_else(|| format!("unknown column '{name}'"))
+    }
+}
+
+/// Prints information about each torrent as a table, JSON or CSV
+///
+/// The database columns are combined with the decoded libtorrent resume data and metadata, so
+/// no external tool is needed to look inside the blobs.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::list;
+/// let config = Config { list_format: OutputFormat::Json, list_columns: vec![Column::Hash] };
+/// list::list_torrents(&storage, config);
+/// ```
+///
+/// ## Configuration
+/// - The output format is set by config.list_format.
+/// - The columns are set by config.list_columns, in the given order.
+pub fn list_torrents(storage: &dyn Storage, config: &Config) -> Result<(), Box<dyn Error>> {
+    write_torrents(storage, config, &mut io::stdout().lock())
+}
+
+/// Writes the torrent list to any writer, see `list_torrents`
+fn write_torrents(
+    storage: &dyn Storage,
+    config: &Config,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let columns = &config.list_columns;
+
+    let mut rows = Vec::new();
+    for torrent in config.filter.select(storage.torrents()?)? {
+        let resume_data: Fastresume = serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?;
+        // Torrents added by magnet link have no metadata until it is downloaded
+        let metadata = torrent
+            .metadata
+            .as_deref()
+            .and_then(|metadata| Metadata::from_bytes(metadata).ok());
+
+        let row: Vec<Value> = columns
+            .iter()
+            .map(|column| column_value(*column, &torrent, &resume_data, metadata.as_ref()))
+            .collect();
+        rows.push(row);
+    }
+
+    match config.list_format {
+        OutputFormat::Table => write_table(out, columns, &rows)?,
+        OutputFormat::Json => {
+            let objects: Vec<Map<String, Value>> = rows
+                .into_iter()
+                .map(|row| {
+                    columns
+                        .iter()
+                        .map(|column| column.name().to_string())
+                        .zip(row)
+                        .collect()
+                })
+                .collect();
+            writeln!(out, "{}", serde_json::to_string_pretty(&objects)?)?;
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(columns.iter().map(Column::name))?;
+            for row in &rows {
+                writer.write_record(row.iter().map(display_value))?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the value of a single column for a torrent
+fn column_value(
+    column: Column,
+    torrent: &DatabaseRow,
+    resume_data: &Fastresume,
+    metadata: Option<&Metadata>,
+) -> Value {
+    match column {
+        Column::Hash => json!(torrent.torrent_id),
+        Column::Name => json!(torrent
+            .name
+            .clone()
+            .or_else(|| metadata.map(|metadata| metadata.info.name.clone()))),
+        Column::Category => json!(torrent.category),
+        Column::Tags => json!(torrent.tags),
+        // The target_save_path is absent if the torrent is in AutoTMM mode
+        Column::SavePath => json!(torrent
+            .target_save_path
+            .clone()
+            .unwrap_or_else(|| String::from_utf8_lossy(&resume_data.save_path).into_owned())),
+        Column::Trackers => json!(resume_data.trackers),
+        Column::Size => json!(metadata.and_then(|metadata| metadata.total_size().ok())),
+        Column::Uploaded => json!(resume_data.total_uploaded),
+        Column::Downloaded => json!(resume_data.total_downloaded),
+        Column::Added => json!(format_time(resume_data.added_time)),
+        Column::Completed => json!(format_time(resume_data.completed_time)),
+        Column::State => {
+            let state = match (torrent.stopped != 0, torrent.has_seed_status != 0) {
+                (true, _) => "stopped",
+                (false, true) => "seeding",
+                (false, false) => "downloading",
+            };
+            match torrent.operating_mode.as_str() {
+                "Forced" => json!(format!("{state} (forced)")),
+                _ => json!(state),
+            }
+        }
+    }
+}
+
+/// Formats a Unix timestamp as local time, `None` if the time is not set
+fn format_time(timestamp: i64) -> Option<String> {
+    if timestamp <= 0 {
+        return None;
+    }
+
+    Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+/// Formats a value for plain text output
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(value) => value.clone(),
+        // Trackers are a list of tiers
+        Value::Array(values) => values
+            .iter()
+            .map(display_value)
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<String>>()
+            .join(", "),
+        value => value.to_string(),
+    }
+}
+
+/// Writes the rows as a table with aligned columns
+fn write_table(out: &mut dyn Write, columns: &[Column], rows: &[Vec<Value>]) -> io::Result<()> {
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| row.iter().map(display_value).collect())
+        .collect();
+
+    let mut widths: Vec<usize> = columns.iter().map(|column| column.name().len()).collect();
+    for row in &rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.chars().count());
+        }
+    }
+
+    let mut write_row = |values: Vec<&str>| {
+        let line: Vec<String> = values
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{value:<width$}"))
+            .collect();
+        writeln!(out, "{}", line.join("  ").trim_end())
+    };
+
+    write_row(columns.iter().map(Column::name).collect())?;
+    for row in &rows {
+        write_row(row.iter().map(String::as_str).collect())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, MAGNET, MAGNET_ID, SEEDING, SEEDING_ID, TORRENT};
+
+    fn values(torrent: &DatabaseRow, metadata: Option<&Metadata>) -> Vec<Value> {
+        let resume_data = serde_bencode::from_bytes(&torrent.libtorrent_resume_data).unwrap();
+        Column::ALL
+            .into_iter()
+            .map(|column| column_value(column, torrent, &resume_data, metadata))
+            .collect()
+    }
+
+    #[test]
+    fn parses_columns_in_the_given_order() {
+        let config = Config::from_args(&["--list", "--columns", "size, hash,save_path"]).unwrap();
+        assert_eq!(
+            config.list_columns,
+            vec![Column::Size, Column::Hash, Column::SavePath]
+        );
+
+        let config = Config::from_args(&["--list"]).unwrap();
+        assert_eq!(config.list_columns, Column::ALL.to_vec());
+        assert_eq!(config.list_format, OutputFormat::Table);
+    }
+
+    #[test]
+    fn unknown_columns_and_formats_are_an_error() {
+        let error = Config::from_args(&["--list", "--columns", "hash,ratio"]).unwrap_err();
+        assert_eq!(error, "unknown column 'ratio'");
+
+        let error = Config::from_args(&["--list", "--format", "xml"]).unwrap_err();
+        assert_eq!(error, "unknown format 'xml', expected table, json or csv");
+    }
+
+    #[test]
+    fn column_names_round_trip() {
+        for column in Column::ALL {
+            assert_eq!(column.name().parse::<Column>(), Ok(column));
+        }
+    }
+
+    #[test]
+    fn values_come_from_the_blobs() {
+        let torrent = test_util::torrent(SEEDING_ID, SEEDING, Some(TORRENT));
+        let metadata = Metadata::from_bytes(TORRENT).unwrap();
+        let values = values(&torrent, Some(&metadata));
+
+        assert_eq!(values[0], json!(SEEDING_ID));
+        assert_eq!(values[1], json!("Show.S01"));
+        assert_eq!(values[2], json!("tv"));
+        assert_eq!(values[4], json!("/data/tv"));
+        assert_eq!(values[6], json!(40000));
+        assert_eq!(values[7], json!(120000));
+        assert_eq!(values[8], json!(40000));
+        assert_eq!(
+            display_value(&values[5]),
+            "http://tracker.example.org:6969/announce, udp://backup.example.org:1337/announce"
+        );
+    }
+
+    #[test]
+    fn magnet_links_have_no_size() {
+        let torrent = test_util::torrent(MAGNET_ID, MAGNET, None);
+        let values = values(&torrent, None);
+
+        assert_eq!(values[6], Value::Null);
+        assert_eq!(display_value(&values[6]), "");
+        assert_eq!(display_value(&values[10]), "");
+    }
+
+    /// Lists the loaded seeding torrent and magnet link with the given arguments
+    fn list(args: &[&str]) -> String {
+        let mut all_args = vec!["--list"];
+        all_args.extend(args);
+        let config = Config::from_args(&all_args).unwrap();
+
+        let mut out = Vec::new();
+        write_torrents(&test_util::loaded_storage(), &config, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn json_output_has_an_object_per_torrent() {
+        let output = list(&["--format", "json", "--columns", "hash,size,trackers"]);
+        let torrents: Value = serde_json::from_str(&output).unwrap();
+        let trackers = json!([
+            ["http://tracker.example.org:6969/announce"],
+            ["udp://backup.example.org:1337/announce"]
+        ]);
+
+        assert_eq!(
+            torrents,
+            json!([
+                {"hash": MAGNET_ID, "size": null, "trackers": trackers},
+                {"hash": SEEDING_ID, "size": 40000, "trackers": trackers}
+            ])
+        );
+    }
+
+    #[test]
+    fn csv_output_has_a_header_row() {
+        let output = list(&["--format", "csv", "--columns", "hash,name,tags,size"]);
+
+        assert_eq!(
+            output,
+            format!(
+                "hash,name,tags,size\n{MAGNET_ID},,,\n{SEEDING_ID},Show.S01,\"hd,keep\",40000\n"
+            )
+        );
+    }
+
+    #[test]
+    fn table_output_aligns_columns() {
+        let output = list(&["--columns", "name,size"]);
+
+        assert_eq!(output, "name      size\n\nShow.S01  40000\n");
+    }
+}
@@ -0,0 +1,131 @@
+//! Tools for loading fastresume files into the SQLite database
+
+use crate::config::Config;
+use crate::storage::bt_backup::BtBackupStorage;
+use crate::storage::Storage;
+use std::error::Error;
+
+/// Loads a BT_backup directory of fastresume files into the SQLite database
+///
+/// This is the reverse of `dump_db::to_fastresume`. Each `<hash>.fastresume` file is paired with its
+/// `<hash>.torrent` file, the `qBt-*` keys are moved into their database columns, and the torrent is
+/// inserted into the `torrents` table. Torrents added by magnet link have no `.torrent` file until
+/// their metadata is downloaded, so they are inserted with NULL metadata just like qB stores them.
+/// *NOTE: TORRENTS THAT ALREADY EXIST IN THE DATABASE WILL BE OVERWRITTEN!*
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::load_db;
+/// let config = Config::build()?;
+/// load_db::from_fastresume(&mut storage, &config)?;
+/// ```
+///
+/// ## Configuration
+/// - You can configure the input directory by setting config.input_directory, otherwise it defaults
+///   to the `BT_backup` directory in the qB directory.
+///
+/// ## Verbose output
+/// If verbose output is enabled it will output the torrent hash for each torrent loaded.
+pub fn from_fastresume(storage: &mut dyn Storage, config: &Config) -> Result<(), Box<dyn Error>> {
+    println!("fastresume -> DB: loading fastresume files...");
+
+    let mut num_torrents_loaded = 0;
+
+    let dir_path = config.bt_backup_directory();
+    if config.verbose {
+        println!("fastresume -> DB: input directory: {:?}", dir_path);
+    }
+
+    let torrents = BtBackupStorage::open(config)?.torrents()?;
+    for torrent in config.filter.select(torrents)? {
+        storage.insert(&torrent)?;
+
+        if config.verbose {
+            match torrent.metadata {
+                Some(_) => println!("fastresume -> DB: loaded {}", torrent.torrent_id),
+                None => println!(
+                    "fastresume -> DB: loaded {} without metadata, no .torrent file found",
+                    torrent.torrent_id
+                ),
+            }
+        }
+
+        num_torrents_loaded += 1;
+    }
+
+    match num_torrents_loaded {
+        0 => println!("fastresume -> DB: no torrents were loaded"),
+        1 => println!("fastresume -> DB: 1 torrent was loaded"),
+        _ => println!(
+            "fastresume -> DB: {} torrents were loaded",
+            num_torrents_loaded
+        ),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sqlite::SqliteStorage;
+    use crate::test_util::{self, MAGNET_ID, SEEDING_ID};
+
+    #[test]
+    fn loads_fastresume_files_in_queue_order() {
+        let dir = test_util::bt_backup_dir();
+        let mut storage = SqliteStorage::new(test_util::database()).unwrap();
+        let config = test_util::bt_backup_config(&dir, &["--verbose"]);
+
+        from_fastresume(&mut storage, &config).unwrap();
+
+        let db = storage.connection();
+        let (queue_position, category, tags): (i64, String, String) = db
+            .query_row(
+                "SELECT queue_position, category, tags FROM torrents WHERE torrent_id = ?1",
+                [SEEDING_ID],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(queue_position, 1);
+        assert_eq!(category, "tv");
+        assert_eq!(tags, "hd,keep");
+
+        // Loading again replaces the torrent instead of failing on the existing hash
+        from_fastresume(&mut storage, &config).unwrap();
+        let db = storage.connection();
+        let count: i64 = db
+            .query_row(
+                "SELECT COUNT(*) FROM torrents WHERE torrent_id = ?1",
+                [SEEDING_ID],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn loads_magnet_links_without_metadata() {
+        let dir = test_util::bt_backup_dir();
+        let mut storage = SqliteStorage::new(test_util::database()).unwrap();
+        let config = test_util::bt_backup_config(&dir, &[]);
+
+        from_fastresume(&mut storage, &config).unwrap();
+
+        let torrents: Vec<(String, i64, bool)> = storage
+            .connection()
+            .prepare("SELECT torrent_id, queue_position, metadata IS NULL FROM torrents ORDER BY queue_position")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            torrents,
+            [
+                (MAGNET_ID.to_string(), 0, true),
+                (SEEDING_ID.to_string(), 1, false)
+            ]
+        );
+    }
+}
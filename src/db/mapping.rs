@@ -0,0 +1,604 @@
+//! Tools for applying many save path and tracker replacements in one run
+
+use crate::config::Config;
+use crate::db;
+use crate::db::save_path::{PathMatch, PathScope, SavePath};
+use crate::db::tracker_url::TrackerUrl;
+use crate::storage::Storage;
+use serde_derive::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// How the rules of a mapping file are applied to a torrent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MappingMode {
+    /// Only the first matching save path rule and tracker rule are applied
+    FirstMatch,
+    /// All rules are applied in order, each to the result of the previous ones
+    Ordered,
+}
+
+impl FromStr for MappingMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<MappingMode, String> {
+        match mode {
+            "first-match" => Ok(MappingMode::FirstMatch),
+            "ordered" => Ok(MappingMode::Ordered),
+            _ => Err(format!(
+                "unknown mapping mode '{mode}', expected first-match or ordered"
+            )),
+        }
+    }
+}
+
+/// A replacement rule as written in a mapping file
+///
+/// Unknown keys are rejected, so a misspelled option can not silently fall back to the default
+/// case-sensitive substring match.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// "save_path" or "tracker", only allowed in CSV files
+    #[serde(default)]
+    kind: Option<String>,
+    /// Existing save path or tracker URL
+    old: String,
+    /// New save path or tracker URL
+    new: String,
+    /// Match mode, see `--path-match` and `--tracker-match`
+    #[serde(default, rename = "match")]
+    match_mode: Option<String>,
+    /// Matches a save path case-insensitively, not allowed for tracker rules
+    #[serde(default)]
+    ignore_case: Option<bool>,
+    /// Which save paths are replaced, see `--path-scope`, not allowed for tracker rules
+    #[serde(default)]
+    scope: Option<String>,
+}
+
+/// Contents of a TOML mapping file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct MappingFile {
+    #[serde(default)]
+    save_path: Vec<Rule>,
+    #[serde(default)]
+    tracker: Vec<Rule>,
+}
+
+/// Save path and tracker replacements read from a mapping file
+#[derive(Debug)]
+pub struct Mapping {
+    /// How the rules are applied to a torrent
+    pub mode: MappingMode,
+    /// Save path rules, in file order
+    pub save_paths: Vec<SavePath>,
+    /// Tracker rules, in file order
+    pub tracker_urls: Vec<TrackerUrl>,
+}
+
+impl Mapping {
+    /// Reads a TOML or CSV mapping file, depending on its extension
+    ///
+    /// A TOML file has a `[[save_path]]` or `[[tracker]]` table per rule. A CSV file has a header
+    /// row and a `kind` column that is either `save_path` or `tracker`. Each rule has an `old` and
+    /// a `new` value, and optionally a `match` mode. Save path rules may also have an `ignore_case`
+    /// flag and a `scope`.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let mapping = Mapping::load(Path::new("mapping.toml"), MappingMode::FirstMatch, "/")?;
+    /// println!("{} save path rules", mapping.save_paths.len());
+    /// ```
+    ///
+    /// ```toml
+    /// [[save_path]]
+    /// old = "/mnt/disk1/movies"
+    /// new = "/pool/movies"
+    /// match = "prefix"
+    ///
+    /// [[tracker]]
+    /// old = "http://old.tracker"
+    /// new = "https://new.tracker"
+    /// ```
+    pub fn load(path: &Path, mode: MappingMode, separator: &str) -> Result<Mapping, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("could not read {:?}: {err}", path.display()))?;
+
+        let (save_path_rules, tracker_rules) =
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("toml") => {
+                    let file: MappingFile = toml::from_str(&contents)
+                        .map_err(|err| format!("invalid mapping file: {err}"))?;
+                    // The table a rule is in already is its kind
+                    if file
+                        .save_path
+                        .iter()
+                        .chain(&file.tracker)
+                        .any(|rule| rule.kind.is_some())
+                    {
+                        return Err(String::from(
+                            "invalid mapping file: 'kind' is only used in CSV files, use \
+                             [[save_path]] and [[tracker]] tables instead",
+                        ));
+                    }
+                    (file.save_path, file.tracker)
+                }
+                Some("csv") => {
+                    let mut save_path_rules = Vec::new();
+                    let mut tracker_rules = Vec::new();
+                    let mut reader = csv::ReaderBuilder::new()
+                        .trim(csv::Trim::All)
+                        .from_reader(contents.as_bytes());
+                    for rule in reader.deserialize::<Rule>() {
+                        let rule = rule.map_err(|err| format!("invalid mapping file: {err}"))?;
+                        match rule.kind.as_deref() {
+                            Some("save_path") => save_path_rules.push(rule),
+                            Some("tracker") => tracker_rules.push(rule),
+                            kind => {
+                                return Err(format!(
+                                    "invalid mapping file: unknown kind '{}', expected \
+                                     save_path or tracker",
+                                    kind.unwrap_or_default()
+                                ))
+                            }
+                        }
+                    }
+                    (save_path_rules, tracker_rules)
+                }
+                _ => {
+                    return Err(format!(
+                        "{:?} is not a .toml or .csv mapping file",
+                        path.display()
+                    ))
+                }
+            };
+
+        let mut save_paths = Vec::new();
+        for (index, rule) in save_path_rules.iter().enumerate() {
+            let save_path = rule
+                .match_mode
+                .as_deref()
+                .unwrap_or("substring")
+                .parse::<PathMatch>()
+                .and_then(|match_mode| {
+                    let scope = rule
+                        .scope
+                        .as_deref()
+                        .unwrap_or("both")
+                        .parse::<PathScope>()?;
+                    SavePath::new(
+                        &rule.old,
+                        &rule.new,
+                        separator,
+                        match_mode,
+                        rule.ignore_case.unwrap_or(false),
+                        scope,
+                    )
+                });
+            save_paths
+                .push(save_path.map_err(|err| format!("save path rule {}: {err}", index + 1))?);
+        }
+
+        let mut tracker_urls = Vec::new();
+        for (index, rule) in tracker_rules.iter().enumerate() {
+            if rule.ignore_case.is_some() || rule.scope.is_some() {
+                return Err(format!(
+                    "tracker rule {}: ignore_case and scope only apply to save path rules",
+                    index + 1
+                ));
+            }
+            let regex = match rule.match_mode.as_deref() {
+                None | Some("substring") => false,
+                Some("regex") => true,
+                Some(mode) => {
+                    return Err(format!(
+                        "tracker rule {}: unknown tracker match mode '{mode}', expected \
+                         substring or regex",
+                        index + 1
+                    ))
+                }
+            };
+            tracker_urls.push(
+                TrackerUrl::new(&rule.old, &rule.new, regex)
+                    .map_err(|err| format!("tracker rule {}: {err}", index + 1))?,
+            );
+        }
+
+        if save_paths.is_empty() && tracker_urls.is_empty() {
+            return Err(format!("{:?} contains no rules", path.display()));
+        }
+
+        Ok(Mapping {
+            mode,
+            save_paths,
+            tracker_urls,
+        })
+    }
+}
+
+/// Returns the numbers of the rules that match a torrent, starting at 1
+fn matching_rules<T>(
+    rules: &[T],
+    matches: impl Fn(&T) -> Result<bool, Box<dyn Error>>,
+) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut numbers = Vec::new();
+    for (index, rule) in rules.iter().enumerate() {
+        if matches(rule)? {
+            numbers.push(index + 1);
+        }
+    }
+
+    Ok(numbers)
+}
+
+/// Applies all save path and tracker rules of a mapping file in a single pass over the torrents
+///
+/// With `MappingMode::FirstMatch` only the first matching save path rule and the first matching
+/// tracker rule are applied to a torrent. With `MappingMode::Ordered` every rule is applied in
+/// order, each to the result of the previous ones. Either way, a torrent whose current save path
+/// or trackers match more than one rule is reported as a conflict.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::mapping;
+/// mapping::apply_mapping(&mut storage, &mapping, &config)?;
+/// ```
+///
+/// ## Verbose output
+/// If verbose output is enabled it will output the torrent hash and the rules applied to it.
+pub fn apply_mapping(
+    storage: &mut dyn Storage,
+    mapping: &Mapping,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    println!(
+        "Mapping: applying {} save path and {} tracker rules",
+        mapping.save_paths.len(),
+        mapping.tracker_urls.len()
+    );
+
+    let mut save_path_hits = vec![0; mapping.save_paths.len()];
+    let mut tracker_hits = vec![0; mapping.tracker_urls.len()];
+    let mut num_conflicts = 0;
+    let mut num_torrents_updated = 0;
+    for mut torrent in config.filter.select(storage.torrents()?)? {
+        let save_path_matches = matching_rules(&mapping.save_paths, |rule| rule.matches(&torrent))?;
+        let tracker_matches = matching_rules(&mapping.tracker_urls, |rule| rule.matches(&torrent))?;
+        for (kind, matches) in [
+            ("save path", &save_path_matches),
+            ("tracker", &tracker_matches),
+        ] {
+            if matches.len() > 1 {
+                eprintln!(
+                    "Mapping: conflict, {} matches {kind} rules {matches:?}",
+                    torrent.torrent_id
+                );
+                num_conflicts += 1;
+            }
+        }
+
+        let mut applied = Vec::new();
+        match mapping.mode {
+            MappingMode::FirstMatch => {
+                if let Some(&number) = save_path_matches.first() {
+                    mapping.save_paths[number - 1].rewrite(&mut torrent)?;
+                    save_path_hits[number - 1] += 1;
+                    applied.push(format!("save path rule {number}"));
+                }
+                if let Some(&number) = tracker_matches.first() {
+                    mapping.tracker_urls[number - 1].rewrite(&mut torrent)?;
+                    tracker_hits[number - 1] += 1;
+                    applied.push(format!("tracker rule {number}"));
+                }
+            }
+            MappingMode::Ordered => {
+                for (index, rule) in mapping.save_paths.iter().enumerate() {
+                    if rule.rewrite(&mut torrent)?.is_some() {
+                        save_path_hits[index] += 1;
+                        applied.push(format!("save path rule {}", index + 1));
+                    }
+                }
+                for (index, rule) in mapping.tracker_urls.iter().enumerate() {
+                    if rule.rewrite(&mut torrent)?.is_some() {
+                        tracker_hits[index] += 1;
+                        applied.push(format!("tracker rule {}", index + 1));
+                    }
+                }
+            }
+        }
+        if applied.is_empty() {
+            continue;
+        }
+
+        storage.update(&torrent)?;
+
+        if config.verbose {
+            println!(
+                "Mapping: applied {} to {}",
+                applied.join(", "),
+                torrent.torrent_id
+            );
+        }
+
+        num_torrents_updated += 1;
+    }
+
+    let torrents = |hits: usize| match hits {
+        1 => String::from("1 torrent"),
+        _ => format!("{hits} torrents"),
+    };
+    for (index, (rule, hits)) in mapping.save_paths.iter().zip(save_path_hits).enumerate() {
+        println!(
+            "Mapping: save path rule {} '{}' -> '{}' matched {}",
+            index + 1,
+            rule.old,
+            rule.new,
+            torrents(hits)
+        );
+    }
+    for (index, (rule, hits)) in mapping.tracker_urls.iter().zip(tracker_hits).enumerate() {
+        println!(
+            "Mapping: tracker rule {} '{}' -> '{}' matched {}",
+            index + 1,
+            rule.old,
+            rule.new,
+            torrents(hits)
+        );
+    }
+    match num_conflicts {
+        0 => {}
+        1 => println!("Mapping: 1 conflict, see the warning above"),
+        _ => println!("Mapping: {num_conflicts} conflicts, see the warnings above"),
+    }
+
+    let verb = db::verb(config, num_torrents_updated);
+    match num_torrents_updated {
+        0 => println!("Mapping: no torrents {verb} updated"),
+        1 => println!("Mapping: 1 torrent {verb} updated"),
+        _ => println!("Mapping: {num_torrents_updated} torrents {verb} updated"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, SEEDING_ID};
+
+    /// Writes a mapping file with the given extension and loads it
+    fn load(extension: &str, contents: &str, mode: MappingMode) -> Result<Mapping, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("mapping.{extension}"));
+        fs::write(&path, contents).unwrap();
+        Mapping::load(&path, mode, "/")
+    }
+
+    /// Applies a TOML mapping to the loaded torrents and returns the new save path of the seeding
+    /// torrent
+    fn apply(contents: &str, mode: MappingMode) -> String {
+        let mapping = load("toml", contents, mode).unwrap();
+        let mut storage = test_util::loaded_storage();
+        let config = Config::from_args(&[]).unwrap();
+
+        apply_mapping(&mut storage, &mapping, &config).unwrap();
+
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        torrent.target_save_path.unwrap()
+    }
+
+    #[test]
+    fn loads_toml_rules() {
+        let mapping = load(
+            "toml",
+            r#"
+            [[save_path]]
+            old = "/mnt/disk1/movies"
+            new = "/pool/movies"
+            match = "prefix"
+
+            [[save_path]]
+            old = 'D:\Media'
+            new = "/pool/media"
+            match = "segment"
+            ignore_case = true
+
+            [[tracker]]
+            old = "http://old.tracker"
+            new = "https://new.tracker"
+            "#,
+            MappingMode::Ordered,
+        )
+        .unwrap();
+
+        assert_eq!(mapping.mode, MappingMode::Ordered);
+        assert_eq!(mapping.save_paths.len(), 2);
+        assert_eq!(mapping.save_paths[0].match_mode, PathMatch::Prefix);
+        assert!(!mapping.save_paths[0].ignore_case);
+        assert_eq!(mapping.save_paths[1].old, "D:\\Media");
+        assert_eq!(mapping.save_paths[1].match_mode, PathMatch::Segment);
+        assert!(mapping.save_paths[1].ignore_case);
+        assert_eq!(mapping.tracker_urls.len(), 1);
+        assert_eq!(mapping.tracker_urls[0].new, "https://new.tracker");
+    }
+
+    #[test]
+    fn loads_csv_rules() {
+        let mapping = load(
+            "csv",
+            "kind, old, new, match, ignore_case\n\
+             save_path, /mnt/disk1, /pool, prefix, true\n\
+             tracker, ^http://(.*), https://$1, regex,\n\
+             save_path, /downloads, /pool/downloads, ,\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap();
+
+        assert_eq!(mapping.save_paths.len(), 2);
+        assert_eq!(mapping.save_paths[0].match_mode, PathMatch::Prefix);
+        assert!(mapping.save_paths[0].ignore_case);
+        assert_eq!(mapping.save_paths[1].old, "/downloads");
+        assert_eq!(mapping.save_paths[1].match_mode, PathMatch::Substring);
+        assert_eq!(mapping.tracker_urls.len(), 1);
+        assert!(mapping.tracker_urls[0].regex.is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let error = load(
+            "toml",
+            "[[savepath]]\nold = \"/a\"\nnew = \"/b\"\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap_err();
+        assert!(error.starts_with("invalid mapping file"), "{error}");
+
+        let error = load("csv", "kind,old,new\npath,/a,/b\n", MappingMode::FirstMatch).unwrap_err();
+        assert_eq!(
+            error,
+            "invalid mapping file: unknown kind 'path', expected save_path or tracker"
+        );
+
+        let error = load("toml", "", MappingMode::FirstMatch).unwrap_err();
+        assert!(error.ends_with("contains no rules"), "{error}");
+
+        let error = load("json", "{}", MappingMode::FirstMatch).unwrap_err();
+        assert!(
+            error.ends_with("is not a .toml or .csv mapping file"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_rule_keys() {
+        let error = load(
+            "toml",
+            "[[save_path]]\nold = \"/a\"\nnew = \"/b\"\nignorecase = true\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap_err();
+        assert!(error.contains("unknown field `ignorecase`"), "{error}");
+
+        let error = load(
+            "csv",
+            "kind,old,new,mode\nsave_path,/a,/b,prefix\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap_err();
+        assert!(error.contains("unknown field `mode`"), "{error}");
+    }
+
+    #[test]
+    fn rejects_kind_in_toml_files() {
+        let error = load(
+            "toml",
+            "[[save_path]]\nkind = \"tracker\"\nold = \"/a\"\nnew = \"/b\"\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap_err();
+        assert!(
+            error.contains("'kind' is only used in CSV files"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn rejects_save_path_options_on_tracker_rules() {
+        let error = load(
+            "toml",
+            "[[tracker]]\nold = \"http://a\"\nnew = \"http://b\"\nscope = \"save\"\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "tracker rule 1: ignore_case and scope only apply to save path rules"
+        );
+
+        let error = load(
+            "csv",
+            "kind,old,new,ignore_case\n\
+             save_path,/a,/b,true\n\
+             tracker,http://a,http://b,true\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "tracker rule 1: ignore_case and scope only apply to save path rules"
+        );
+    }
+
+    #[test]
+    fn reports_the_failing_rule() {
+        let error = load(
+            "toml",
+            "[[save_path]]\nold = \"/a\"\nnew = \"/b\"\n\n\
+             [[save_path]]\nold = \"/c\"\nnew = \"/d\"\nmatch = \"glob\"\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap_err();
+        assert!(
+            error.starts_with("save path rule 2: unknown path match mode"),
+            "{error}"
+        );
+
+        let error = load(
+            "toml",
+            "[[tracker]]\nold = \"(\"\nnew = \"/b\"\nmatch = \"regex\"\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap_err();
+        assert!(error.starts_with("tracker rule 1: invalid"), "{error}");
+    }
+
+    #[test]
+    fn overlapping_rules_are_a_conflict() {
+        let mapping = load(
+            "toml",
+            "[[save_path]]\nold = \"/data\"\nnew = \"/a\"\nmatch = \"prefix\"\n\n\
+             [[save_path]]\nold = \"/data/tv\"\nnew = \"/b\"\nmatch = \"prefix\"\n\n\
+             [[save_path]]\nold = \"/data/movies\"\nnew = \"/c\"\nmatch = \"prefix\"\n",
+            MappingMode::FirstMatch,
+        )
+        .unwrap();
+        let torrent = test_util::loaded_storage()
+            .torrent(SEEDING_ID)
+            .unwrap()
+            .unwrap();
+
+        let matches = matching_rules(&mapping.save_paths, |rule| rule.matches(&torrent)).unwrap();
+        assert_eq!(matches, [1, 2]);
+    }
+
+    #[test]
+    fn first_match_applies_one_rule() {
+        let rules = "[[save_path]]\nold = \"/data/\"\nnew = \"/mnt/\"\nmatch = \"prefix\"\n\n\
+                     [[save_path]]\nold = \"/mnt/tv\"\nnew = \"/pool/tv\"\nmatch = \"prefix\"\n";
+
+        assert_eq!(apply(rules, MappingMode::FirstMatch), "/mnt/tv");
+    }
+
+    #[test]
+    fn ordered_applies_every_rule_to_the_result() {
+        let rules = "[[save_path]]\nold = \"/data/\"\nnew = \"/mnt/\"\nmatch = \"prefix\"\n\n\
+                     [[save_path]]\nold = \"/mnt/tv\"\nnew = \"/pool/tv\"\nmatch = \"prefix\"\n";
+
+        assert_eq!(apply(rules, MappingMode::Ordered), "/pool/tv");
+    }
+
+    #[test]
+    fn mapping_mode_is_parsed() {
+        assert_eq!("ordered".parse::<MappingMode>(), Ok(MappingMode::Ordered));
+        assert_eq!(
+            "last-match".parse::<MappingMode>(),
+            Err(String::from(
+                "unknown mapping mode 'last-match', expected first-match or ordered"
+            ))
+        );
+    }
+}
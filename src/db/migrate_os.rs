@@ -0,0 +1,601 @@
+//! Tools for moving torrents between Windows and Unix-like systems
+
+use crate::common::database::DatabaseRow;
+use crate::common::fastresume::Fastresume;
+use crate::config::Config;
+use crate::db;
+use crate::db::save_path::{self, PathMatch, PathScope, SavePath};
+use crate::storage::Storage;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Operating system the torrents are migrated to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetOs {
+    /// Linux, macOS and other systems with `/` separators
+    Unix,
+    /// Windows, with `\` separators and drive letters
+    Windows,
+}
+
+impl FromStr for TargetOs {
+    type Err = String;
+
+    fn from_str(os: &str) -> Result<TargetOs, String> {
+        match os {
+            "unix" => Ok(TargetOs::Unix),
+            "windows" => Ok(TargetOs::Windows),
+            _ => Err(format!(
+                "unknown target OS '{os}', expected unix or windows"
+            )),
+        }
+    }
+}
+
+/// Direction of a WSL or Docker path translation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Translation {
+    /// From the paths inside WSL or a container to the paths on the host
+    ToHost,
+    /// From the paths on the host to the paths inside WSL or a container
+    ToGuest,
+}
+
+impl FromStr for Translation {
+    type Err = String;
+
+    fn from_str(translation: &str) -> Result<Translation, String> {
+        match translation {
+            "to-host" => Ok(Translation::ToHost),
+            "to-guest" => Ok(Translation::ToGuest),
+            _ => Err(format!(
+                "unknown translation '{translation}', expected to-host or to-guest"
+            )),
+        }
+    }
+}
+
+/// Cross-OS migration information
+///
+/// Every drive or share rule is a prefix replacement, see `PathMatch::Prefix`. Paths from Windows
+/// are matched case-insensitively, since Windows does not tell `D:\Media` and `d:\media` apart.
+#[derive(Debug)]
+pub struct OsMigration {
+    /// Operating system the torrents are migrated to
+    pub target: TargetOs,
+    /// Drive and share rules, in the order they are tried
+    pub rules: Vec<SavePath>,
+}
+
+impl OsMigration {
+    /// Builds the migration information from `FROM=TO` drive and share rules
+    ///
+    /// Fails if there are no rules or a rule has no `=`.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let migration = OsMigration::new(
+    ///     TargetOs::Unix,
+    ///     &[String::from("D:\\=/mnt/d/"), String::from("\\\\nas\\media=/mnt/nas/")],
+    /// )?;
+    /// ```
+    pub fn new(target: TargetOs, drive_maps: &[String]) -> Result<OsMigration, String> {
+        if drive_maps.is_empty() {
+            return Err(String::from(
+                "--migrate-to requires at least one --drive-map",
+            ));
+        }
+
+        let mut rules = Vec::new();
+        for drive_map in drive_maps {
+            let Some((old, new)) = drive_map.split_once('=') else {
+                return Err(format!(
+                    "invalid --drive-map '{drive_map}', expected FROM=TO"
+                ));
+            };
+            rules.push((old.to_string(), new.to_string()));
+        }
+
+        // Only Windows paths are migrated to Unix
+        OsMigration::from_rules(target, &rules, target == TargetOs::Unix)
+    }
+
+    /// Builds the migration information from pairs of existing and new path prefixes
+    ///
+    /// `ignore_case` should be set if the existing paths are Windows paths.
+    pub fn from_rules(
+        target: TargetOs,
+        rules: &[(String, String)],
+        ignore_case: bool,
+    ) -> Result<OsMigration, String> {
+        let separator = match target {
+            TargetOs::Unix => "/",
+            TargetOs::Windows => "\\",
+        };
+        let rules = rules
+            .iter()
+            .map(|(old, new)| {
+                SavePath::new(
+                    old,
+                    new,
+                    separator,
+                    PathMatch::Prefix,
+                    ignore_case,
+                    PathScope::Both,
+                )
+            })
+            .collect::<Result<Vec<SavePath>, String>>()?;
+
+        Ok(OsMigration { target, rules })
+    }
+
+    /// Builds the translation between Windows drives and their WSL mounts, e.g. `C:\` and
+    /// `/mnt/c/`
+    ///
+    /// The host is Windows and the guest is WSL.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let migration = OsMigration::wsl(Translation::ToGuest)?;
+    /// ```
+    pub fn wsl(translation: Translation) -> Result<OsMigration, String> {
+        let drives = ('a'..='z').map(|drive| {
+            (
+                format!("{}:\\", drive.to_ascii_uppercase()),
+                format!("/mnt/{drive}/"),
+            )
+        });
+        match translation {
+            Translation::ToHost => OsMigration::from_rules(
+                TargetOs::Windows,
+                &drives
+                    .map(|(host, guest)| (guest, host))
+                    .collect::<Vec<_>>(),
+                false,
+            ),
+            Translation::ToGuest => {
+                OsMigration::from_rules(TargetOs::Unix, &drives.collect::<Vec<_>>(), true)
+            }
+        }
+    }
+
+    /// Builds the translation between host directories and the container directories they are
+    /// mounted at, read from a compose-style volume list
+    ///
+    /// Each entry is a `host:container` or `host:container:mode` volume, one per line, as found
+    /// under `volumes:` in a `docker-compose.yml`. Relative host directories are relative to the
+    /// directory of the list. Named volumes are skipped, since they do not live in a host
+    /// directory of their own. The longest directories are tried first, so nested volumes work.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let migration = OsMigration::docker(Path::new("volumes.yml"), Translation::ToHost)?;
+    /// ```
+    pub fn docker(volumes_file: &Path, translation: Translation) -> Result<OsMigration, String> {
+        let contents = fs::read_to_string(volumes_file)
+            .map_err(|err| format!("could not read {:?}: {err}", volumes_file.display()))?;
+        let base_dir = volumes_file.parent().unwrap_or(Path::new(""));
+
+        let mut volumes = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let entry = line.trim().trim_start_matches('-').trim();
+            let entry = entry.trim_matches(|c| c == '"' || c == '\'');
+            if entry.is_empty() || entry.starts_with('#') || entry.ends_with(':') {
+                continue;
+            }
+
+            // A Windows host directory starts with a drive letter, whose colon is no separator
+            let drive_length = match entry.as_bytes() {
+                [drive, b':', ..] if drive.is_ascii_alphabetic() => 2,
+                _ => 0,
+            };
+            let mut parts = entry[drive_length..].split(':');
+            let (Some(host), Some(container)) = (parts.next(), parts.next()) else {
+                return Err(format!(
+                    "invalid volume '{entry}' on line {} of {:?}, expected host:container",
+                    index + 1,
+                    volumes_file.display()
+                ));
+            };
+            let host = format!("{}{host}", &entry[..drive_length]);
+
+            let host = match host.chars().next() {
+                Some('.') => {
+                    let joined = base_dir.join(&host);
+                    fs::canonicalize(&joined)
+                        .unwrap_or(joined)
+                        .to_string_lossy()
+                        .into_owned()
+                }
+                Some('~') => {
+                    return Err(format!(
+                        "unsupported volume '{entry}' on line {}, use an absolute host directory",
+                        index + 1
+                    ))
+                }
+                Some('/' | '\\') => host,
+                _ if drive_length > 0 => host,
+                // A named volume
+                _ => continue,
+            };
+            volumes.push((host, container.to_string()));
+        }
+        if volumes.is_empty() {
+            return Err(format!(
+                "{:?} contains no host:container volumes",
+                volumes_file.display()
+            ));
+        }
+
+        let windows_host = volumes
+            .iter()
+            .any(|(host, _)| save_path::is_absolute(host) && !host.starts_with('/'));
+        match translation {
+            Translation::ToHost => {
+                let mut rules: Vec<(String, String)> = volumes
+                    .into_iter()
+                    .map(|(host, container)| (container, host))
+                    .collect();
+                rules.sort_by_key(|(container, _)| std::cmp::Reverse(container.len()));
+                let target = match windows_host {
+                    true => TargetOs::Windows,
+                    false => TargetOs::Unix,
+                };
+                OsMigration::from_rules(target, &rules, false)
+            }
+            Translation::ToGuest => {
+                volumes.sort_by_key(|(host, _)| std::cmp::Reverse(host.len()));
+                OsMigration::from_rules(TargetOs::Unix, &volumes, windows_host)
+            }
+        }
+    }
+
+    /// Converts a Unix-style path, such as `target_save_path`, with the first matching rule
+    ///
+    /// Returns `None` if no rule covers the path.
+    pub fn convert_unix(&self, path: &str) -> Option<String> {
+        let path = self.rules.iter().find_map(|rule| rule.replace_unix(path))?;
+        Some(self.drive_root(path, "/"))
+    }
+
+    /// Converts an OS-specific path, such as the libtorrent `save_path`, with the first matching
+    /// rule
+    ///
+    /// Returns `None` if no rule covers the path.
+    pub fn convert_os(&self, path: &str) -> Option<String> {
+        let path = self.rules.iter().find_map(|rule| rule.replace_os(path))?;
+        let separator = match self.target {
+            TargetOs::Unix => "/",
+            TargetOs::Windows => "\\",
+        };
+        Some(self.drive_root(path, separator))
+    }
+
+    /// Keeps a bare drive such as `D:` pointing at the root of the drive, rather than the current
+    /// directory on it
+    fn drive_root(&self, path: String, separator: &str) -> String {
+        let is_drive = path.len() == 2 && path.ends_with(':');
+        match self.target == TargetOs::Windows && is_drive {
+            true => path + separator,
+            false => path,
+        }
+    }
+
+    /// Converts all path-bearing fields of a torrent
+    ///
+    /// Renamed files in `mapped_files` that are relative to the save path only get their
+    /// separators converted. Returns the fields and paths that no rule covers, which are left as
+    /// they are.
+    pub fn rewrite(&self, torrent: &mut DatabaseRow) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?;
+        let mut uncovered = Vec::new();
+
+        // qB stores the "target_save_path" and "download_path" with Unix-style separators, even
+        // on Windows. These fields will be absent if the torrent is in AutoTMM mode
+        for (name, path) in [
+            ("target_save_path", &mut torrent.target_save_path),
+            ("download_path", &mut torrent.download_path),
+        ] {
+            let Some(old_path) = path.as_ref() else {
+                continue;
+            };
+            match self.convert_unix(old_path) {
+                Some(new_path) => *path = Some(new_path),
+                None => uncovered.push(format!("{name} {old_path:?}")),
+            }
+        }
+
+        let resume_data_save_path = String::from_utf8(resume_data.save_path)?;
+        resume_data.save_path = match self.convert_os(&resume_data_save_path) {
+            Some(new_path) => new_path.into(),
+            None => {
+                uncovered.push(format!("save_path {resume_data_save_path:?}"));
+                resume_data_save_path.into()
+            }
+        };
+
+        if let Some(mapped_files) = resume_data.mapped_files.as_mut() {
+            for (index, path) in mapped_files.iter_mut().enumerate() {
+                if save_path::is_absolute(path) {
+                    match self.convert_os(path) {
+                        Some(new_path) => *path = new_path,
+                        None => uncovered.push(format!("mapped_files {index} {path:?}")),
+                    }
+                } else {
+                    *path = match self.target {
+                        TargetOs::Unix => path.replace('\\', "/"),
+                        TargetOs::Windows => path.replace('/', "\\"),
+                    };
+                }
+            }
+        }
+
+        torrent.libtorrent_resume_data = serde_bencode::to_bytes(&resume_data)?;
+
+        Ok(uncovered)
+    }
+}
+
+/// Migrates torrents between Windows and Unix-like systems in a single pass
+///
+/// Converts every location where qB stores a path: `target_save_path`, `download_path`, and the
+/// `save_path` and `mapped_files` in `libtorrent_resume_data`. For a BT_backup directory these are
+/// `qBt-savePath`, `qBt-downloadPath`, `save_path` and `mapped_files` in each .fastresume file.
+/// Each path is converted with the first drive or share rule that covers it, so more specific
+/// rules should come first. Paths that no rule covers are left unchanged and reported.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::migrate_os::{migrate_os, OsMigration, TargetOs};
+/// let migration = OsMigration::new(TargetOs::Unix, &[String::from("D:\\=/mnt/d/")])?;
+/// migrate_os(&mut storage, &migration, &config)?;
+/// ```
+///
+/// ## Verbose output
+/// If verbose output is enabled it will output the torrent hash for each torrent updated.
+pub fn migrate_os(
+    storage: &mut dyn Storage,
+    migration: &OsMigration,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let target = match migration.target {
+        TargetOs::Unix => "Unix",
+        TargetOs::Windows => "Windows",
+    };
+    println!(
+        "OS migration: migrating to {target} with {} path rules",
+        migration.rules.len()
+    );
+
+    let mut num_uncovered = 0;
+    let mut num_torrents_updated = 0;
+    for mut torrent in config.filter.select(storage.torrents()?)? {
+        let original = torrent.clone();
+        let uncovered = migration.rewrite(&mut torrent)?;
+        for path in &uncovered {
+            eprintln!(
+                "OS migration: no rule covers the {path} of {}",
+                torrent.torrent_id
+            );
+        }
+        num_uncovered += uncovered.len();
+
+        let changed = torrent.target_save_path != original.target_save_path
+            || torrent.download_path != original.download_path
+            || torrent.libtorrent_resume_data != original.libtorrent_resume_data;
+        if !changed {
+            continue;
+        }
+
+        storage.update(&torrent)?;
+
+        if config.verbose {
+            println!("OS migration: updated paths of {}", torrent.torrent_id);
+        }
+
+        num_torrents_updated += 1;
+    }
+
+    match num_uncovered {
+        0 => println!("OS migration: every path was covered by a rule"),
+        1 => println!("OS migration: 1 path was not covered by any rule, see the warning above"),
+        _ => println!(
+            "OS migration: {num_uncovered} paths were not covered by any rule, see the warnings \
+             above"
+        ),
+    }
+
+    let verb = db::verb(config, num_torrents_updated);
+    match num_torrents_updated {
+        0 => println!("OS migration: no torrents {verb} updated"),
+        1 => println!("OS migration: 1 torrent {verb} updated"),
+        _ => println!("OS migration: {num_torrents_updated} torrents {verb} updated"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, SEEDING, SEEDING_ID};
+
+    fn drive_maps(maps: &[&str]) -> Vec<String> {
+        maps.iter().map(|map| map.to_string()).collect()
+    }
+
+    #[test]
+    fn windows_to_unix() {
+        let migration = OsMigration::new(
+            TargetOs::Unix,
+            &drive_maps(&[
+                "D:\\Media\\=/srv/media/",
+                "D:\\=/mnt/d/",
+                "\\\\nas\\tv=/mnt/nas/tv",
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            migration.convert_os("D:\\Media\\TV").as_deref(),
+            Some("/srv/media/TV")
+        );
+        assert_eq!(
+            migration.convert_os("d:\\Downloads\\Sub").as_deref(),
+            Some("/mnt/d/Downloads/Sub")
+        );
+        assert_eq!(
+            migration.convert_unix("D:/Downloads").as_deref(),
+            Some("/mnt/d/Downloads")
+        );
+        assert_eq!(
+            migration.convert_unix("//nas/tv/Show").as_deref(),
+            Some("/mnt/nas/tv/Show")
+        );
+        assert_eq!(migration.convert_os("E:\\Downloads"), None);
+    }
+
+    #[test]
+    fn unix_to_windows() {
+        let migration = OsMigration::new(
+            TargetOs::Windows,
+            &drive_maps(&["/mnt/d=D:", "/home=C:\\Users"]),
+        )
+        .unwrap();
+
+        assert_eq!(migration.convert_os("/mnt/d").as_deref(), Some("D:\\"));
+        assert_eq!(
+            migration.convert_os("/mnt/d/tv/Show").as_deref(),
+            Some("D:\\tv\\Show")
+        );
+        assert_eq!(
+            migration.convert_unix("/home/me").as_deref(),
+            Some("C:/Users/me")
+        );
+        // Unix paths are case-sensitive
+        assert_eq!(migration.convert_os("/MNT/d/tv"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_drive_maps() {
+        assert!(OsMigration::new(TargetOs::Unix, &[]).is_err());
+        assert!(OsMigration::new(TargetOs::Unix, &drive_maps(&["D:\\"])).is_err());
+    }
+
+    #[test]
+    fn rewrite_converts_every_path() {
+        let torrent = test_util::torrent(SEEDING_ID, SEEDING, None);
+
+        let migration =
+            OsMigration::new(TargetOs::Windows, &drive_maps(&["/data=D:\\Data"])).unwrap();
+        let mut migrated = torrent.clone();
+        assert!(migration.rewrite(&mut migrated).unwrap().is_empty());
+        let resume_data: Fastresume =
+            serde_bencode::from_bytes(&migrated.libtorrent_resume_data).unwrap();
+        assert_eq!(migrated.target_save_path.as_deref(), Some("D:/Data/tv"));
+        assert_eq!(resume_data.save_path, b"D:\\Data\\tv");
+        assert_eq!(
+            resume_data.mapped_files.unwrap(),
+            ["", "Subs\\Show.S01E01.en.srt"]
+        );
+
+        let migration =
+            OsMigration::new(TargetOs::Windows, &drive_maps(&["/other=D:\\Other"])).unwrap();
+        let mut migrated = torrent.clone();
+        assert_eq!(
+            migration.rewrite(&mut migrated).unwrap(),
+            ["target_save_path \"/data/tv\"", "save_path \"/data/tv\""]
+        );
+        assert_eq!(migrated.target_save_path, torrent.target_save_path);
+    }
+
+    #[test]
+    fn wsl_translation() {
+        let to_guest = OsMigration::wsl(Translation::ToGuest).unwrap();
+        assert_eq!(
+            to_guest.convert_os("C:\\Users\\me").as_deref(),
+            Some("/mnt/c/Users/me")
+        );
+        assert_eq!(
+            to_guest.convert_unix("d:/Media").as_deref(),
+            Some("/mnt/d/Media")
+        );
+
+        let to_host = OsMigration::wsl(Translation::ToHost).unwrap();
+        assert_eq!(
+            to_host.convert_os("/mnt/d/Media/tv").as_deref(),
+            Some("D:\\Media\\tv")
+        );
+        assert_eq!(to_host.convert_os("/home/me"), None);
+    }
+
+    #[test]
+    fn docker_volume_translation() {
+        let dir = tempfile::tempdir().unwrap();
+        let volumes_file = dir.path().join("volumes.yml");
+        fs::write(
+            &volumes_file,
+            "volumes:\n  - /srv/media:/data\n  - \"/srv/media/tv:/data/tv:ro\"\n  \
+             - config:/config\n  # - /unused:/unused\n",
+        )
+        .unwrap();
+
+        let to_host = OsMigration::docker(&volumes_file, Translation::ToHost).unwrap();
+        assert_eq!(to_host.target, TargetOs::Unix);
+        // Nested volumes are tried first, and named volumes are skipped
+        assert_eq!(to_host.rules.len(), 2);
+        assert_eq!(
+            to_host.convert_os("/data/tv/Show").as_deref(),
+            Some("/srv/media/tv/Show")
+        );
+        assert_eq!(
+            to_host.convert_unix("/data/movies").as_deref(),
+            Some("/srv/media/movies")
+        );
+        assert_eq!(to_host.convert_os("/config/qBittorrent"), None);
+
+        let to_guest = OsMigration::docker(&volumes_file, Translation::ToGuest).unwrap();
+        assert_eq!(
+            to_guest.convert_os("/srv/media/tv").as_deref(),
+            Some("/data/tv")
+        );
+    }
+
+    #[test]
+    fn docker_windows_host_and_invalid_volumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let volumes_file = dir.path().join("volumes.yml");
+
+        fs::write(&volumes_file, "- D:\\Media:/data\n").unwrap();
+        let to_host = OsMigration::docker(&volumes_file, Translation::ToHost).unwrap();
+        assert_eq!(to_host.target, TargetOs::Windows);
+        assert_eq!(
+            to_host.convert_os("/data/tv").as_deref(),
+            Some("D:\\Media\\tv")
+        );
+
+        fs::write(&volumes_file, "- ~/media:/data\n").unwrap();
+        assert!(OsMigration::docker(&volumes_file, Translation::ToHost).is_err());
+
+        fs::write(&volumes_file, "- config:/config\n").unwrap();
+        assert!(OsMigration::docker(&volumes_file, Translation::ToHost).is_err());
+    }
+
+    #[test]
+    fn docker_relative_host_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("downloads")).unwrap();
+        let volumes_file = dir.path().join("volumes.yml");
+        fs::write(&volumes_file, "- ./downloads:/downloads\n").unwrap();
+
+        let to_host = OsMigration::docker(&volumes_file, Translation::ToHost).unwrap();
+        let host = fs::canonicalize(dir.path().join("downloads")).unwrap();
+        assert_eq!(
+            to_host.convert_os("/downloads/tv"),
+            Some(format!("{}/tv", host.display()))
+        );
+    }
+}
@@ -19,21 +19,39 @@
 //! });
 
 use crate::config::Config;
-use rusqlite::{Connection, OpenFlags, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusqlite::{Connection, DatabaseName, OpenFlags, Result};
 use std::error::Error;
-use std::fs;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
 
+pub mod backups;
 pub mod dump_db;
-pub mod query;
+pub mod instance;
+pub mod list;
+pub mod load_db;
+pub mod mapping;
+pub mod migrate_os;
+pub mod normalize;
+pub mod rename_files;
 pub mod save_path;
+pub mod schema;
 pub mod tracker_url;
+pub mod undo;
+pub mod verify;
 
 /// qB torrents.db struct
 pub struct DB {}
 impl DB {
     /// Creates a timestamped backup of the torrents.db file before modification
     ///
+    /// The backup is made with SQLite's online backup API rather than a file copy. It includes
+    /// changes that are still in the `-wal` file and is a consistent snapshot even if another
+    /// process writes to the database at the same time. The copy is switched to a rollback journal,
+    /// so it is a single self-contained file, and checked with `PRAGMA integrity_check`.
+    ///
     /// ## Examples
     /// ```rs
     /// DB::backup(&config).unwrap_or_else(|err| {
@@ -47,6 +65,10 @@ impl DB {
     /// qbfrt --disable-backup
     /// ```
     ///
+    /// ## Configuration
+    /// - The backup is saved to config.backup_directory, otherwise it defaults to the qB directory.
+    /// - The backup is compressed with gzip if config.compress_backup is set.
+    ///
     /// ## Verbose output
     /// If verbose output is enabled with `--verbose` or `-v` it will then output the path to the backup.
     /// In the case where backup is disabled it will output that instead.
@@ -54,10 +76,19 @@ impl DB {
         if !config.disable_backup {
             println!("Creating database backup...");
 
+            let backup_dir = config.db_backup_directory();
+            fs::create_dir_all(&backup_dir)?;
+
             let datetime = chrono::offset::Local::now().format("%Y%m%d%H%M%S");
-            let backup_file =
-                Path::new(&config.qb_directory).join(format!("torrents.db-{datetime}.bak"));
-            fs::copy(&config.db_file, &backup_file)?;
+            let mut backup_file = backup_dir.join(format!("torrents.db-{datetime}.bak"));
+
+            let db = DB::connect_read_only(config)?;
+            db.backup(DatabaseName::Main, &backup_file, None)?;
+            DB::check_backup(&backup_file)?;
+
+            if config.compress_backup {
+                backup_file = DB::compress(&backup_file)?;
+            }
 
             if config.verbose {
                 println!("Backup saved to: {:?}", backup_file.display());
@@ -69,6 +100,34 @@ impl DB {
         Ok(())
     }
 
+    /// Makes a backup self-contained and checks that it is not corrupt
+    fn check_backup(backup_file: &Path) -> Result<(), Box<dyn Error>> {
+        let backup = Connection::open(backup_file)?;
+        backup.pragma_update(None, "journal_mode", "DELETE")?;
+
+        // integrity_check returns a single "ok" row, or one row per problem found
+        let result: String = backup.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        if result != "ok" {
+            return Err(format!("backup failed the integrity check: {result}").into());
+        }
+
+        Ok(())
+    }
+
+    /// Compresses a backup with gzip, replacing the uncompressed file
+    fn compress(backup_file: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        let mut file_name = backup_file.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".gz");
+        let compressed_file = backup_file.with_file_name(file_name);
+
+        let mut encoder = GzEncoder::new(File::create(&compressed_file)?, Compression::default());
+        io::copy(&mut File::open(backup_file)?, &mut encoder)?;
+        encoder.finish()?;
+        fs::remove_file(backup_file)?;
+
+        Ok(compressed_file)
+    }
+
     /// Opens the torrents.db SQLite database and returns the connection
     ///
     /// ## Example
@@ -88,4 +147,132 @@ impl DB {
                 | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )?)
     }
+    /// Opens the torrents.db SQLite database read-only and returns the connection
+    ///
+    /// Unlike `DB::connect` this does not output anything, so it can be used by read-only
+    /// operations whose output may be piped to other programs.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let conn = DB::connect_read_only(&config).unwrap_or_else(|err| {
+    ///     eprintln!("Could not connect to database: {err}");
+    ///     process::exit(1);
+    /// });
+    /// ```
+    pub fn connect_read_only(config: &Config) -> Result<Connection, Box<dyn Error>> {
+        Ok(Connection::open_with_flags(
+            &config.db_file,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?)
+    }
+}
+
+/// Returns the verb for a summary line about a number of items, e.g. "were" in "2 torrents were
+/// updated"
+///
+/// In a dry run nothing is written, so the verb is "would be" for any number of items.
+///
+/// ## Example
+/// ```rs
+/// let verb = db::verb(&config, num_torrents_updated);
+/// println!("Save path: {num_torrents_updated} torrents {verb} updated");
+/// ```
+pub fn verb(config: &Config, count: usize) -> &'static str {
+    match (config.dry_run, count) {
+        (true, _) => "would be",
+        (false, 1) => "was",
+        (false, _) => "were",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use flate2::read::GzDecoder;
+
+    fn count_torrents(db_file: &Path) -> i64 {
+        let db = Connection::open(db_file).unwrap();
+        db.query_row("SELECT COUNT(*) FROM torrents", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn backup_files(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect()
+    }
+
+    #[test]
+    fn backup_is_a_self_contained_copy() {
+        let dir = test_util::qb_dir();
+        let backup_dir = dir.path().join("backups");
+        let config = test_util::qb_config(&dir, &["--backup-dir", backup_dir.to_str().unwrap()]);
+
+        DB::backup(&config).unwrap();
+
+        let backups = backup_files(&backup_dir);
+        assert_eq!(backups.len(), 1);
+        let file_name = backups[0].file_name().unwrap().to_str().unwrap();
+        assert!(file_name.starts_with("torrents.db-") && file_name.ends_with(".bak"));
+        assert_eq!(count_torrents(&backups[0]), count_torrents(&config.db_file));
+
+        let backup = Connection::open(&backups[0]).unwrap();
+        let journal_mode: String = backup
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "delete");
+    }
+
+    #[test]
+    fn compressed_backup_replaces_the_copy() {
+        let dir = test_util::qb_dir();
+        let backup_dir = dir.path().join("backups");
+        let config = test_util::qb_config(
+            &dir,
+            &[
+                "--backup-dir",
+                backup_dir.to_str().unwrap(),
+                "--compress-backup",
+            ],
+        );
+
+        DB::backup(&config).unwrap();
+
+        let backups = backup_files(&backup_dir);
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].extension().unwrap(), "gz");
+
+        let decompressed = dir.path().join("decompressed.db");
+        let mut decoder = GzDecoder::new(File::open(&backups[0]).unwrap());
+        io::copy(&mut decoder, &mut File::create(&decompressed).unwrap()).unwrap();
+        assert_eq!(
+            count_torrents(&decompressed),
+            count_torrents(&config.db_file)
+        );
+    }
+
+    #[test]
+    fn disabled_backup_writes_nothing() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &["--disable-backup"]);
+
+        DB::backup(&config).unwrap();
+
+        assert_eq!(backup_files(dir.path()), [config.db_file]);
+    }
+
+    #[test]
+    fn verb_follows_count_and_dry_run() {
+        let config = Config::from_args(&[]).unwrap();
+        assert_eq!(verb(&config, 0), "were");
+        assert_eq!(verb(&config, 1), "was");
+        assert_eq!(verb(&config, 2), "were");
+
+        let config = Config::from_args(&["--dry-run", "--old-path", "/a", "--new-path", "/b"]);
+        assert_eq!(verb(&config.unwrap(), 1), "would be");
+    }
 }
@@ -0,0 +1,437 @@
+//! Tools for making torrent paths portable between macOS, Linux and Windows
+
+use crate::common::database::DatabaseRow;
+use crate::common::fastresume::Fastresume;
+use crate::config::Config;
+use crate::db::{self, save_path};
+use crate::storage::Storage;
+use std::error::Error;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters Windows does not allow in file and directory names
+const RESERVED_CHARACTERS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows does not allow as file or directory names, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Unicode normalization form for paths
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnicodeForm {
+    /// Composed characters, as used by Linux and Windows, e.g. `é` as a single code point
+    Nfc,
+    /// Decomposed characters, as stored by macOS, e.g. `é` as `e` and a combining accent
+    Nfd,
+}
+
+impl FromStr for UnicodeForm {
+    type Err = String;
+
+    fn from_str(form: &str) -> Result<UnicodeForm, String> {
+        match form {
+            "nfc" => Ok(UnicodeForm::Nfc),
+            "nfd" => Ok(UnicodeForm::Nfd),
+            _ => Err(format!(
+                "unknown normalization form '{form}', expected nfc or nfd"
+            )),
+        }
+    }
+}
+
+/// What to do with names Windows does not allow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowsNames {
+    /// Only report them
+    Check,
+    /// Replace the reserved characters with `_` and append `_` to reserved names
+    Sanitize,
+}
+
+impl FromStr for WindowsNames {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<WindowsNames, String> {
+        match mode {
+            "check" => Ok(WindowsNames::Check),
+            "sanitize" => Ok(WindowsNames::Sanitize),
+            _ => Err(format!(
+                "unknown Windows names mode '{mode}', expected check or sanitize"
+            )),
+        }
+    }
+}
+
+/// Path normalization information
+#[derive(Debug)]
+pub struct PathNormalization {
+    /// Unicode normalization form to convert paths to
+    pub form: Option<UnicodeForm>,
+    /// What to do with names Windows does not allow
+    pub windows_names: Option<WindowsNames>,
+}
+
+/// A path of a torrent changed by `PathNormalization::rewrite`
+#[derive(Debug)]
+pub struct PathChange {
+    /// Field the path is stored in, e.g. `save_path` or `mapped_files 2`
+    pub field: String,
+    /// Path before the change
+    pub old_path: String,
+    /// Path after the change
+    pub new_path: String,
+}
+
+/// A name Windows does not allow, found by `PathNormalization::rewrite`
+#[derive(Debug)]
+pub struct PathProblem {
+    /// Field the path is stored in, e.g. `save_path` or `mapped_files 2`
+    pub field: String,
+    /// Path containing the name
+    pub path: String,
+    /// What is wrong with the name
+    pub problem: String,
+}
+
+impl PathNormalization {
+    /// Returns true if paths are changed, not only checked for names Windows does not allow
+    pub fn modifies(&self) -> bool {
+        self.form.is_some() || self.windows_names == Some(WindowsNames::Sanitize)
+    }
+
+    /// Normalizes a single path
+    ///
+    /// Returns the normalized path and the names Windows does not allow. Those are sanitized if
+    /// `WindowsNames::Sanitize` is set, and reported either way.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let normalization = PathNormalization {
+    ///     form: Some(UnicodeForm::Nfc),
+    ///     windows_names: Some(WindowsNames::Sanitize),
+    /// };
+    /// let (path, problems) = normalization.normalize("D:\\Music\\Live: Paris?");
+    /// ```
+    pub fn normalize(&self, path: &str) -> (String, Vec<String>) {
+        let path = match self.form {
+            Some(UnicodeForm::Nfc) => path.nfc().collect(),
+            Some(UnicodeForm::Nfd) => path.nfd().collect(),
+            None => path.to_string(),
+        };
+
+        match self.windows_names {
+            Some(windows_names) => check_windows_names(&path, windows_names),
+            None => (path, vec![]),
+        }
+    }
+
+    /// Normalizes all path-bearing fields of a torrent
+    ///
+    /// These are `target_save_path`, `download_path`, and the `save_path` and `mapped_files` in
+    /// `libtorrent_resume_data`. Returns the changed paths and the names Windows does not allow.
+    pub fn rewrite(
+        &self,
+        torrent: &mut DatabaseRow,
+    ) -> Result<(Vec<PathChange>, Vec<PathProblem>), Box<dyn Error>> {
+        let mut resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?;
+        let resume_data_save_path = String::from_utf8(resume_data.save_path)?;
+        let mut mapped_files = resume_data.mapped_files.take();
+
+        let mut paths: Vec<(String, &mut String)> = Vec::new();
+        let mut save_path = resume_data_save_path;
+        if let Some(path) = torrent.target_save_path.as_mut() {
+            paths.push((String::from("target_save_path"), path));
+        }
+        if let Some(path) = torrent.download_path.as_mut() {
+            paths.push((String::from("download_path"), path));
+        }
+        paths.push((String::from("save_path"), &mut save_path));
+        for (index, path) in mapped_files.iter_mut().flatten().enumerate() {
+            if !path.is_empty() {
+                paths.push((format!("mapped_files {index}"), path));
+            }
+        }
+
+        let mut changes = Vec::new();
+        let mut problems = Vec::new();
+        for (field, path) in paths {
+            let (new_path, path_problems) = self.normalize(path);
+            problems.extend(path_problems.into_iter().map(|problem| PathProblem {
+                field: field.clone(),
+                path: path.clone(),
+                problem,
+            }));
+            if new_path != *path {
+                changes.push(PathChange {
+                    field,
+                    old_path: std::mem::replace(path, new_path.clone()),
+                    new_path,
+                });
+            }
+        }
+
+        resume_data.save_path = save_path.into();
+        resume_data.mapped_files = mapped_files;
+        torrent.libtorrent_resume_data = serde_bencode::to_bytes(&resume_data)?;
+
+        Ok((changes, problems))
+    }
+}
+
+/// Checks every name in a path against the Windows naming rules, sanitizing them if requested
+///
+/// A drive such as `D:` at the start of the path is left alone. Returns the path and a description
+/// of each name Windows does not allow.
+fn check_windows_names(path: &str, windows_names: WindowsNames) -> (String, Vec<String>) {
+    let drive_length = match path.as_bytes() {
+        [drive, b':', ..] if drive.is_ascii_alphabetic() => 2,
+        _ => 0,
+    };
+    let (drive, rest) = path.split_at(drive_length);
+
+    let mut problems = Vec::new();
+    let mut names = Vec::new();
+    let mut separators = rest.chars().filter(|c| save_path::is_separator(*c));
+    for name in rest.split(save_path::is_separator) {
+        let mut new_name: String = name
+            .chars()
+            .map(|c| {
+                if RESERVED_CHARACTERS.contains(&c) || c.is_ascii_control() {
+                    problems.push(format!("{name:?} contains the reserved character {c:?}"));
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect();
+
+        // Windows drops trailing dots and spaces, so the name would not be found again
+        if name != "." && name != ".." && name.ends_with(['.', ' ']) {
+            problems.push(format!("{name:?} ends with a dot or space"));
+            let trimmed = new_name.trim_end_matches(['.', ' ']).len();
+            new_name.replace_range(trimmed.., &"_".repeat(new_name.len() - trimmed));
+        }
+
+        let stem = new_name.split('.').next().unwrap_or_default().trim_end();
+        if RESERVED_NAMES.contains(&stem.to_ascii_uppercase().as_str()) {
+            problems.push(format!("{name:?} is the reserved name {stem}"));
+            new_name.insert(stem.len(), '_');
+        }
+
+        names.push(new_name);
+        if let Some(separator) = separators.next() {
+            names.push(separator.to_string());
+        }
+    }
+
+    match windows_names {
+        WindowsNames::Check => (path.to_string(), problems),
+        WindowsNames::Sanitize => (format!("{drive}{}", names.concat()), problems),
+    }
+}
+
+/// Normalizes the Unicode form of torrent paths and finds the names Windows does not allow
+///
+/// Every change and every name Windows does not allow is reported. With `WindowsNames::Sanitize`
+/// those names are sanitized as well. Only the stored paths change, the files and directories
+/// on disk have to be renamed to match.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::normalize::{normalize_paths, PathNormalization, UnicodeForm};
+/// let normalization = PathNormalization {
+///     form: Some(UnicodeForm::Nfc),
+///     windows_names: None,
+/// };
+/// normalize_paths(&mut storage, &normalization, &config)?;
+/// ```
+///
+/// ## Verbose output
+/// If verbose output is enabled it will output the torrent hash for each torrent updated.
+pub fn normalize_paths(
+    storage: &mut dyn Storage,
+    normalization: &PathNormalization,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let mut steps = Vec::new();
+    match normalization.form {
+        Some(UnicodeForm::Nfc) => steps.push("converting paths to NFC"),
+        Some(UnicodeForm::Nfd) => steps.push("converting paths to NFD"),
+        None => {}
+    }
+    match normalization.windows_names {
+        Some(WindowsNames::Check) => steps.push("checking for names Windows does not allow"),
+        Some(WindowsNames::Sanitize) => steps.push("sanitizing names Windows does not allow"),
+        None => {}
+    }
+    println!("Normalize: {}", steps.join(", "));
+
+    let mut num_problems = 0;
+    let mut num_torrents_updated = 0;
+    for mut torrent in config.filter.select(storage.torrents()?)? {
+        let (changes, problems) = normalization.rewrite(&mut torrent)?;
+        for problem in &problems {
+            eprintln!(
+                "Normalize: warning, {} {:?} of {}: {}",
+                problem.field, problem.path, torrent.torrent_id, problem.problem
+            );
+        }
+        num_problems += problems.len();
+
+        if changes.is_empty() {
+            continue;
+        }
+
+        storage.update(&torrent)?;
+
+        for change in &changes {
+            println!(
+                "Normalize: {}: {} {:?} -> {:?}",
+                torrent.torrent_id, change.field, change.old_path, change.new_path
+            );
+        }
+        if config.verbose {
+            println!("Normalize: updated paths of {}", torrent.torrent_id);
+        }
+
+        num_torrents_updated += 1;
+    }
+
+    if normalization.windows_names.is_some() {
+        match num_problems {
+            0 => println!("Normalize: no names Windows does not allow were found"),
+            1 => println!("Normalize: 1 problem with a Windows name, see the warning above"),
+            _ => println!(
+                "Normalize: {num_problems} problems with Windows names, see the warnings above"
+            ),
+        }
+    }
+
+    let verb = db::verb(config, num_torrents_updated);
+    match num_torrents_updated {
+        0 => println!("Normalize: no torrents {verb} updated"),
+        1 => println!("Normalize: 1 torrent {verb} updated"),
+        _ => println!("Normalize: {num_torrents_updated} torrents {verb} updated"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sqlite::SqliteStorage;
+    use crate::test_util;
+
+    fn normalization(
+        form: Option<UnicodeForm>,
+        windows_names: Option<WindowsNames>,
+    ) -> PathNormalization {
+        PathNormalization {
+            form,
+            windows_names,
+        }
+    }
+
+    #[test]
+    fn converts_unicode_forms() {
+        let composed = "/music/Beyonc\u{e9}";
+        let decomposed = "/music/Beyonce\u{301}";
+
+        let nfc = normalization(Some(UnicodeForm::Nfc), None);
+        assert_eq!(nfc.normalize(decomposed), (composed.to_string(), vec![]));
+        let nfd = normalization(Some(UnicodeForm::Nfd), None);
+        assert_eq!(nfd.normalize(composed), (decomposed.to_string(), vec![]));
+    }
+
+    #[test]
+    fn sanitizes_reserved_characters() {
+        let sanitize = normalization(None, Some(WindowsNames::Sanitize));
+
+        let (path, problems) = sanitize.normalize("D:\\Music\\Live: Paris?\\a|b.flac");
+        assert_eq!(path, "D:\\Music\\Live_ Paris_\\a_b.flac");
+        assert_eq!(problems.len(), 3);
+    }
+
+    #[test]
+    fn sanitizes_reserved_names() {
+        let sanitize = normalization(None, Some(WindowsNames::Sanitize));
+
+        assert_eq!(sanitize.normalize("/data/CON").0, "/data/CON_");
+        assert_eq!(sanitize.normalize("/data/aux.txt").0, "/data/aux_.txt");
+        assert_eq!(
+            sanitize.normalize("/data/com1.tar.gz").0,
+            "/data/com1_.tar.gz"
+        );
+        assert_eq!(sanitize.normalize("/data/CONSOLE").0, "/data/CONSOLE");
+        assert_eq!(sanitize.normalize("/data/LPT10").0, "/data/LPT10");
+    }
+
+    #[test]
+    fn sanitizes_trailing_dots_and_spaces() {
+        let sanitize = normalization(None, Some(WindowsNames::Sanitize));
+
+        assert_eq!(
+            sanitize.normalize("/data/Album.../track ").0,
+            "/data/Album___/track_"
+        );
+        assert_eq!(sanitize.normalize("../data/./tv").0, "../data/./tv");
+    }
+
+    #[test]
+    fn check_reports_without_changing() {
+        let check = normalization(None, Some(WindowsNames::Check));
+
+        let (path, problems) = check.normalize("/data/NUL/a?b");
+        assert_eq!(path, "/data/NUL/a?b");
+        assert_eq!(problems.len(), 2);
+
+        let (path, problems) = check.normalize("C:\\Users\\me");
+        assert_eq!(path, "C:\\Users\\me");
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn windows_separator_alone_checks_nothing() {
+        let config = Config::from_args(&["--use-win-sep"]).unwrap();
+        assert!(config.normalization.is_none());
+        assert!(Config::from_args(&["--list", "--use-win-sep"]).is_ok());
+
+        let config =
+            Config::from_args(&["--old-path", "/a", "--new-path", "B:\\", "--use-win-sep"])
+                .unwrap();
+        let normalization = config.normalization.unwrap();
+        assert_eq!(normalization.windows_names, Some(WindowsNames::Check));
+    }
+
+    #[test]
+    fn only_changing_paths_modifies() {
+        assert!(!Config::from_args(&["--windows-names", "check"])
+            .unwrap()
+            .modifies());
+        assert!(Config::from_args(&["--windows-names", "sanitize"])
+            .unwrap()
+            .modifies());
+        assert!(Config::from_args(&["--normalize", "nfc"])
+            .unwrap()
+            .modifies());
+    }
+
+    #[test]
+    fn check_runs_against_read_only_storage() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &["--windows-names", "check"]);
+        let db = db::DB::connect_read_only(&config).unwrap();
+        let mut storage = SqliteStorage::new(db).unwrap();
+
+        normalize_paths(
+            &mut storage,
+            config.normalization.as_ref().unwrap(),
+            &config,
+        )
+        .unwrap();
+    }
+}
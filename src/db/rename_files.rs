@@ -0,0 +1,262 @@
+//! Tools for renaming torrent files by pattern
+
+use crate::common::database::DatabaseRow;
+use crate::common::fastresume::Fastresume;
+use crate::common::metadata::Metadata;
+use crate::common::pattern;
+use crate::config::Config;
+use crate::db;
+use crate::storage::Storage;
+use regex::Regex;
+use std::error::Error;
+
+/// A file renamed by `FileRename::rewrite`
+#[derive(Debug)]
+pub struct RenamedFile {
+    /// Path relative to the save path before the rename
+    pub old_path: String,
+    /// Path relative to the save path after the rename
+    pub new_path: String,
+}
+
+/// File rename information
+#[derive(Debug)]
+pub struct FileRename {
+    /// Existing file name or path fragment
+    pub old: String,
+    /// New file name or path fragment
+    pub new: String,
+    /// Separator to use in renamed file paths, should default to current OS style
+    pub separator: String,
+    /// Compiled existing file name if it is a regular expression
+    ///
+    /// The new file name may then refer to its capture groups as `$1` or `${name}`.
+    pub regex: Option<Regex>,
+}
+
+impl FileRename {
+    /// Builds the file rename information for an existing and a new file name
+    ///
+    /// Fails if `regex` is set and the existing file name is not a valid regular expression, see
+    /// `pattern::compile_regex`.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let file_rename = FileRename::new(r"\.mkv\.part$", ".mkv", "/", true)?;
+    /// ```
+    pub fn new(old: &str, new: &str, separator: &str, regex: bool) -> Result<FileRename, String> {
+        let regex = match regex {
+            true => Some(pattern::compile_regex(
+                old,
+                new,
+                false,
+                ("--old-file-name", "--new-file-name"),
+            )?),
+            false => None,
+        };
+
+        Ok(FileRename {
+            old: old.to_string(),
+            new: new.to_string(),
+            separator: separator.to_string(),
+            regex,
+        })
+    }
+
+    /// Renames the matching files of a torrent in its `mapped_files`
+    ///
+    /// Each file is matched by its current path relative to the save path, which is the renamed
+    /// path if it was renamed before and the path from the metadata otherwise. Returns the
+    /// renamed files, or `None` if no file matches. Torrents without metadata have no file list
+    /// and never match.
+    pub fn rewrite(
+        &self,
+        torrent: &mut DatabaseRow,
+    ) -> Result<Option<Vec<RenamedFile>>, Box<dyn Error>> {
+        let Some(metadata) = &torrent.metadata else {
+            return Ok(None);
+        };
+
+        let mut resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?;
+        let metadata = Metadata::from_bytes(metadata)?;
+        let files = metadata.files()?;
+
+        // qB records content layout changes as renamed files, but a "NoSubfolder" layout without
+        // any renamed files strips the root folder. Once a file is renamed, every file has to be
+        // mapped to keep that layout
+        let mapped_files = resume_data.mapped_files.take().unwrap_or_default();
+        let strip_root_folder = mapped_files.is_empty() && torrent.content_layout == "NoSubfolder";
+
+        let mut updated_files = Vec::with_capacity(files.len());
+        let mut renamed = Vec::new();
+        for (index, file) in files.iter().enumerate() {
+            let mapped = mapped_files.get(index).filter(|mapped| !mapped.is_empty());
+            let path = match mapped {
+                Some(mapped) => mapped.clone(),
+                None if strip_root_folder && file.path.len() > 1 => {
+                    file.path[1..].join(&self.separator)
+                }
+                None => file.path.join(&self.separator),
+            };
+
+            // Padding files are never written to disk, so there is nothing to rename
+            let new_path = match file.padding {
+                true => None,
+                false => self.replace(&path),
+            };
+            match new_path {
+                Some(new_path) => {
+                    updated_files.push(new_path.clone());
+                    renamed.push(RenamedFile {
+                        old_path: path,
+                        new_path,
+                    });
+                }
+                None if strip_root_folder && !file.padding => updated_files.push(path),
+                None => updated_files.push(mapped.cloned().unwrap_or_default()),
+            }
+        }
+        if renamed.is_empty() {
+            return Ok(None);
+        }
+
+        resume_data.mapped_files = Some(updated_files);
+        torrent.libtorrent_resume_data = serde_bencode::to_bytes(&resume_data)?;
+
+        Ok(Some(renamed))
+    }
+
+    /// Replaces the existing file name in a relative file path
+    ///
+    /// Returns `None` if the path does not match or the replacement leaves it unchanged.
+    fn replace(&self, path: &str) -> Option<String> {
+        let new_path = match &self.regex {
+            Some(regex) => regex.replace_all(path, &self.new).into_owned(),
+            None if self.old.is_empty() => return None,
+            None => path.replace(&self.old, &self.new),
+        };
+
+        Some(new_path).filter(|new_path| new_path != path && !new_path.is_empty())
+    }
+}
+
+/// Renames the files of torrents by pattern, by updating `mapped_files` in `libtorrent_resume_data`
+///
+/// Only the resume data is changed, the files themselves are not moved. qB looks for the renamed
+/// files at their new paths, so they need to be renamed on disk as well.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::rename_files::{rename_files, FileRename};
+/// let config = Config { verbose: true };
+/// let file_rename = FileRename::new(r"^(.*)\.mkv\.part$", "$1.mkv", "/", true)?;
+/// rename_files(&mut storage, &file_rename, &config)?;
+/// ```
+///
+/// ## Verbose output
+/// If verbose output is enabled it will output the torrent hash and the old and new path of each
+/// renamed file.
+pub fn rename_files(
+    storage: &mut dyn Storage,
+    file_rename: &FileRename,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    println!(
+        "File rename: replacing {} with {}",
+        file_rename.old, file_rename.new
+    );
+
+    let mut num_files_renamed = 0;
+    let mut num_torrents_updated = 0;
+    for mut torrent in config.filter.select(storage.torrents()?)? {
+        let Some(renamed) = file_rename.rewrite(&mut torrent)? else {
+            continue;
+        };
+
+        storage.update(&torrent)?;
+
+        if config.verbose {
+            println!("File rename: renamed files of {}", torrent.torrent_id);
+            for file in &renamed {
+                println!(
+                    "{}: {:?} -> {:?}",
+                    torrent.torrent_id, file.old_path, file.new_path
+                );
+            }
+        }
+
+        num_files_renamed += renamed.len();
+        num_torrents_updated += 1;
+    }
+
+    let files_verb = db::verb(config, num_files_renamed);
+    let torrents_verb = db::verb(config, num_torrents_updated);
+    match num_files_renamed {
+        0 => println!("File rename: no files {files_verb} renamed"),
+        1 => println!("File rename: 1 file {files_verb} renamed"),
+        _ => println!("File rename: {num_files_renamed} files {files_verb} renamed"),
+    }
+    match num_torrents_updated {
+        0 => println!("File rename: no torrents {torrents_verb} updated"),
+        1 => println!("File rename: 1 torrent {torrents_verb} updated"),
+        _ => println!("File rename: {num_torrents_updated} torrents {torrents_verb} updated"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, SEEDING, SEEDING_ID, TORRENT};
+
+    fn torrent(metadata: Option<&[u8]>) -> DatabaseRow {
+        test_util::torrent(SEEDING_ID, SEEDING, metadata)
+    }
+
+    fn mapped_files(torrent: &DatabaseRow) -> Vec<String> {
+        let resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data).unwrap();
+        resume_data.mapped_files.unwrap()
+    }
+
+    #[test]
+    fn renames_matching_files() {
+        let mut torrent = torrent(Some(TORRENT));
+        let file_rename = FileRename::new(r"^(.*)\.mkv$", "$1.mp4", "/", true).unwrap();
+
+        let renamed = file_rename.rewrite(&mut torrent).unwrap().unwrap();
+
+        assert_eq!(renamed.len(), 1);
+        assert_eq!(renamed[0].old_path, "Show.S01/Show.S01E01.mkv");
+        assert_eq!(renamed[0].new_path, "Show.S01/Show.S01E01.mp4");
+        assert_eq!(
+            mapped_files(&torrent),
+            ["Show.S01/Show.S01E01.mp4", "Subs/Show.S01E01.en.srt"]
+        );
+    }
+
+    #[test]
+    fn matches_renamed_paths() {
+        let mut torrent = torrent(Some(TORRENT));
+        let file_rename = FileRename::new(".en.srt", ".eng.srt", "/", false).unwrap();
+
+        let renamed = file_rename.rewrite(&mut torrent).unwrap().unwrap();
+
+        assert_eq!(renamed[0].old_path, "Subs/Show.S01E01.en.srt");
+        assert_eq!(mapped_files(&torrent), ["", "Subs/Show.S01E01.eng.srt"]);
+    }
+
+    #[test]
+    fn skips_torrents_without_matches_or_metadata() {
+        let file_rename = FileRename::new(".avi", ".mkv", "/", false).unwrap();
+        assert!(file_rename
+            .rewrite(&mut torrent(Some(TORRENT)))
+            .unwrap()
+            .is_none());
+
+        let file_rename = FileRename::new(".mkv", ".mp4", "/", false).unwrap();
+        assert!(file_rename.rewrite(&mut torrent(None)).unwrap().is_none());
+    }
+}
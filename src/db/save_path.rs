@@ -1,11 +1,108 @@
 //! Tools for modifying torrent save path
 
-use crate::common::database::PathData;
+use crate::common::database::DatabaseRow;
 use crate::common::fastresume::Fastresume;
+use crate::common::pattern;
 use crate::config::Config;
-use crate::db::query;
-use rusqlite::{named_params, Connection};
+use crate::db;
+use crate::storage::Storage;
+use regex::Regex;
 use std::error::Error;
+use std::str::FromStr;
+
+/// How the existing save path is matched
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathMatch {
+    /// Every occurrence of the existing save path string is replaced, even inside a directory name
+    Substring,
+    /// The existing save path is only replaced at the start of a path, and only if it ends at a
+    /// path separator, so `/torrents/movie` matches `/torrents/movie/a` but not `/torrents/movies`
+    Prefix,
+    /// The existing save path is replaced wherever it covers whole path segments
+    Segment,
+    /// The existing save path is a regular expression, and the new save path may refer to its
+    /// capture groups as `$1` or `${name}`
+    Regex,
+}
+
+impl FromStr for PathMatch {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<PathMatch, String> {
+        match mode {
+            "substring" => Ok(PathMatch::Substring),
+            "prefix" => Ok(PathMatch::Prefix),
+            "segment" => Ok(PathMatch::Segment),
+            "regex" => Ok(PathMatch::Regex),
+            _ => Err(format!(
+                "unknown path match mode '{mode}', expected substring, prefix, segment or regex"
+            )),
+        }
+    }
+}
+
+/// Which save path locations of a torrent are replaced
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathScope {
+    /// Only the save path, `target_save_path`/`qBt-savePath`
+    Save,
+    /// Only the incomplete download path, `download_path`/`qBt-downloadPath`
+    Download,
+    /// Both the save path and the incomplete download path
+    Both,
+}
+
+impl PathScope {
+    /// Returns true if the save path is replaced
+    fn save(self) -> bool {
+        self != PathScope::Download
+    }
+
+    /// Returns true if the incomplete download path is replaced
+    fn download(self) -> bool {
+        self != PathScope::Save
+    }
+}
+
+impl FromStr for PathScope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<PathScope, String> {
+        match scope {
+            "save" => Ok(PathScope::Save),
+            "download" => Ok(PathScope::Download),
+            "both" => Ok(PathScope::Both),
+            _ => Err(format!(
+                "unknown path scope '{scope}', expected save, download or both"
+            )),
+        }
+    }
+}
+
+/// New save path locations of a torrent, see `SavePath::rewrite`
+struct Replaced {
+    target_save_path: Option<String>,
+    download_path: Option<String>,
+    /// libtorrent save path, OS-specific
+    save_path: String,
+    /// Renamed file paths, only replaced along with the libtorrent save path
+    mapped_files: Option<Vec<String>>,
+}
+
+/// Returns true for both Unix- and Windows-style path separators
+pub fn is_separator(c: char) -> bool {
+    c == '/' || c == '\\'
+}
+
+/// Returns true for Unix-style absolute paths, Windows drive paths and network paths
+pub fn is_absolute(path: &str) -> bool {
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), _) if is_separator(c) => true,
+        (Some(drive), Some(':')) => drive.is_ascii_alphabetic(),
+        _ => false,
+    }
+}
 
 /// Fastresume save path information
 ///
@@ -24,28 +121,338 @@ pub struct SavePath {
     pub new: String,
     /// Separator to use in file paths, should default to current OS style
     pub separator: String,
+    /// How the existing save path is matched
+    pub match_mode: PathMatch,
+    /// Matches the existing save path case-insensitively, e.g. for paths from Windows
+    pub ignore_case: bool,
+    /// Which save path locations are replaced
+    pub scope: PathScope,
+    /// Compiled existing save path if the match mode is `PathMatch::Regex`
+    ///
+    /// The same expression is matched against Unix-style and OS-specific paths.
+    pub regex: Option<Regex>,
+}
+
+impl SavePath {
+    /// Builds the save path information for an existing and a new save path
+    ///
+    /// Fails if the match mode is `PathMatch::Regex` and the existing save path is not a valid
+    /// regular expression, see `pattern::compile_regex`.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let save_path = SavePath::new(
+    ///     "D:\\Downloads",
+    ///     "/downloads",
+    ///     "/",
+    ///     PathMatch::Prefix,
+    ///     true,
+    ///     PathScope::Both,
+    /// )?;
+    /// ```
+    pub fn new(
+        old: &str,
+        new: &str,
+        separator: &str,
+        match_mode: PathMatch,
+        ignore_case: bool,
+        scope: PathScope,
+    ) -> Result<SavePath, String> {
+        let regex = match match_mode {
+            PathMatch::Regex => Some(pattern::compile_regex(
+                old,
+                new,
+                ignore_case,
+                ("--old-path", "--new-path"),
+            )?),
+            _ => None,
+        };
+
+        // qB saves a Unix-style path irrespective of OS, so we need to use that in some places
+        // Yes, even Windows network paths are saved that way e.g. \\server\folder -> //server/folder
+        Ok(SavePath {
+            old_unix: old.replace('\\', "/"),
+            new_unix: new.replace('\\', "/"),
+            old: old.to_string(),
+            new: new.to_string(),
+            separator: separator.to_string(),
+            match_mode,
+            ignore_case,
+            scope,
+            regex,
+        })
+    }
+
+    /// Returns true if a save path location of a torrent in scope matches the existing save path
+    pub fn matches(&self, torrent: &DatabaseRow) -> Result<bool, Box<dyn Error>> {
+        Ok(self.replace_torrent(torrent)?.is_some())
+    }
+
+    /// Replaces the existing save path in all save path locations of a torrent in scope
+    ///
+    /// Returns the new libtorrent save path, or `None` if the torrent does not match. A warning is
+    /// shown if `target_save_path` or `download_path` pointed to the same directory as the
+    /// libtorrent save path before, but not after the replacement.
+    pub fn rewrite(&self, torrent: &mut DatabaseRow) -> Result<Option<String>, Box<dyn Error>> {
+        let Some(replaced) = self.replace_torrent(torrent)? else {
+            return Ok(None);
+        };
+
+        let mut resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?;
+        let resume_data_save_path = String::from_utf8(resume_data.save_path)?;
+
+        // Both paths pointed to the same directory, so they should keep doing so
+        let unix = |path: &str| path.replace('\\', "/");
+        for (name, old_path, new_path) in [
+            (
+                "target_save_path",
+                &torrent.target_save_path,
+                &replaced.target_save_path,
+            ),
+            (
+                "download_path",
+                &torrent.download_path,
+                &replaced.download_path,
+            ),
+        ] {
+            if let (Some(old_path), Some(new_path)) = (old_path, new_path) {
+                if unix(old_path) == unix(&resume_data_save_path)
+                    && unix(new_path) != unix(&replaced.save_path)
+                {
+                    eprintln!(
+                        "Save path: warning, the {name} of {} becomes {new_path:?}, but its \
+                         libtorrent save_path becomes {:?}",
+                        torrent.torrent_id, replaced.save_path
+                    );
+                }
+            }
+        }
+
+        torrent.target_save_path = replaced.target_save_path;
+        torrent.download_path = replaced.download_path;
+        resume_data.save_path = replaced.save_path.clone().into();
+        resume_data.mapped_files = replaced.mapped_files;
+        torrent.libtorrent_resume_data = serde_bencode::to_bytes(&resume_data)?;
+
+        Ok(Some(replaced.save_path))
+    }
+
+    /// Computes the new save path locations of a torrent, or `None` if none of those in scope match
+    fn replace_torrent(&self, torrent: &DatabaseRow) -> Result<Option<Replaced>, Box<dyn Error>> {
+        let resume_data: Fastresume = serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?;
+        let resume_data_save_path = String::from_utf8(resume_data.save_path)?;
+
+        // libtorrent stores the directory the torrent currently lives in, which is the download
+        // path while it is incomplete and the save path after that. In the libtorrent data, qB
+        // uses OS-specific separators
+        let unix = |path: &str| path.replace('\\', "/");
+        let in_download_path = torrent
+            .download_path
+            .as_ref()
+            .is_some_and(|path| unix(path) == unix(&resume_data_save_path));
+        let new_save_path = match in_download_path {
+            true => self.scope.download(),
+            false => self.scope.save(),
+        }
+        .then(|| self.replace_os(&resume_data_save_path))
+        .flatten();
+
+        // qB stores the "target_save_path" and "download_path" with Unix-style separators, even
+        // on Windows. These fields will be absent if the torrent is in AutoTMM mode
+        let replace_column = |path: &Option<String>, in_scope: bool| {
+            path.as_ref()
+                .filter(|_| in_scope)
+                .and_then(|path| self.replace_unix(path))
+        };
+        let new_target_save_path = replace_column(&torrent.target_save_path, self.scope.save());
+        let new_download_path = replace_column(&torrent.download_path, self.scope.download());
+
+        if new_save_path.is_none() && new_target_save_path.is_none() && new_download_path.is_none()
+        {
+            return Ok(None);
+        }
+
+        // Renamed files are relative to the libtorrent save path, unless they were moved out of it
+        let mapped_files = match new_save_path {
+            Some(_) => resume_data.mapped_files.map(|mapped_files| {
+                mapped_files
+                    .iter()
+                    .map(|path| self.replace_mapped_file(path))
+                    .collect()
+            }),
+            None => resume_data.mapped_files,
+        };
+
+        Ok(Some(Replaced {
+            target_save_path: new_target_save_path.or(torrent.target_save_path.clone()),
+            download_path: new_download_path.or(torrent.download_path.clone()),
+            save_path: new_save_path.unwrap_or(resume_data_save_path),
+            mapped_files,
+        }))
+    }
+
+    /// Returns true if the existing save path matches a path with either style of separators
+    pub fn matches_path(&self, path: &str) -> bool {
+        self.replace_unix(path).is_some()
+    }
+
+    /// Replaces the existing save path in a Unix-style path, such as `target_save_path`
+    ///
+    /// Returns `None` if the path does not match the existing save path.
+    pub fn replace_unix(&self, path: &str) -> Option<String> {
+        self.replace(path, &self.old_unix, &self.new_unix)
+    }
+
+    /// Replaces the existing save path in an OS-specific path, such as the libtorrent `save_path`
+    ///
+    /// It is up to the end user to make sure their path strings use the appropriate separator for
+    /// matching, unless a segment-aware match mode is used. However, we do allow conversion to and
+    /// from Windows- and Unix-style separators after the replacement.
+    ///
+    /// Returns `None` if the path does not match the existing save path.
+    pub fn replace_os(&self, path: &str) -> Option<String> {
+        let path = self.replace(path, &self.old, &self.new)?;
+        Some(self.convert_separators(&path))
+    }
+
+    /// Replaces the existing save path in a renamed file path from `mapped_files`
+    ///
+    /// Renamed files are usually relative to the save path, those only get their separators
+    /// converted. Absolute paths are replaced like the libtorrent save path, and left as they are
+    /// if they do not match.
+    pub fn replace_mapped_file(&self, path: &str) -> String {
+        if path.is_empty() {
+            return String::new();
+        }
+        if is_absolute(path) {
+            return self.replace_os(path).unwrap_or(path.to_string());
+        }
+        self.convert_separators(path)
+    }
+
+    /// Converts all separators in a path to the configured separator
+    fn convert_separators(&self, path: &str) -> String {
+        if self.separator == *"\\" {
+            path.replace('/', &self.separator)
+        } else {
+            path.replace('\\', &self.separator)
+        }
+    }
+
+    /// Replaces every match of `old` in `path` with `new`, according to the match mode
+    ///
+    /// The segment-aware match modes ignore trailing separators, so `/old/` and `/old` are the
+    /// same, and treat `/` and `\` as the same separator.
+    fn replace(&self, path: &str, old: &str, new: &str) -> Option<String> {
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(path) {
+                return None;
+            }
+            return Some(regex.replace_all(path, new).into_owned());
+        }
+
+        let (old, trimmed_new) = match self.match_mode {
+            PathMatch::Substring | PathMatch::Regex => (old, new),
+            PathMatch::Prefix | PathMatch::Segment => (
+                old.trim_end_matches(is_separator),
+                new.trim_end_matches(is_separator),
+            ),
+        };
+
+        let mut replaced = String::with_capacity(path.len());
+        let mut matched = false;
+        let mut index = 0;
+        while index < path.len() {
+            if let Some(end) = self.match_at(path, index, old) {
+                replaced.push_str(trimmed_new);
+                matched = true;
+                // An empty match, e.g. of the root directory, still has to move on
+                if end > index {
+                    index = end;
+                    continue;
+                }
+            }
+            let c = path[index..].chars().next()?;
+            replaced.push(c);
+            index += c.len_utf8();
+        }
+
+        match (matched, replaced.is_empty()) {
+            (false, _) => None,
+            // The new path is the root directory, which lost its only separator to the trimming
+            (true, true) => Some(new.to_string()),
+            (true, false) => Some(replaced),
+        }
+    }
+
+    /// Returns the end of the match if `old` matches `path` at the byte offset `start`
+    fn match_at(&self, path: &str, start: usize, old: &str) -> Option<usize> {
+        let segment_aware = matches!(self.match_mode, PathMatch::Prefix | PathMatch::Segment);
+        let at_segment_start =
+            start == 0 || old.starts_with(is_separator) || path[..start].ends_with(is_separator);
+        match self.match_mode {
+            PathMatch::Substring | PathMatch::Regex if old.is_empty() => return None,
+            PathMatch::Prefix if start != 0 => return None,
+            PathMatch::Segment if !at_segment_start || (old.is_empty() && start != 0) => {
+                return None
+            }
+            _ => {}
+        }
+
+        let mut end = start;
+        let mut path_chars = path[start..].chars();
+        for old_char in old.chars() {
+            let path_char = path_chars.next()?;
+            let equal = match (segment_aware, self.ignore_case) {
+                (true, _) if is_separator(old_char) && is_separator(path_char) => true,
+                (_, true) => old_char.to_lowercase().eq(path_char.to_lowercase()),
+                (_, false) => old_char == path_char,
+            };
+            if !equal {
+                return None;
+            }
+            end += path_char.len_utf8();
+        }
+
+        if segment_aware && end < path.len() && !path[end..].starts_with(is_separator) {
+            return None;
+        }
+
+        Some(end)
+    }
 }
 
-/// Performs a string replace operation on two database columns where qB stores the
-/// save path information: `target_save_path` and `libtorrent_resume_data`.
+/// Performs a string replace operation on the locations where qB stores the save path information:
+/// `target_save_path`, `download_path` and the `save_path` in `libtorrent_resume_data`. Renamed
+/// files in `mapped_files` get their separators converted, or are replaced as well if they are
+/// absolute paths.
+///
+/// For a BT_backup directory these are `qBt-savePath`, `qBt-downloadPath` and `save_path` in each
+/// .fastresume file.
+///
+/// The scope of the `SavePath` selects whether the save path, the incomplete download path or both
+/// are replaced. The libtorrent `save_path` follows whichever of the two the torrent currently
+/// lives in, i.e. the download path while it is incomplete.
 ///
 /// ## Example
 /// ```rs
-/// use qbfrt::db::save_path::{change_save_path, SavePath};
+/// use qbfrt::db::save_path::{change_save_path, PathMatch, PathScope, SavePath};
 /// let config = Config { verbose: true };
-/// let save_path = SavePath {
-///     old_unix: String::from("/old/save/path"),
-///     new_unix: String::from("/new/test/dir"),
-///     old: String::from("\\old\\save\\path"),
-///     new: String::from("\\new\\test\\dir"),
-///     separator: '\\'.to_string(),
-/// };
-/// change_save_path(&connection, save_path, config);
+/// let save_path = SavePath::new(
+///     "\\old\\save\\path",
+///     "\\new\\test\\dir",
+///     "\\",
+///     PathMatch::Segment,
+///     false,
+///     PathScope::Both,
+/// )?;
+/// change_save_path(&mut storage, save_path, config);
 /// ```
 ///
 /// ## Verbose output
-/// If verbose output is enabled it will output the torrent hash, the new target_save_path,
-/// and the new save_path within in the libtorrent_resume_data blob.
+/// If verbose output is enabled it will output the torrent hash, the new target_save_path, the new
+/// download_path, and the new save_path within in the libtorrent_resume_data blob.
 ///
 /// ## Troubleshooting
 /// ### Save path was updated but qB says the torrent files are missing
@@ -60,7 +467,7 @@ pub struct SavePath {
 /// > the target_save_path and libtorrent_resume_data have the exact same path separators for the new.
 /// > string. If they are different, you likely used the incorrect path separators in the old string.
 pub fn change_save_path(
-    db: &Connection,
+    storage: &mut dyn Storage,
     save_path: &SavePath,
     config: &Config,
 ) -> Result<(), Box<dyn Error>> {
@@ -69,86 +476,308 @@ pub fn change_save_path(
         save_path.old, save_path.new
     );
 
-    let all_torrents = query::fetch_all_torrents::<PathData>(
-        db,
-        "
-        SELECT id, torrent_id, target_save_path, libtorrent_resume_data 
-        FROM torrents
-        ",
-    )?;
-
     let mut num_torrents_updated = 0;
-    for torrent in all_torrents {
-        let mut trigger_update = false;
-        let bencoded_resume_data = torrent.libtorrent_resume_data.as_slice();
-        let mut resume_data: Fastresume = serde_bencode::from_bytes(bencoded_resume_data)?;
-        let resume_data_save_path = String::from_utf8(resume_data.save_path)?;
+    for mut torrent in config.filter.select(storage.torrents()?)? {
+        let Some(new_save_path) = save_path.rewrite(&mut torrent)? else {
+            continue;
+        };
 
-        // libtorrent save path is the source of truth since it will always be defined
-        if resume_data_save_path.contains(&save_path.old) {
-            trigger_update = true;
-        }
+        storage.update(&torrent)?;
 
-        // qB stores the "target_save_path" with Unix-style separators, even on Windows
-        // This field will be absent if the torrent is in AutoTMM mode
-        let mut target_save_path: Option<String> = None;
-        if torrent.target_save_path.is_some() {
-            target_save_path = Some(
-                torrent
-                    .target_save_path
-                    .unwrap()
-                    .replace(&save_path.old_unix, &save_path.new_unix),
+        if config.verbose {
+            println!("Save path: updated save path for {}", torrent.torrent_id);
+            println!(
+                "{}: new target_save_path is '{:?}'",
+                torrent.torrent_id, torrent.target_save_path
+            );
+            println!(
+                "{}: new download_path is '{:?}'",
+                torrent.torrent_id, torrent.download_path
+            );
+            println!(
+                "{}: new libtorrent_resume_data path is {:?}",
+                torrent.torrent_id, new_save_path
             );
         }
 
-        // In the libtorrent data, qB uses OS-specific separators. It is up to the end user to make
-        // sure their path strings use the appropriate separator for matching. However, we do allow
-        // conversion to and from Windows- and Unix-style separators after the replacement.
-        if save_path.separator == *"\\" {
-            resume_data.save_path = resume_data_save_path
-                .replace(&save_path.old, &save_path.new)
-                .replace('/', &save_path.separator)
-                .into();
-        } else {
-            resume_data.save_path = resume_data_save_path
-                .replace(&save_path.old, &save_path.new)
-                .replace('\\', &save_path.separator)
-                .into();
-        }
-
-        if trigger_update {
-            let mut update_stmt = db.prepare(
-                "
-                UPDATE torrents
-                SET target_save_path = :tsp, libtorrent_resume_data = :lrd
-                WHERE id = :id
-                RETURNING torrent_id;
-                ",
-            )?;
-            update_stmt.query_row(
-                named_params! {":tsp": target_save_path, ":lrd": serde_bencode::to_bytes(&resume_data)?, ":id": torrent.id},
-                |row| {
-                    let updated_row_id = row.get::<usize, String>(0)?;
-
-                    if config.verbose {
-                        println!("Save path: updated save path for {}", updated_row_id);
-                        println!("{}: new target_save_path is '{:?}'", updated_row_id, target_save_path);
-                        println!("{}: new libtorrent_resume_data path is {:?}", updated_row_id, String::from_utf8(resume_data.save_path).unwrap());
-                    }
-
-                    num_torrents_updated +=1;
-
-                    Ok(())
-                }
-            )?;
-        }
+        num_torrents_updated += 1;
     }
 
+    let verb = db::verb(config, num_torrents_updated);
     match num_torrents_updated {
-        0 => println!("Save path: no torrents were updated"),
-        1 => println!("Save path: 1 torrent was updated"),
-        _ => println!("Save path: {} torrents were updated", num_torrents_updated),
+        0 => println!("Save path: no torrents {verb} updated"),
+        1 => println!("Save path: 1 torrent {verb} updated"),
+        _ => println!("Save path: {num_torrents_updated} torrents {verb} updated"),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::bt_backup::BtBackupStorage;
+    use crate::test_util::{self, DOWNLOADING, MAGNET_ID, SEEDING, SEEDING_ID};
+
+    #[test]
+    fn changes_save_paths_in_fastresume_files() {
+        let dir = test_util::bt_backup_dir();
+        let config = test_util::bt_backup_config(
+            &dir,
+            &[
+                "--use-fastresume",
+                "--old-path",
+                "/data/",
+                "--new-path",
+                "/mnt/",
+                "--use-unix-sep",
+            ],
+        );
+        let mut storage = BtBackupStorage::open(&config).unwrap();
+
+        change_save_path(&mut storage, config.save_path.as_ref().unwrap(), &config).unwrap();
+
+        for torrent_id in [SEEDING_ID, MAGNET_ID] {
+            let torrent = storage.torrent(torrent_id).unwrap().unwrap();
+            let resume_data: Fastresume =
+                serde_bencode::from_bytes(&torrent.libtorrent_resume_data).unwrap();
+            assert_eq!(resume_data.save_path, b"/mnt/tv");
+            assert_eq!(torrent.target_save_path.as_deref(), Some("/mnt/tv"));
+        }
+    }
+
+    fn save_path(old: &str, new: &str, args: &[&str]) -> SavePath {
+        let mut all_args = vec!["--old-path", old, "--new-path", new, "--use-unix-sep"];
+        all_args.extend(args);
+        Config::from_args(&all_args).unwrap().save_path.unwrap()
+    }
+
+    #[test]
+    fn substring_replaces_inside_names() {
+        let save_path = save_path("/torrents/movie", "/media/movie", &[]);
+
+        assert_eq!(
+            save_path.replace_unix("/torrents/movies/a").as_deref(),
+            Some("/media/movies/a")
+        );
+        assert_eq!(save_path.replace_unix("/downloads/movie"), None);
+    }
+
+    #[test]
+    fn prefix_only_matches_whole_segments_at_the_start() {
+        let save_path = save_path(
+            "/torrents/movie/",
+            "/media/movie",
+            &["--path-match", "prefix"],
+        );
+
+        assert_eq!(
+            save_path.replace_unix("/torrents/movie").as_deref(),
+            Some("/media/movie")
+        );
+        assert_eq!(
+            save_path.replace_unix("/torrents/movie/a").as_deref(),
+            Some("/media/movie/a")
+        );
+        assert_eq!(save_path.replace_unix("/torrents/movies"), None);
+        assert_eq!(save_path.replace_unix("/mnt/torrents/movie"), None);
+    }
+
+    #[test]
+    fn segment_matches_whole_segments_anywhere() {
+        let save_path = save_path("torrents", "media", &["--path-match", "segment"]);
+
+        assert_eq!(
+            save_path.replace_unix("/mnt/torrents/tv").as_deref(),
+            Some("/mnt/media/tv")
+        );
+        assert_eq!(save_path.replace_unix("/mnt/old-torrents/tv"), None);
+        assert_eq!(save_path.replace_unix("/mnt/torrents2"), None);
+    }
+
+    #[test]
+    fn prefix_replaces_the_root_directory() {
+        let save_path = save_path("/", "/data", &["--path-match", "prefix"]);
+
+        assert_eq!(save_path.replace_unix("/tv").as_deref(), Some("/data/tv"));
+        assert_eq!(
+            save_path.replace_unix("/tv/Show").as_deref(),
+            Some("/data/tv/Show")
+        );
+    }
+
+    #[test]
+    fn ignore_case_matches_windows_paths() {
+        let save_path = save_path(
+            "D:\\Media",
+            "/mnt/media",
+            &["--path-match", "prefix", "--ignore-case"],
+        );
+
+        assert_eq!(
+            save_path.replace_os("d:\\media\\TV").as_deref(),
+            Some("/mnt/media/TV")
+        );
+        assert_eq!(
+            save_path.replace_unix("D:/MEDIA/tv").as_deref(),
+            Some("/mnt/media/tv")
+        );
+    }
+
+    #[test]
+    fn match_options_require_a_save_path() {
+        let error = Config::from_args(&["--path-match", "prefix"]).unwrap_err();
+        assert_eq!(
+            error,
+            "--path-match, --ignore-case and --path-scope require --old-path and --new-path"
+        );
+
+        let error = Config::from_args(&[
+            "--old-path",
+            "/a",
+            "--new-path",
+            "/b",
+            "--path-match",
+            "glob",
+        ])
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "unknown path match mode 'glob', expected substring, prefix, segment or regex"
+        );
+    }
+
+    #[test]
+    fn prefix_skips_sibling_directories() {
+        let dir = test_util::bt_backup_dir();
+        let config = test_util::bt_backup_config(
+            &dir,
+            &[
+                "--use-fastresume",
+                "--old-path",
+                "/data/t",
+                "--new-path",
+                "/mnt/t",
+                "--path-match",
+                "prefix",
+            ],
+        );
+        let mut storage = BtBackupStorage::open(&config).unwrap();
+
+        change_save_path(&mut storage, config.save_path.as_ref().unwrap(), &config).unwrap();
+
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.target_save_path.as_deref(), Some("/data/tv"));
+    }
+
+    #[test]
+    fn regex_replaces_with_capture_groups() {
+        let save_path = save_path(
+            r"^/mnt/disk[0-9]+/(?<kind>\w+)",
+            "/pool/${kind}",
+            &["--path-match", "regex"],
+        );
+
+        assert_eq!(
+            save_path.replace_unix("/mnt/disk3/tv/Show").as_deref(),
+            Some("/pool/tv/Show")
+        );
+        assert_eq!(save_path.replace_unix("/srv/disk3/tv"), None);
+    }
+
+    /// Returns the libtorrent save path of a torrent
+    fn libtorrent_save_path(torrent: &DatabaseRow) -> String {
+        let resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data).unwrap();
+        String::from_utf8(resume_data.save_path).unwrap()
+    }
+
+    /// Rewrites `/data` to `/mnt` in the incomplete torrent with the given path scope
+    fn rewrite_downloading(scope: &str) -> DatabaseRow {
+        let save_path = save_path(
+            "/data",
+            "/mnt",
+            &["--path-match", "prefix", "--path-scope", scope],
+        );
+        let mut torrent = test_util::torrent(SEEDING_ID, DOWNLOADING, None);
+        assert_eq!(
+            save_path.rewrite(&mut torrent).unwrap().as_deref(),
+            Some(libtorrent_save_path(&torrent).as_str())
+        );
+
+        torrent
+    }
+
+    #[test]
+    fn download_scope_only_rewrites_the_download_path() {
+        let torrent = rewrite_downloading("download");
+
+        assert_eq!(torrent.target_save_path.as_deref(), Some("/data/tv"));
+        assert_eq!(torrent.download_path.as_deref(), Some("/mnt/incomplete"));
+        assert_eq!(libtorrent_save_path(&torrent), "/mnt/incomplete");
+    }
+
+    #[test]
+    fn save_scope_leaves_incomplete_torrents_where_they_are() {
+        let torrent = rewrite_downloading("save");
+
+        assert_eq!(torrent.target_save_path.as_deref(), Some("/mnt/tv"));
+        assert_eq!(torrent.download_path.as_deref(), Some("/data/incomplete"));
+        assert_eq!(libtorrent_save_path(&torrent), "/data/incomplete");
+    }
+
+    #[test]
+    fn both_scopes_rewrite_every_location() {
+        let torrent = rewrite_downloading("both");
+
+        assert_eq!(torrent.target_save_path.as_deref(), Some("/mnt/tv"));
+        assert_eq!(torrent.download_path.as_deref(), Some("/mnt/incomplete"));
+        assert_eq!(libtorrent_save_path(&torrent), "/mnt/incomplete");
+    }
+
+    #[test]
+    fn download_scope_skips_completed_torrents() {
+        let save_path = save_path(
+            "/data",
+            "/mnt",
+            &["--path-match", "prefix", "--path-scope", "download"],
+        );
+        let mut torrent = test_util::torrent(SEEDING_ID, SEEDING, None);
+
+        assert!(!save_path.matches(&torrent).unwrap());
+        assert_eq!(save_path.rewrite(&mut torrent).unwrap(), None);
+        assert_eq!(libtorrent_save_path(&torrent), "/data/tv");
+    }
+
+    #[test]
+    fn path_scope_is_parsed() {
+        assert_eq!("download".parse::<PathScope>(), Ok(PathScope::Download));
+        assert_eq!(
+            "incomplete".parse::<PathScope>(),
+            Err(String::from(
+                "unknown path scope 'incomplete', expected save, download or both"
+            ))
+        );
+    }
+
+    #[test]
+    fn mapped_files_follow_the_separator() {
+        let save_path = SavePath::new(
+            "D:\\",
+            "/mnt/d/",
+            "/",
+            PathMatch::Prefix,
+            true,
+            PathScope::Both,
+        )
+        .unwrap();
+
+        assert_eq!(save_path.replace_mapped_file("Sub\\a.srt"), "Sub/a.srt");
+        assert_eq!(
+            save_path.replace_mapped_file("D:\\Other\\a.mkv"),
+            "/mnt/d/Other/a.mkv"
+        );
+        assert_eq!(save_path.replace_mapped_file("E:\\a.mkv"), "E:\\a.mkv");
+        assert_eq!(save_path.replace_mapped_file(""), "");
+    }
+}
@@ -0,0 +1,653 @@
+//! torrents.db schema detection and migration
+//!
+//! qB records the schema version of torrents.db in the `version` row of the `meta` table and
+//! upgrades older databases on start. Each version only ever added columns or indexes:
+//!
+//! | Version | Change                                                          |
+//! |---------|-----------------------------------------------------------------|
+//! | 1       | initial schema                                                  |
+//! | 2       | `download_path`                                                 |
+//! | 3       | `stop_condition`                                                |
+//! | 4       | index on `torrent_id`                                           |
+//! | 5       | `inactive_seeding_time_limit`                                   |
+//! | 6       | `ssl_certificate`, `ssl_private_key` and `ssl_dh_params`        |
+//! | 7       | `share_limit_action`                                            |
+//! | 8       | no column changes                                               |
+//!
+//! `migrate_schema` moves a database between these versions, so it can be used with an older or
+//! newer qB release.
+
+use crate::config::Config;
+use crate::db::{self, instance, DB};
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, DatabaseName, Transaction};
+use serde_bencode::value::Value as BencodeValue;
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Newest schema version this version of qbfrt knows about
+pub const LATEST_VERSION: i64 = 8;
+
+/// A column added by a schema version after the first
+pub struct AddedColumn {
+    /// Schema version the column was added in
+    pub version: i64,
+    /// Column name
+    pub name: &'static str,
+    /// Column definition, as qB creates it when upgrading
+    pub definition: &'static str,
+    /// Key of the value in a .fastresume file
+    pub resume_data_key: &'static str,
+}
+
+/// Columns added by each schema version, see the module documentation
+pub const ADDED_COLUMNS: [AddedColumn; 7] = [
+    AddedColumn {
+        version: 2,
+        name: "download_path",
+        definition: "TEXT",
+        resume_data_key: "qBt-downloadPath",
+    },
+    AddedColumn {
+        version: 3,
+        name: "stop_condition",
+        definition: "TEXT NOT NULL DEFAULT `None`",
+        resume_data_key: "qBt-stopCondition",
+    },
+    AddedColumn {
+        version: 5,
+        name: "inactive_seeding_time_limit",
+        definition: "INTEGER NOT NULL DEFAULT -2",
+        resume_data_key: "qBt-inactiveSeedingTimeLimit",
+    },
+    AddedColumn {
+        version: 6,
+        name: "ssl_certificate",
+        definition: "TEXT",
+        resume_data_key: "qBt-sslCertificate",
+    },
+    AddedColumn {
+        version: 6,
+        name: "ssl_private_key",
+        definition: "TEXT",
+        resume_data_key: "qBt-sslPrivateKey",
+    },
+    AddedColumn {
+        version: 6,
+        name: "ssl_dh_params",
+        definition: "TEXT",
+        resume_data_key: "qBt-sslDhParams",
+    },
+    AddedColumn {
+        version: 7,
+        name: "share_limit_action",
+        definition: "TEXT NOT NULL DEFAULT `Default`",
+        resume_data_key: "qBt-shareLimitAction",
+    },
+];
+
+/// Schema version that added the index on `torrent_id`
+const INDEX_VERSION: i64 = 4;
+
+/// Schema of an opened torrents.db
+#[derive(Debug, Clone)]
+pub struct Schema {
+    /// Schema version from the `meta` table
+    pub version: i64,
+    /// Columns of the `torrents` table, in table order
+    pub columns: Vec<String>,
+}
+
+impl Schema {
+    /// Reads the schema version and the columns of the `torrents` table
+    ///
+    /// Fails if the database was created by a qB release newer than this version of qbfrt
+    /// supports, since its columns can not be written safely.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let schema = Schema::read(&db)?;
+    /// println!("torrents.db schema version {}", schema.version);
+    /// ```
+    pub fn read(db: &Connection) -> Result<Schema, Box<dyn Error>> {
+        // qB stores the version as an integer, but the column is declared as BLOB
+        let version: Value = db
+            .query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| {
+                row.get(0)
+            })
+            .map_err(|err| {
+                format!("could not read the schema version, is this a qB torrents.db? {err}")
+            })?;
+        let version = match version {
+            Value::Integer(version) => version,
+            Value::Text(ref text) => text
+                .parse()
+                .map_err(|_| format!("invalid schema version {version:?}"))?,
+            _ => return Err(format!("invalid schema version {version:?}").into()),
+        };
+
+        if version > LATEST_VERSION {
+            return Err(format!(
+                "torrents.db has schema version {version}, but only versions up to \
+                 {LATEST_VERSION} are supported. Update qbfrt"
+            )
+            .into());
+        }
+        if version < 1 {
+            return Err(format!("invalid schema version {version}").into());
+        }
+
+        let mut stmt = db.prepare("SELECT name FROM pragma_table_info('torrents')")?;
+        let columns = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        if columns.is_empty() {
+            return Err("torrents.db has no torrents table".into());
+        }
+
+        Ok(Schema { version, columns })
+    }
+
+    /// Returns true if the `torrents` table has the given column
+    pub fn has_column(&self, column: &str) -> bool {
+        self.columns.iter().any(|name| name == column)
+    }
+
+    /// Returns the columns the schema version should have that are missing from the table
+    pub fn missing_columns(&self) -> Vec<&'static str> {
+        ADDED_COLUMNS
+            .iter()
+            .filter(|column| column.version <= self.version && !self.has_column(column.name))
+            .map(|column| column.name)
+            .collect()
+    }
+}
+
+/// Decoded `libtorrent_resume_data` blob
+type ResumeData = HashMap<Vec<u8>, BencodeValue>;
+
+/// Resume data and column values of a torrent, see `read_torrents`
+struct TorrentRow {
+    id: i64,
+    resume_data: ResumeData,
+    values: Vec<Value>,
+}
+
+/// Migrates torrents.db to another schema version
+///
+/// Upgrading adds the missing columns with the defaults qB uses. Downgrading drops the columns the
+/// older version does not know, but keeps their values in the `libtorrent_resume_data` blob under
+/// the `qBt-*` keys of a .fastresume file, e.g. `qBt-shareLimitAction`. qB ignores these keys, and
+/// a later upgrade moves them back into their columns, so switching back and forth between qB
+/// releases loses nothing. `SqliteStorage` treats a stashed `qBt-downloadPath` like the
+/// `download_path` column, so path rewrites in between also change it.
+///
+/// The migration runs in a single transaction. It is refused while qB is running, see
+/// `instance::check_not_running`, and the database is backed up first with `DB::backup`.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::schema;
+/// schema::migrate_schema(5, &config)?;
+/// ```
+///
+/// ## Dry run
+/// If config.dry_run is set, the migration runs on an in-memory copy of the database, which is
+/// discarded afterwards.
+///
+/// ## Verbose output
+/// If verbose output is enabled it will output each column that is added or dropped.
+pub fn migrate_schema(version: i64, config: &Config) -> Result<(), Box<dyn Error>> {
+    if !(1..=LATEST_VERSION).contains(&version) {
+        return Err(format!(
+            "unknown schema version {version}, only versions 1 to {LATEST_VERSION} are supported"
+        )
+        .into());
+    }
+
+    if !config.dry_run {
+        instance::check_not_running(config)?;
+    }
+    let schema = Schema::read(&DB::connect_read_only(config)?)?;
+    if schema.version == version {
+        println!("Migrate schema: torrents.db already has schema version {version}");
+        return Ok(());
+    }
+    println!(
+        "Migrate schema: migrating torrents.db from version {} to {version}",
+        schema.version
+    );
+
+    let mut db = match config.dry_run {
+        true => {
+            let mut db = Connection::open_in_memory()?;
+            db.restore(
+                DatabaseName::Main,
+                &config.db_file,
+                None::<fn(rusqlite::backup::Progress)>,
+            )?;
+            db
+        }
+        false => {
+            DB::backup(config)?;
+            DB::connect(config)?
+        }
+    };
+
+    let tx = db.transaction()?;
+    let (from, to) = (schema.version.min(version), schema.version.max(version));
+    let columns: Vec<&AddedColumn> = ADDED_COLUMNS
+        .iter()
+        .filter(|column| column.version > from && column.version <= to)
+        .collect();
+
+    let num_torrents_updated = if version > schema.version {
+        let columns: Vec<&AddedColumn> = columns
+            .into_iter()
+            .filter(|column| !schema.has_column(column.name))
+            .collect();
+        for column in &columns {
+            tx.execute_batch(&format!(
+                "ALTER TABLE torrents ADD COLUMN {} {}",
+                column.name, column.definition
+            ))?;
+            if config.verbose {
+                println!("Migrate schema: added column {}", column.name);
+            }
+        }
+        if from < INDEX_VERSION && INDEX_VERSION <= to {
+            tx.execute_batch(
+                "CREATE INDEX IF NOT EXISTS torrents_torrent_id_INDEX ON torrents (torrent_id)",
+            )?;
+        }
+        restore_columns(&tx, &columns)?
+    } else {
+        let columns: Vec<&AddedColumn> = columns
+            .into_iter()
+            .filter(|column| schema.has_column(column.name))
+            .collect();
+        let num_torrents_updated = stash_columns(&tx, &columns)?;
+        for column in &columns {
+            tx.execute_batch(&format!("ALTER TABLE torrents DROP COLUMN {}", column.name))?;
+            if config.verbose {
+                println!("Migrate schema: dropped column {}", column.name);
+            }
+        }
+        if from < INDEX_VERSION && INDEX_VERSION <= to {
+            tx.execute_batch("DROP INDEX IF EXISTS torrents_torrent_id_INDEX")?;
+        }
+        num_torrents_updated
+    };
+
+    tx.execute(
+        "UPDATE meta SET value = ?1 WHERE key = 'version'",
+        params![version],
+    )?;
+    tx.commit()?;
+
+    let verb = db::verb(config, num_torrents_updated);
+    match num_torrents_updated {
+        0 => println!("Migrate schema: no torrents {verb} updated"),
+        1 => println!("Migrate schema: 1 torrent {verb} updated"),
+        _ => println!("Migrate schema: {num_torrents_updated} torrents {verb} updated"),
+    }
+    println!(
+        "Migrate schema: torrents.db {} migrated to schema version {version}",
+        db::verb(config, 1)
+    );
+
+    Ok(())
+}
+
+/// Reads the resume data and the given columns of every torrent, by row id
+fn read_torrents(
+    tx: &Transaction,
+    columns: &[&AddedColumn],
+) -> Result<Vec<TorrentRow>, Box<dyn Error>> {
+    let names: Vec<&str> = columns.iter().map(|column| column.name).collect();
+    let mut stmt = tx.prepare(&format!(
+        "SELECT id, libtorrent_resume_data, {} FROM torrents",
+        names.join(", ")
+    ))?;
+    let rows = stmt
+        .query_map([], |row| {
+            let values = (0..columns.len())
+                .map(|index| row.get(index + 2))
+                .collect::<Result<Vec<Value>, _>>()?;
+            Ok((row.get(0)?, row.get::<_, Vec<u8>>(1)?, values))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut torrents = Vec::new();
+    for (id, resume_data, values) in rows {
+        let BencodeValue::Dict(resume_data) = serde_bencode::from_bytes(&resume_data)? else {
+            return Err(format!("libtorrent_resume_data of row {id} is not a dictionary").into());
+        };
+        torrents.push(TorrentRow {
+            id,
+            resume_data,
+            values,
+        });
+    }
+
+    Ok(torrents)
+}
+
+/// Writes the resume data of a torrent
+fn write_resume_data(
+    tx: &Transaction,
+    id: i64,
+    resume_data: ResumeData,
+) -> Result<(), Box<dyn Error>> {
+    let resume_data = serde_bencode::to_bytes(&BencodeValue::Dict(resume_data))?;
+    tx.prepare_cached("UPDATE torrents SET libtorrent_resume_data = ?1 WHERE id = ?2")?
+        .execute(params![resume_data, id])?;
+
+    Ok(())
+}
+
+/// Copies the values of columns that are about to be dropped into the resume data
+///
+/// Returns the number of torrents whose resume data changed.
+fn stash_columns(tx: &Transaction, columns: &[&AddedColumn]) -> Result<usize, Box<dyn Error>> {
+    if columns.is_empty() {
+        return Ok(0);
+    }
+
+    let mut num_torrents_updated = 0;
+    for TorrentRow {
+        id,
+        mut resume_data,
+        values,
+    } in read_torrents(tx, columns)?
+    {
+        let mut changed = false;
+        for (column, value) in columns.iter().zip(values) {
+            let value = match value {
+                Value::Null => continue,
+                Value::Integer(value) => BencodeValue::Int(value),
+                Value::Text(value) => BencodeValue::Bytes(value.into_bytes()),
+                value => {
+                    return Err(
+                        format!("unexpected {} value {value:?} in row {id}", column.name).into(),
+                    )
+                }
+            };
+            resume_data.insert(column.resume_data_key.as_bytes().to_vec(), value);
+            changed = true;
+        }
+
+        if changed {
+            write_resume_data(tx, id, resume_data)?;
+            num_torrents_updated += 1;
+        }
+    }
+
+    Ok(num_torrents_updated)
+}
+
+/// Moves the values stashed by `stash_columns` from the resume data into newly added columns
+///
+/// Columns without a stashed value keep their default. Returns the number of torrents whose
+/// resume data changed.
+fn restore_columns(tx: &Transaction, columns: &[&AddedColumn]) -> Result<usize, Box<dyn Error>> {
+    if columns.is_empty() {
+        return Ok(0);
+    }
+
+    let mut num_torrents_updated = 0;
+    for TorrentRow {
+        id,
+        mut resume_data,
+        ..
+    } in read_torrents(tx, columns)?
+    {
+        let mut changed = false;
+        for column in columns {
+            let Some(value) = resume_data.remove(column.resume_data_key.as_bytes()) else {
+                continue;
+            };
+            let value = match value {
+                BencodeValue::Int(value) => Value::Integer(value),
+                BencodeValue::Bytes(value) => Value::Text(String::from_utf8(value)?),
+                value => {
+                    return Err(format!(
+                        "unexpected {} value {value:?} in row {id}",
+                        column.resume_data_key
+                    )
+                    .into())
+                }
+            };
+            tx.execute(
+                &format!("UPDATE torrents SET {} = ?1 WHERE id = ?2", column.name),
+                params![value, id],
+            )?;
+            changed = true;
+        }
+
+        if changed {
+            write_resume_data(tx, id, resume_data)?;
+            num_torrents_updated += 1;
+        }
+    }
+
+    Ok(num_torrents_updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::save_path;
+    use crate::storage::sqlite::SqliteStorage;
+    use crate::storage::Storage;
+    use crate::test_util::{self, SEEDING_ID};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn reads_the_latest_schema() {
+        let schema = Schema::read(&test_util::database()).unwrap();
+        assert_eq!(schema.version, LATEST_VERSION);
+        assert!(schema.has_column("share_limit_action"));
+        assert!(schema.missing_columns().is_empty());
+    }
+
+    #[test]
+    fn reads_an_old_schema() {
+        let schema = Schema::read(&test_util::database_v1()).unwrap();
+        assert_eq!(schema.version, 1);
+        assert!(!schema.has_column("download_path"));
+        assert!(schema.missing_columns().is_empty());
+    }
+
+    #[test]
+    fn reports_missing_columns() {
+        let db = test_util::database();
+        db.execute_batch("ALTER TABLE torrents DROP COLUMN share_limit_action")
+            .unwrap();
+
+        let schema = Schema::read(&db).unwrap();
+        assert_eq!(schema.missing_columns(), ["share_limit_action"]);
+    }
+
+    #[test]
+    fn accepts_a_text_version() {
+        let db = test_util::database_v1();
+        db.execute_batch("UPDATE meta SET value = '3' WHERE key = 'version'")
+            .unwrap();
+
+        let schema = Schema::read(&db).unwrap();
+        assert_eq!(schema.version, 3);
+        assert_eq!(
+            schema.missing_columns(),
+            ["download_path", "stop_condition"]
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let db = test_util::database();
+        db.execute_batch("UPDATE meta SET value = 9 WHERE key = 'version'")
+            .unwrap();
+        let error = Schema::read(&db).unwrap_err();
+        assert!(error.to_string().contains("Update qbfrt"), "{error}");
+
+        db.execute_batch("UPDATE meta SET value = 0 WHERE key = 'version'")
+            .unwrap();
+        assert_eq!(
+            Schema::read(&db).unwrap_err().to_string(),
+            "invalid schema version 0"
+        );
+
+        let db = Connection::open_in_memory().unwrap();
+        let error = Schema::read(&db).unwrap_err();
+        assert!(
+            error.to_string().contains("is this a qB torrents.db?"),
+            "{error}"
+        );
+    }
+
+    /// Returns the moved columns and the resume data of the seeding torrent
+    fn seeding_row(config: &Config) -> (Option<String>, i64, String, Vec<u8>) {
+        DB::connect_read_only(config)
+            .unwrap()
+            .query_row(
+                "SELECT download_path, inactive_seeding_time_limit, share_limit_action, \
+                 libtorrent_resume_data FROM torrents WHERE torrent_id = ?1",
+                [SEEDING_ID],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap()
+    }
+
+    /// Returns the keys of the `libtorrent_resume_data` blob of the seeding torrent
+    fn resume_data(config: &Config) -> ResumeData {
+        let resume_data: Vec<u8> = DB::connect_read_only(config)
+            .unwrap()
+            .query_row(
+                "SELECT libtorrent_resume_data FROM torrents WHERE torrent_id = ?1",
+                [SEEDING_ID],
+                |row| row.get(0),
+            )
+            .unwrap();
+        match serde_bencode::from_bytes(&resume_data).unwrap() {
+            BencodeValue::Dict(resume_data) => resume_data,
+            _ => panic!("libtorrent_resume_data is not a dictionary"),
+        }
+    }
+
+    /// Creates a qB directory whose seeding torrent uses columns added after version 1
+    fn qb_dir(config_args: &[&str]) -> (TempDir, Config) {
+        let dir = test_util::qb_dir();
+        let mut args = vec!["--force"];
+        args.extend(config_args);
+        let config = test_util::qb_config(&dir, &args);
+        DB::connect(&config)
+            .unwrap()
+            .execute(
+                "UPDATE torrents SET download_path = '/data/incomplete', \
+                 inactive_seeding_time_limit = 60, share_limit_action = 'Stop' \
+                 WHERE torrent_id = ?1",
+                [SEEDING_ID],
+            )
+            .unwrap();
+
+        (dir, config)
+    }
+
+    #[test]
+    fn downgrade_and_upgrade_keep_values() {
+        let (_dir, config) = qb_dir(&[]);
+        let original = seeding_row(&config);
+
+        migrate_schema(1, &config).unwrap();
+        let db = DB::connect_read_only(&config).unwrap();
+        let schema = Schema::read(&db).unwrap();
+        assert_eq!(schema.version, 1);
+        assert!(!schema.has_column("share_limit_action"));
+        let resume_data = resume_data(&config);
+        assert_eq!(
+            resume_data.get(&b"qBt-shareLimitAction"[..]),
+            Some(&BencodeValue::Bytes(b"Stop".to_vec()))
+        );
+        assert_eq!(
+            resume_data.get(&b"qBt-inactiveSeedingTimeLimit"[..]),
+            Some(&BencodeValue::Int(60))
+        );
+        assert_eq!(
+            resume_data.get(&b"qBt-downloadPath"[..]),
+            Some(&BencodeValue::Bytes(b"/data/incomplete".to_vec()))
+        );
+
+        // The downgraded database can still be read, with the stashed download_path
+        let storage = SqliteStorage::new(db).unwrap();
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.download_path.as_deref(), Some("/data/incomplete"));
+
+        migrate_schema(LATEST_VERSION, &config).unwrap();
+        let db = DB::connect_read_only(&config).unwrap();
+        assert_eq!(Schema::read(&db).unwrap().version, LATEST_VERSION);
+        assert!(Schema::read(&db).unwrap().missing_columns().is_empty());
+        assert_eq!(seeding_row(&config), original);
+    }
+
+    #[test]
+    fn path_rewrites_update_the_stashed_download_path() {
+        let (_dir, config) = qb_dir(&[
+            "--old-path",
+            "/data",
+            "--new-path",
+            "/mnt",
+            "--use-unix-sep",
+        ]);
+
+        migrate_schema(1, &config).unwrap();
+        let mut storage = SqliteStorage::new(DB::connect(&config).unwrap()).unwrap();
+        save_path::change_save_path(&mut storage, config.save_path.as_ref().unwrap(), &config)
+            .unwrap();
+        drop(storage);
+        assert_eq!(
+            resume_data(&config).get(&b"qBt-downloadPath"[..]),
+            Some(&BencodeValue::Bytes(b"/mnt/incomplete".to_vec()))
+        );
+
+        migrate_schema(LATEST_VERSION, &config).unwrap();
+        let (download_path, _, _, resume_data) = seeding_row(&config);
+        assert_eq!(download_path.as_deref(), Some("/mnt/incomplete"));
+        let resume_data: BencodeValue = serde_bencode::from_bytes(&resume_data).unwrap();
+        let BencodeValue::Dict(resume_data) = resume_data else {
+            panic!("libtorrent_resume_data is not a dictionary");
+        };
+        assert!(!resume_data.contains_key(&b"qBt-downloadPath"[..]));
+        assert_eq!(
+            resume_data.get(&b"save_path"[..]),
+            Some(&BencodeValue::Bytes(b"/mnt/tv".to_vec()))
+        );
+    }
+
+    #[test]
+    fn dry_run_leaves_the_database_untouched() {
+        let (dir, config) = qb_dir(&["--dry-run"]);
+        let original = fs::read(&config.db_file).unwrap();
+
+        migrate_schema(1, &config).unwrap();
+
+        assert_eq!(fs::read(&config.db_file).unwrap(), original);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let (_dir, config) = qb_dir(&[]);
+
+        let error = migrate_schema(LATEST_VERSION + 1, &config).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "unknown schema version {}, only versions 1 to {LATEST_VERSION} are supported",
+                LATEST_VERSION + 1
+            )
+        );
+        assert!(migrate_schema(LATEST_VERSION, &config).is_ok());
+    }
+}
@@ -1,10 +1,12 @@
 //! Tools for modifying torrent tracker URLs
 
-use crate::common::database::LibtorrentResumeData;
+use crate::common::database::DatabaseRow;
 use crate::common::fastresume::Fastresume;
+use crate::common::pattern;
 use crate::config::Config;
-use crate::db::query;
-use rusqlite::{named_params, Connection};
+use crate::db;
+use crate::storage::Storage;
+use regex::Regex;
 use std::error::Error;
 
 /// Tracker url information
@@ -14,6 +16,93 @@ pub struct TrackerUrl {
     pub old: String,
     /// New tracker URL
     pub new: String,
+    /// Compiled existing tracker URL if it is a regular expression
+    ///
+    /// The new tracker URL may then refer to its capture groups as `$1` or `${name}`.
+    pub regex: Option<Regex>,
+}
+
+impl TrackerUrl {
+    /// Builds the tracker url information for an existing and a new tracker URL
+    ///
+    /// Fails if `regex` is set and the existing tracker URL is not a valid regular expression,
+    /// see `pattern::compile_regex`.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let tracker_url = TrackerUrl::new("^http://", "https://", true)?;
+    /// ```
+    pub fn new(old: &str, new: &str, regex: bool) -> Result<TrackerUrl, String> {
+        let regex = match regex {
+            true => Some(pattern::compile_regex(
+                old,
+                new,
+                false,
+                ("--old-tracker", "--new-tracker"),
+            )?),
+            false => None,
+        };
+
+        Ok(TrackerUrl {
+            old: old.to_string(),
+            new: new.to_string(),
+            regex,
+        })
+    }
+
+    /// Returns true if one of the trackers of a torrent matches the existing tracker URL
+    pub fn matches(&self, torrent: &DatabaseRow) -> Result<bool, Box<dyn Error>> {
+        let resume_data: Fastresume = serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?;
+
+        Ok(self.replace_trackers(&resume_data.trackers).is_some())
+    }
+
+    /// Replaces the existing tracker URL in the trackers of a torrent
+    ///
+    /// Returns the new trackers, or `None` if the torrent does not match.
+    pub fn rewrite(
+        &self,
+        torrent: &mut DatabaseRow,
+    ) -> Result<Option<Vec<Vec<String>>>, Box<dyn Error>> {
+        let bencoded_resume_data = torrent.libtorrent_resume_data.as_slice();
+        let mut resume_data: Fastresume = serde_bencode::from_bytes(bencoded_resume_data)?;
+
+        // Trackers are stored in a nested bencode list, only relevant torrents are updated
+        let Some(updated_trackers) = self.replace_trackers(&resume_data.trackers) else {
+            return Ok(None);
+        };
+        resume_data.trackers = updated_trackers.clone();
+        torrent.libtorrent_resume_data = serde_bencode::to_bytes(&resume_data)?;
+
+        Ok(Some(updated_trackers))
+    }
+
+    /// Replaces the existing tracker URL in a nested list of tracker tiers
+    ///
+    /// Returns `None` if none of the trackers contain the existing tracker URL.
+    pub fn replace_trackers(&self, trackers: &[Vec<String>]) -> Option<Vec<Vec<String>>> {
+        let matched = trackers.iter().flatten().any(|tracker| match &self.regex {
+            Some(regex) => regex.is_match(tracker),
+            None => tracker.contains(&self.old),
+        });
+        if !matched {
+            return None;
+        }
+
+        let updated_trackers = trackers
+            .iter()
+            .map(|tier| {
+                tier.iter()
+                    .map(|tracker| match &self.regex {
+                        Some(regex) => regex.replace_all(tracker, &self.new).into_owned(),
+                        None => tracker.replace(&self.old, &self.new),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Some(updated_trackers)
+    }
 }
 
 /// Performs a string replace operation on torrent trackers
@@ -22,18 +111,15 @@ pub struct TrackerUrl {
 /// ```rs
 /// use qbfrt::db::{tracker_url, TrackerUrl};
 /// let config = Config { verbose: true };
-/// let tracker_url = TrackerUrl {
-///     old: String::from("http://"),
-///     new: String::from("https://"),
-/// };
-/// change_tracker_url(&connection, tracker_url, config);
+/// let tracker_url = TrackerUrl::new("http://", "https://", false)?;
+/// change_tracker_url(&mut storage, tracker_url, config);
 /// ```
 ///
 /// ## Verbose output
 /// If verbose output is enabled it will output the torrent hash and full trackers list for
 /// the updated torrent.
 pub fn change_tracker_url(
-    db: &Connection,
+    storage: &mut dyn Storage,
     tracker_url: &TrackerUrl,
     config: &Config,
 ) -> Result<(), Box<dyn Error>> {
@@ -42,77 +128,92 @@ pub fn change_tracker_url(
         tracker_url.old, tracker_url.new
     );
 
-    let all_torrents = query::fetch_all_torrents::<LibtorrentResumeData>(
-        db,
-        "
-        SELECT id, torrent_id, libtorrent_resume_data 
-        FROM torrents
-        ",
-    )?;
-
     let mut num_torrents_updated = 0;
-    for torrent in all_torrents {
-        let mut trigger_update = false;
-        let bencoded_resume_data = torrent.libtorrent_resume_data.as_slice();
-        let mut resume_data: Fastresume = serde_bencode::from_bytes(bencoded_resume_data)?;
-
-        // Trackers are stored in a nested bencode list
-        let mut updated_trackers: Vec<Vec<String>> = Vec::new();
-        resume_data.trackers.iter().for_each(|outer| {
-            let mut tracker_list: Vec<String> = Vec::new();
-
-            outer.iter().for_each(|tracker| {
-                // this will be used later to trigger update on only relevant torrents
-                if tracker.contains(&tracker_url.old) {
-                    trigger_update = true;
-                }
-
-                tracker_list.push(tracker.replace(&tracker_url.old, &tracker_url.new));
-            });
-
-            updated_trackers.push(tracker_list);
-        });
-
-        resume_data.trackers = updated_trackers;
-
-        if trigger_update {
-            let mut update_stmt = db.prepare(
-                "
-                UPDATE torrents
-                SET libtorrent_resume_data = :lrd
-                WHERE id = :id
-                RETURNING torrent_id;
-                ",
-            )?;
-            update_stmt.query_row(
-                named_params! {":lrd": serde_bencode::to_bytes(&resume_data)?, ":id": torrent.id},
-                |row| {
-                    let updated_row_id = row.get::<usize, String>(0)?;
-
-                    if config.verbose {
-                        println!("Tracker url: updated tracker URLs for {}", updated_row_id);
-                        println!(
-                            "{}: new tracker urls are {:?}",
-                            updated_row_id, resume_data.trackers
-                        );
-                    }
-
-                    num_torrents_updated += 1;
-
-                    Ok(())
-                },
-            )?;
+    for mut torrent in config.filter.select(storage.torrents()?)? {
+        let Some(updated_trackers) = tracker_url.rewrite(&mut torrent)? else {
+            continue;
+        };
+
+        storage.update(&torrent)?;
+
+        if config.verbose {
+            println!(
+                "Tracker url: updated tracker URLs for {}",
+                torrent.torrent_id
+            );
+            println!(
+                "{}: new tracker urls are {:?}",
+                torrent.torrent_id, updated_trackers
+            );
         }
+
+        num_torrents_updated += 1;
     }
 
+    let verb = db::verb(config, num_torrents_updated);
     match num_torrents_updated {
-        0 => print!("Tracker url: no torrents were updated"),
-        1 => println!("Tracker url: 1 torrent was updated"),
-        _ => println!(
-            "Tracker url: {} torrents were updated",
-            num_torrents_updated
-        ),
+        0 => println!("Tracker url: no torrents {verb} updated"),
+        1 => println!("Tracker url: 1 torrent {verb} updated"),
+        _ => println!("Tracker url: {num_torrents_updated} torrents {verb} updated"),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, SEEDING_ID};
+
+    #[test]
+    fn changes_matching_trackers() {
+        let mut storage = test_util::loaded_storage();
+        let config = Config::from_args(&[
+            "--old-tracker",
+            "tracker.example.org",
+            "--new-tracker",
+            "tracker.example.net",
+        ])
+        .unwrap();
+
+        change_tracker_url(&mut storage, config.tracker_url.as_ref().unwrap(), &config).unwrap();
+
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        let resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data).unwrap();
+        assert_eq!(
+            resume_data.trackers,
+            [
+                ["http://tracker.example.net:6969/announce"],
+                ["udp://backup.example.org:1337/announce"]
+            ]
+        );
+    }
+
+    #[test]
+    fn regex_replaces_with_capture_groups() {
+        let config = Config::from_args(&[
+            "--old-tracker",
+            r"^(https?)://tracker\.example\.org:\d+",
+            "--new-tracker",
+            "${1}s://tracker.example.net",
+            "--tracker-match",
+            "regex",
+        ])
+        .unwrap();
+        let tracker_url = config.tracker_url.unwrap();
+
+        let trackers = vec![
+            vec![String::from("http://tracker.example.org:6969/announce")],
+            vec![String::from("udp://tracker.example.org:1337/announce")],
+        ];
+        assert_eq!(
+            tracker_url.replace_trackers(&trackers).unwrap(),
+            [
+                ["https://tracker.example.net/announce"],
+                ["udp://tracker.example.org:1337/announce"]
+            ]
+        );
+        assert_eq!(tracker_url.replace_trackers(&trackers[1..]), None);
+    }
+}
@@ -0,0 +1,320 @@
+//! Tools for reverting the changes of an earlier run
+
+use crate::config::Config;
+use crate::db;
+use crate::storage::journal::{self, Journal};
+use crate::storage::Storage;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+
+/// Reverts the changes an earlier run recorded in its undo journal
+///
+/// Only the torrents changed by that run are touched, and only the columns and resume data keys it
+/// changed. Torrents added since are left alone. A torrent is skipped with a warning if one of
+/// those values was changed again after the run, since reverting it would lose the newer change.
+///
+/// The undo is recorded in a journal of its own, so it can be undone as well.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::undo;
+/// undo::undo_run(&mut storage, "20240101120000", &config)?;
+/// ```
+///
+/// ## Verbose output
+/// If verbose output is enabled it will output the torrent hash for each torrent reverted.
+pub fn undo_run(
+    storage: &mut dyn Storage,
+    run_id: &str,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    println!("Undo: reverting run {run_id}");
+
+    let journal = Journal::load(run_id, config)?;
+    if journal.storage != journal::storage_mode(config) {
+        return Err(format!(
+            "run {run_id} changed the {} storage, not {}",
+            journal.storage,
+            journal::storage_mode(config)
+        )
+        .into());
+    }
+
+    let mut num_torrents_reverted = 0;
+    for entry in &journal.torrents {
+        let torrent_id = &entry.torrent_id;
+        let current = storage.torrent(torrent_id)?;
+
+        match (&entry.before, &entry.after, current) {
+            // The torrent was added by the run
+            (None, Some(_), Some(_)) => storage.remove(torrent_id)?,
+            // The torrent was removed by the run
+            (Some(before), None, None) => {
+                let changes = before
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Some(value.clone())))
+                    .collect();
+                storage.insert(&journal::apply_columns(None, &changes)?)?;
+            }
+            (Some(before), Some(after), Some(current)) => {
+                let columns = journal::columns(&current)?;
+                let conflicts: BTreeSet<&String> = before
+                    .keys()
+                    .chain(after.keys())
+                    .filter(|name| columns.get(*name) != after.get(*name))
+                    .collect();
+                if !conflicts.is_empty() {
+                    eprintln!(
+                        "Undo: skipping {torrent_id}, it was changed again since: {conflicts:?}"
+                    );
+                    continue;
+                }
+
+                let mut changes: BTreeMap<String, _> =
+                    after.keys().map(|name| (name.clone(), None)).collect();
+                for (name, value) in before {
+                    changes.insert(name.clone(), Some(value.clone()));
+                }
+                storage.update(&journal::apply_columns(Some(&current), &changes)?)?;
+            }
+            (_, None, Some(_)) => {
+                eprintln!("Undo: skipping {torrent_id}, it was added again since");
+                continue;
+            }
+            (_, _, None) => {
+                eprintln!("Undo: skipping {torrent_id}, it was removed since");
+                continue;
+            }
+        }
+
+        if config.verbose {
+            println!("Undo: reverted {torrent_id}");
+        }
+
+        num_torrents_reverted += 1;
+    }
+
+    let verb = db::verb(config, num_torrents_reverted);
+    match num_torrents_reverted {
+        0 => println!("Undo: no torrents {verb} reverted"),
+        1 => println!("Undo: 1 torrent {verb} reverted"),
+        _ => println!("Undo: {num_torrents_reverted} torrents {verb} reverted"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::database::DatabaseRow;
+    use crate::common::fastresume::Fastresume;
+    use crate::db::{save_path, DB};
+    use crate::storage::bt_backup::BtBackupStorage;
+    use crate::storage::journal::JournalStorage;
+    use crate::storage::sqlite::SqliteStorage;
+    use crate::test_util::{self, SEEDING_ID};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn open(config: &Config) -> Box<dyn Storage> {
+        Box::new(SqliteStorage::new(DB::connect(config).unwrap()).unwrap())
+    }
+
+    /// Moves every torrent from /data/ to /mnt/ in a journaled run and returns its run id
+    fn move_torrents(dir: &TempDir) -> String {
+        let config = test_util::qb_config(
+            dir,
+            &[
+                "--old-path",
+                "/data/",
+                "--new-path",
+                "/mnt/",
+                "--use-unix-sep",
+            ],
+        );
+        let mut storage = JournalStorage::new(open(&config), &config);
+        storage.begin().unwrap();
+        save_path::change_save_path(&mut storage, config.save_path.as_ref().unwrap(), &config)
+            .unwrap();
+        storage.commit().unwrap();
+
+        latest_run_id(&config)
+    }
+
+    /// Returns the id of the last journaled run
+    fn latest_run_id(config: &Config) -> String {
+        let mut run_ids: Vec<String> = fs::read_dir(config.journal_directory())
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                path.file_stem().unwrap().to_str().unwrap().to_string()
+            })
+            .collect();
+        run_ids.sort_by_key(|run_id| (run_id.len(), run_id.clone()));
+        run_ids.pop().unwrap()
+    }
+
+    /// Reverts a run in a journaled run of its own
+    fn undo(dir: &TempDir, run_id: &str) {
+        let config = test_util::qb_config(dir, &["--undo", run_id]);
+        let mut storage = JournalStorage::new(open(&config), &config);
+        storage.begin().unwrap();
+        undo_run(&mut storage, run_id, &config).unwrap();
+        storage.commit().unwrap();
+    }
+
+    /// Returns the target_save_path column and the libtorrent save_path of the seeding torrent
+    fn save_paths(dir: &TempDir) -> (String, String) {
+        let config = test_util::qb_config(dir, &[]);
+        let torrent = open(&config).torrent(SEEDING_ID).unwrap().unwrap();
+        let resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data).unwrap();
+        (
+            torrent.target_save_path.unwrap(),
+            String::from_utf8(resume_data.save_path).unwrap(),
+        )
+    }
+
+    /// Changes a column of the seeding torrent outside of a journaled run
+    fn change_outside_run(dir: &TempDir, change: impl FnOnce(&mut DatabaseRow)) {
+        let config = test_util::qb_config(dir, &[]);
+        let mut storage = open(&config);
+        let mut torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        change(&mut torrent);
+        storage.update(&torrent).unwrap();
+    }
+
+    #[test]
+    fn journal_keeps_only_changed_columns() {
+        let dir = test_util::qb_dir();
+        let run_id = move_torrents(&dir);
+
+        let journal = Journal::load(&run_id, &test_util::qb_config(&dir, &[])).unwrap();
+        assert_eq!(journal.storage, "torrents.db");
+        let entry = journal
+            .torrents
+            .iter()
+            .find(|entry| entry.torrent_id == SEEDING_ID)
+            .unwrap();
+        let changed: Vec<&String> = entry.after.as_ref().unwrap().keys().collect();
+        assert_eq!(
+            changed,
+            ["libtorrent_resume_data.save_path", "target_save_path"]
+        );
+    }
+
+    #[test]
+    fn reverts_a_run() {
+        let dir = test_util::qb_dir();
+        let run_id = move_torrents(&dir);
+        assert_eq!(
+            save_paths(&dir),
+            (String::from("/mnt/tv"), String::from("/mnt/tv"))
+        );
+
+        undo(&dir, &run_id);
+        assert_eq!(
+            save_paths(&dir),
+            (String::from("/data/tv"), String::from("/data/tv"))
+        );
+
+        // The undo is journaled as well, so it can be undone in turn
+        let undo_run_id = latest_run_id(&test_util::qb_config(&dir, &[]));
+        assert_ne!(undo_run_id, run_id);
+        undo(&dir, &undo_run_id);
+        assert_eq!(
+            save_paths(&dir),
+            (String::from("/mnt/tv"), String::from("/mnt/tv"))
+        );
+    }
+
+    #[test]
+    fn ignores_unrelated_changes() {
+        let dir = test_util::qb_dir();
+        let run_id = move_torrents(&dir);
+
+        // The user may recategorize the torrent after the run
+        change_outside_run(&dir, |torrent| {
+            torrent.category = Some(String::from("archive"))
+        });
+
+        undo(&dir, &run_id);
+        assert_eq!(
+            save_paths(&dir),
+            (String::from("/data/tv"), String::from("/data/tv"))
+        );
+        let config = test_util::qb_config(&dir, &[]);
+        let torrent = open(&config).torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.category.as_deref(), Some("archive"));
+    }
+
+    #[test]
+    fn skips_torrents_changed_again() {
+        let dir = test_util::qb_dir();
+        let run_id = move_torrents(&dir);
+
+        change_outside_run(&dir, |torrent| {
+            torrent.target_save_path = Some(String::from("/srv/tv"))
+        });
+
+        undo(&dir, &run_id);
+        assert_eq!(
+            save_paths(&dir),
+            (String::from("/srv/tv"), String::from("/mnt/tv"))
+        );
+    }
+
+    #[test]
+    fn rolled_back_run_has_no_journal() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+        let mut storage = JournalStorage::new(open(&config), &config);
+
+        storage.begin().unwrap();
+        storage.remove(SEEDING_ID).unwrap();
+        storage.rollback().unwrap();
+
+        assert!(!config.journal_directory().exists());
+        assert!(open(&config).torrent(SEEDING_ID).unwrap().is_some());
+    }
+
+    #[test]
+    fn restores_removed_torrents() {
+        let dir = test_util::qb_dir();
+        let config = test_util::qb_config(&dir, &[]);
+        let original = open(&config).torrent(SEEDING_ID).unwrap().unwrap();
+
+        let mut storage = JournalStorage::new(open(&config), &config);
+        storage.begin().unwrap();
+        storage.remove(SEEDING_ID).unwrap();
+        storage.commit().unwrap();
+        assert!(open(&config).torrent(SEEDING_ID).unwrap().is_none());
+
+        undo(&dir, &latest_run_id(&config));
+        let restored = open(&config).torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(
+            restored.libtorrent_resume_data,
+            original.libtorrent_resume_data
+        );
+        assert_eq!(restored.metadata, original.metadata);
+        assert_eq!(restored.tags, original.tags);
+    }
+
+    #[test]
+    fn refuses_journals_of_the_other_storage() {
+        let dir = test_util::qb_dir();
+        let run_id = move_torrents(&dir);
+
+        let config = test_util::qb_config(&dir, &["--use-fastresume", "--undo", &run_id]);
+        let bt_backup_dir = test_util::bt_backup_dir();
+        let mut storage =
+            BtBackupStorage::open(&test_util::bt_backup_config(&bt_backup_dir, &[])).unwrap();
+
+        let error = undo_run(&mut storage, &run_id, &config).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("run {run_id} changed the torrents.db storage, not BT_backup")
+        );
+    }
+}
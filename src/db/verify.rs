@@ -0,0 +1,692 @@
+//! Tools for verifying torrent content on disk
+
+use crate::common::content::{ContentFile, TorrentContent};
+use crate::common::database::DatabaseRow;
+use crate::config::Config;
+use crate::storage::Storage;
+use sha1::{Digest, Sha1};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Largest piece length that is hashed, larger ones only come from corrupt metadata
+const MAX_PIECE_LENGTH: u64 = 128 * 1024 * 1024;
+
+/// Verification result for a single torrent
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    /// All pieces match their hashes
+    Ok,
+    /// None of the torrent files exist
+    Missing,
+    /// Some pieces do not match their hashes
+    Corrupt,
+    /// Some files are missing or have the wrong size, or the torrent is not completely downloaded
+    Partial,
+    /// The torrent can not be verified, e.g. it has no metadata
+    Unsupported(String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Ok => write!(f, "OK"),
+            Status::Missing => write!(f, "MISSING"),
+            Status::Corrupt => write!(f, "CORRUPT"),
+            Status::Partial => write!(f, "PARTIAL"),
+            Status::Unsupported(reason) => write!(f, "SKIPPED ({reason})"),
+        }
+    }
+}
+
+/// Verification report for a single torrent
+#[derive(Debug)]
+pub struct Report {
+    /// Torrent hash
+    pub torrent_id: String,
+    /// Torrent name
+    pub name: String,
+    /// Overall result
+    pub status: Status,
+    /// Number of pieces in the torrent
+    pub num_pieces: usize,
+    /// Pieces that do not match their hash
+    pub failed_pieces: Vec<usize>,
+    /// Pieces that could not be read completely
+    pub unavailable_pieces: usize,
+    /// Files that do not exist
+    pub missing_files: Vec<ContentFile>,
+    /// Files that are shorter or longer than expected
+    pub mismatched_files: Vec<ContentFile>,
+}
+
+impl Report {
+    fn unsupported(torrent: &DatabaseRow, reason: String) -> Report {
+        Report {
+            torrent_id: torrent.torrent_id.clone(),
+            name: torrent.name.clone().unwrap_or_default(),
+            status: Status::Unsupported(reason),
+            num_pieces: 0,
+            failed_pieces: vec![],
+            unavailable_pieces: 0,
+            missing_files: vec![],
+            mismatched_files: vec![],
+        }
+    }
+}
+
+/// Verifies torrent content on disk against the piece hashes in the torrent metadata
+///
+/// Files are located through the effective save path, the content layout and `mapped_files`, see
+/// `TorrentContent::resolve`. Each torrent is reported as OK, missing, corrupt or partial. Only v1
+/// piece hashes are checked, so v2-only torrents are skipped. Hybrid torrents are verified through
+/// their v1 hashes.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::verify;
+/// let config = Config { verbose: true, jobs: 4 };
+/// verify::verify_torrents(&storage, config);
+/// ```
+///
+/// ## Configuration
+/// - Only torrents selected by config.filter are verified.
+/// - Files are looked up through config.path_lookup, if set.
+/// - Torrents are verified in parallel by config.jobs threads.
+///
+/// ## Verbose output
+/// If verbose output is enabled it will output the missing and mismatched files and the failed
+/// pieces of each torrent.
+pub fn verify_torrents(storage: &dyn Storage, config: &Config) -> Result<(), Box<dyn Error>> {
+    println!("Verify: checking torrent content...");
+
+    let torrents = lookup_paths(config.filter.select(storage.torrents()?)?, config)?;
+
+    let reports = run_parallel(&torrents, config.jobs, verify_torrent);
+
+    let mut num_ok = 0;
+    for report in &reports {
+        print_report("Verify", report, config);
+        if report.status == Status::Ok {
+            num_ok += 1;
+        }
+    }
+
+    match reports.len() {
+        0 => println!("Verify: no torrents were verified"),
+        1 => println!("Verify: 1 torrent was verified, {} OK", num_ok),
+        _ => println!(
+            "Verify: {} torrents were verified, {} OK",
+            reports.len(),
+            num_ok
+        ),
+    }
+
+    Ok(())
+}
+
+/// Checks that the content files of each torrent exist with their expected length
+///
+/// This is a quick alternative to `verify_torrents` that does not read any file data, so it can
+/// be run over a large library right after a save path change. Each torrent is reported as OK,
+/// missing or partial.
+///
+/// ## Example
+/// ```rs
+/// use qbfrt::db::verify;
+/// let config = Config { verbose: true };
+/// verify::check_torrents(&storage, config);
+/// ```
+///
+/// ## Configuration
+/// - Only torrents selected by config.filter are checked.
+/// - Files are looked up through config.path_lookup, if set.
+///
+/// ## Verbose output
+/// Only torrents with missing files or files of the wrong size are listed, unless verbose output is
+/// enabled. In that case every torrent is listed along with its missing and mismatched files.
+pub fn check_torrents(storage: &dyn Storage, config: &Config) -> Result<(), Box<dyn Error>> {
+    println!("Check files: checking torrent files...");
+
+    let torrents = lookup_paths(config.filter.select(storage.torrents()?)?, config)?;
+
+    let reports = run_parallel(&torrents, config.jobs, check_torrent);
+
+    let mut num_ok = 0;
+    for report in &reports {
+        if report.status == Status::Ok {
+            num_ok += 1;
+        }
+        if report.status != Status::Ok || config.verbose {
+            print_report("Check files", report, config);
+        }
+    }
+
+    match reports.len() {
+        0 => println!("Check files: no torrents were checked"),
+        1 => println!("Check files: 1 torrent was checked, {} OK", num_ok),
+        _ => println!(
+            "Check files: {} torrents were checked, {} OK",
+            reports.len(),
+            num_ok
+        ),
+    }
+
+    Ok(())
+}
+
+/// Translates the paths of torrents to where this machine finds their files, see
+/// `Config::path_lookup`
+///
+/// Only the in-memory copies are translated, the torrents themselves are left as they are.
+fn lookup_paths(
+    mut torrents: Vec<DatabaseRow>,
+    config: &Config,
+) -> Result<Vec<DatabaseRow>, Box<dyn Error>> {
+    if let Some(path_lookup) = &config.path_lookup {
+        for torrent in &mut torrents {
+            path_lookup.rewrite(torrent)?;
+        }
+    }
+
+    Ok(torrents)
+}
+
+/// Runs `verify` for each torrent on up to `jobs` threads, returning the reports in order
+fn run_parallel(
+    torrents: &[DatabaseRow],
+    jobs: usize,
+    verify: fn(&DatabaseRow) -> Report,
+) -> Vec<Report> {
+    let next_torrent = AtomicUsize::new(0);
+    let reports: Mutex<Vec<Option<Report>>> = Mutex::new(torrents.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, torrents.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next_torrent.fetch_add(1, Ordering::Relaxed);
+                let Some(torrent) = torrents.get(index) else {
+                    break;
+                };
+                let report = verify(torrent);
+                reports.lock().unwrap()[index] = Some(report);
+            });
+        }
+    });
+
+    reports
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Prints the result for a single torrent
+fn print_report(prefix: &str, report: &Report, config: &Config) {
+    let mut details = Vec::new();
+    if !report.missing_files.is_empty() {
+        details.push(format!("{} files missing", report.missing_files.len()));
+    }
+    if !report.mismatched_files.is_empty() {
+        details.push(format!(
+            "{} files with the wrong size",
+            report.mismatched_files.len()
+        ));
+    }
+    if !report.failed_pieces.is_empty() {
+        details.push(format!(
+            "{}/{} pieces failed",
+            report.failed_pieces.len(),
+            report.num_pieces
+        ));
+    }
+    if report.unavailable_pieces > 0 {
+        details.push(format!(
+            "{}/{} pieces unavailable",
+            report.unavailable_pieces, report.num_pieces
+        ));
+    }
+
+    match details.is_empty() {
+        true => println!(
+            "{prefix}: {} {}: {}",
+            report.torrent_id, report.name, report.status
+        ),
+        false => println!(
+            "{prefix}: {} {}: {} ({})",
+            report.torrent_id,
+            report.name,
+            report.status,
+            details.join(", ")
+        ),
+    }
+
+    if config.verbose {
+        for file in &report.missing_files {
+            println!("{}: missing file {:?}", report.torrent_id, file.path);
+        }
+        for file in &report.mismatched_files {
+            println!(
+                "{}: size mismatch {:?}, expected {} bytes",
+                report.torrent_id, file.path, file.length
+            );
+        }
+        if !report.failed_pieces.is_empty() {
+            println!(
+                "{}: failed pieces {:?}",
+                report.torrent_id, report.failed_pieces
+            );
+        }
+    }
+}
+
+/// Checks which files exist with exactly their expected length
+///
+/// A file longer than expected is usually a different file with the same name, so it is reported
+/// just like a truncated one. Returns the missing and mismatched files.
+fn check_files(files: &[ContentFile]) -> (Vec<ContentFile>, Vec<ContentFile>) {
+    let mut missing_files = Vec::new();
+    let mut mismatched_files = Vec::new();
+    for file in files.iter().filter(|file| !file.padding) {
+        match file.path.metadata() {
+            Ok(metadata) if metadata.is_file() && metadata.len() == file.length => {}
+            Ok(metadata) if metadata.is_file() => mismatched_files.push(file.clone()),
+            _ => missing_files.push(file.clone()),
+        }
+    }
+
+    (missing_files, mismatched_files)
+}
+
+/// Checks the existence and length of the files of a single torrent
+fn check_torrent(torrent: &DatabaseRow) -> Report {
+    let content = match TorrentContent::resolve(torrent) {
+        Ok(content) => content,
+        Err(err) => return Report::unsupported(torrent, err.to_string()),
+    };
+
+    let (missing_files, mismatched_files) = check_files(&content.files);
+    let num_content_files = content.files.iter().filter(|file| !file.padding).count();
+    let status = if missing_files.len() == num_content_files {
+        Status::Missing
+    } else if !missing_files.is_empty() || !mismatched_files.is_empty() {
+        Status::Partial
+    } else {
+        Status::Ok
+    };
+
+    Report {
+        torrent_id: torrent.torrent_id.clone(),
+        name: content.name,
+        status,
+        num_pieces: 0,
+        failed_pieces: vec![],
+        unavailable_pieces: 0,
+        missing_files,
+        mismatched_files,
+    }
+}
+
+/// Hashes the content of a single torrent and compares it to the v1 piece hashes
+fn verify_torrent(torrent: &DatabaseRow) -> Report {
+    let content = match TorrentContent::resolve(torrent) {
+        Ok(content) => content,
+        Err(err) => return Report::unsupported(torrent, err.to_string()),
+    };
+    let Some(piece_hashes) = &content.metadata.info.pieces else {
+        return Report::unsupported(torrent, String::from("v2-only torrent"));
+    };
+
+    let piece_length = content.metadata.info.piece_length;
+    if piece_length == 0 || !piece_length.is_power_of_two() || piece_length > MAX_PIECE_LENGTH {
+        return Report::unsupported(torrent, format!("invalid piece length {piece_length}"));
+    }
+    let piece_length = piece_length as usize;
+    let num_pieces = piece_hashes.len() / 20;
+    let (missing_files, mismatched_files) = check_files(&content.files);
+
+    let mut failed_pieces = Vec::new();
+    let mut unavailable_pieces = 0;
+    let mut hasher = PieceHasher {
+        piece_length,
+        buffer: Vec::with_capacity(piece_length),
+        filled: 0,
+        available: true,
+        piece: 0,
+    };
+    let mut finish_piece = |hasher: &mut PieceHasher| {
+        let expected = piece_hashes.get(hasher.piece * 20..hasher.piece * 20 + 20);
+        match hasher.available {
+            true if Sha1::digest(&hasher.buffer).as_slice() == expected.unwrap_or_default() => {}
+            true => failed_pieces.push(hasher.piece),
+            false => unavailable_pieces += 1,
+        }
+        hasher.next_piece();
+    };
+
+    for file in &content.files {
+        let mut reader = match file.padding {
+            true => None,
+            false => File::open(&file.path).ok(),
+        };
+        let mut remaining = file.length as usize;
+
+        while remaining > 0 {
+            let chunk = remaining.min(piece_length - hasher.filled);
+            if file.padding {
+                hasher.push_zeros(chunk);
+            } else if let Some(file_reader) = &mut reader {
+                if let Err(err) = hasher.push_from(file_reader, chunk) {
+                    if err.kind() != ErrorKind::UnexpectedEof {
+                        eprintln!("Verify: could not read {:?}: {err}", file.path);
+                    }
+                    reader = None;
+                }
+            } else {
+                hasher.skip(chunk);
+            }
+            remaining -= chunk;
+
+            if hasher.filled == piece_length {
+                finish_piece(&mut hasher);
+            }
+        }
+    }
+    if hasher.filled > 0 {
+        finish_piece(&mut hasher);
+    }
+
+    let num_content_files = content.files.iter().filter(|file| !file.padding).count();
+    let status = if missing_files.len() == num_content_files {
+        Status::Missing
+    } else if !failed_pieces.is_empty()
+        && !only_unfinished(&failed_pieces, &content.resume_data.pieces)
+    {
+        Status::Corrupt
+    } else if !failed_pieces.is_empty() || unavailable_pieces > 0 || !mismatched_files.is_empty() {
+        Status::Partial
+    } else {
+        Status::Ok
+    };
+
+    Report {
+        torrent_id: torrent.torrent_id.clone(),
+        name: content.name,
+        status,
+        num_pieces,
+        failed_pieces,
+        unavailable_pieces,
+        missing_files,
+        mismatched_files,
+    }
+}
+
+/// Returns true if all failed pieces were never downloaded according to libtorrent
+///
+/// Those pieces are expected to fail for a torrent that is still downloading.
+fn only_unfinished(failed_pieces: &[usize], have_pieces: &[u8]) -> bool {
+    failed_pieces
+        .iter()
+        .all(|piece| have_pieces.get(*piece).is_some_and(|have| have & 1 == 0))
+}
+
+/// Collects the data of one piece at a time across file boundaries
+struct PieceHasher {
+    piece_length: usize,
+    buffer: Vec<u8>,
+    filled: usize,
+    available: bool,
+    piece: usize,
+}
+
+impl PieceHasher {
+    /// Appends `length` bytes read from a file to the current piece
+    ///
+    /// If the piece is already unavailable, the bytes are skipped instead of read, so the next
+    /// piece still starts at the right offset in the file.
+    fn push_from(&mut self, reader: &mut File, length: usize) -> std::io::Result<()> {
+        if !self.available {
+            self.filled += length;
+            reader.seek(SeekFrom::Current(length as i64))?;
+            return Ok(());
+        }
+
+        let start = self.buffer.len();
+        self.buffer.resize(start + length, 0);
+        let result = reader.read_exact(&mut self.buffer[start..]);
+        self.filled += length;
+        if result.is_err() {
+            self.available = false;
+        }
+
+        result
+    }
+
+    /// Appends `length` zero bytes of a padding file to the current piece
+    fn push_zeros(&mut self, length: usize) {
+        if self.available {
+            self.buffer.resize(self.buffer.len() + length, 0);
+        }
+        self.filled += length;
+    }
+
+    /// Marks `length` bytes of the current piece as unavailable
+    fn skip(&mut self, length: usize) {
+        self.available = false;
+        self.filled += length;
+    }
+
+    /// Resets the state for the next piece
+    fn next_piece(&mut self) {
+        debug_assert!(self.filled <= self.piece_length);
+        self.buffer.clear();
+        self.filled = 0;
+        self.available = true;
+        self.piece += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::fastresume::Fastresume;
+    use crate::test_util::{self, PIECE_LENGTH};
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Writes the content of a torrent with two files and four pieces, the second piece spans both
+    /// files
+    fn content() -> (TempDir, DatabaseRow) {
+        let dir = tempfile::tempdir().unwrap();
+        let first = test_util::data(20000, 1);
+        let second = test_util::data(30000, 2);
+        let files = [("E01.mkv", first.as_slice()), ("Subs/E01.srt", &second)];
+        test_util::write_content(dir.path(), &files);
+
+        let torrent = test_util::content_torrent(dir.path(), &files);
+        (dir, torrent)
+    }
+
+    /// Changes the first byte of the third piece, which is in the second file
+    fn corrupt_third_piece(dir: &TempDir) {
+        let path = dir.path().join("Content/Subs/E01.srt");
+        let mut data = fs::read(&path).unwrap();
+        data[PIECE_LENGTH * 2 - 20000] ^= 0xff;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn complete_content_is_ok() {
+        let (_dir, torrent) = content();
+
+        let report = verify_torrent(&torrent);
+
+        assert_eq!(report.status, Status::Ok);
+        assert_eq!(report.num_pieces, 4);
+        assert!(report.failed_pieces.is_empty());
+        assert_eq!(report.name, "Content");
+    }
+
+    #[test]
+    fn missing_content_is_missing() {
+        let (dir, torrent) = content();
+        fs::remove_dir_all(dir.path().join("Content")).unwrap();
+
+        let report = verify_torrent(&torrent);
+
+        assert_eq!(report.status, Status::Missing);
+        assert_eq!(report.missing_files.len(), 2);
+        assert_eq!(report.unavailable_pieces, 4);
+    }
+
+    #[test]
+    fn changed_content_is_corrupt() {
+        let (dir, torrent) = content();
+        corrupt_third_piece(&dir);
+
+        let report = verify_torrent(&torrent);
+
+        assert_eq!(report.status, Status::Corrupt);
+        assert_eq!(report.failed_pieces, [2]);
+    }
+
+    #[test]
+    fn truncated_content_is_partial() {
+        let (dir, torrent) = content();
+        let path = dir.path().join("Content/Subs/E01.srt");
+        fs::write(&path, &fs::read(&path).unwrap()[..20000]).unwrap();
+
+        let report = verify_torrent(&torrent);
+
+        assert_eq!(report.status, Status::Partial);
+        assert_eq!(report.mismatched_files.len(), 1);
+        assert!(report.failed_pieces.is_empty());
+        assert_eq!(report.unavailable_pieces, 2);
+    }
+
+    #[test]
+    fn missing_first_file_keeps_later_pieces_aligned() {
+        let (dir, torrent) = content();
+        fs::remove_file(dir.path().join("Content/E01.mkv")).unwrap();
+
+        let report = verify_torrent(&torrent);
+
+        // The first two pieces need the missing file, the last two only need the second one
+        assert_eq!(report.status, Status::Partial);
+        assert_eq!(report.unavailable_pieces, 2);
+        assert!(report.failed_pieces.is_empty());
+        assert_eq!(report.missing_files.len(), 1);
+    }
+
+    #[test]
+    fn unfinished_pieces_are_partial() {
+        let (dir, mut torrent) = content();
+        corrupt_third_piece(&dir);
+
+        // libtorrent has not downloaded that piece yet
+        let mut resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data).unwrap();
+        resume_data.pieces = vec![1, 1, 0, 1];
+        torrent.libtorrent_resume_data = serde_bencode::to_bytes(&resume_data).unwrap();
+
+        let report = verify_torrent(&torrent);
+
+        assert_eq!(report.status, Status::Partial);
+        assert_eq!(report.failed_pieces, [2]);
+    }
+
+    #[test]
+    fn invalid_piece_lengths_are_skipped() {
+        use serde_bencode::value::Value;
+
+        let (_dir, mut torrent) = content();
+        let original = torrent.metadata.take().unwrap();
+
+        for piece_length in [0, 3 * 16384, 1 << 40] {
+            let Ok(Value::Dict(mut metadata)) = serde_bencode::from_bytes(&original) else {
+                panic!("metadata is not a dictionary");
+            };
+            let Some(Value::Dict(info)) = metadata.get_mut(b"info".as_slice()) else {
+                panic!("metadata has no info dictionary");
+            };
+            info.insert(b"piece length".to_vec(), Value::Int(piece_length));
+            torrent.metadata = Some(serde_bencode::to_bytes(&Value::Dict(metadata)).unwrap());
+
+            let report = verify_torrent(&torrent);
+
+            assert_eq!(
+                report.status,
+                Status::Unsupported(format!("invalid piece length {piece_length}"))
+            );
+        }
+    }
+
+    #[test]
+    fn torrents_without_metadata_are_skipped() {
+        let torrent = test_util::torrent(test_util::MAGNET_ID, test_util::MAGNET, None);
+
+        let report = verify_torrent(&torrent);
+
+        assert!(matches!(report.status, Status::Unsupported(_)));
+    }
+
+    #[test]
+    fn check_reports_missing_and_truncated_files() {
+        let (dir, torrent) = content();
+        assert_eq!(check_torrent(&torrent).status, Status::Ok);
+
+        let path = dir.path().join("Content/Subs/E01.srt");
+        fs::write(&path, b"short").unwrap();
+        let report = check_torrent(&torrent);
+        assert_eq!(report.status, Status::Partial);
+        assert_eq!(report.mismatched_files[0].path, path);
+        assert!(report.missing_files.is_empty());
+
+        fs::remove_file(&path).unwrap();
+        let report = check_torrent(&torrent);
+        assert_eq!(report.status, Status::Partial);
+        assert_eq!(report.missing_files[0].path, path);
+
+        fs::remove_dir_all(dir.path().join("Content")).unwrap();
+        assert_eq!(check_torrent(&torrent).status, Status::Missing);
+    }
+
+    #[test]
+    fn longer_files_are_a_size_mismatch() {
+        let (dir, torrent) = content();
+        let path = dir.path().join("Content/E01.mkv");
+        let mut data = fs::read(&path).unwrap();
+        data.extend_from_slice(b"appended");
+        fs::write(&path, data).unwrap();
+
+        let report = check_torrent(&torrent);
+        assert_eq!(report.status, Status::Partial);
+        assert_eq!(report.mismatched_files[0].path, path);
+
+        let report = verify_torrent(&torrent);
+        assert_eq!(report.status, Status::Partial);
+        assert_eq!(report.mismatched_files.len(), 1);
+    }
+
+    #[test]
+    fn check_ignores_padding_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, length, padding| ContentFile {
+            path: dir.path().join(name),
+            length,
+            padding,
+        };
+        fs::write(dir.path().join("E01.mkv"), [0; 100]).unwrap();
+
+        let (missing_files, mismatched_files) =
+            check_files(&[file("E01.mkv", 100, false), file(".pad/0", 50, true)]);
+
+        assert!(missing_files.is_empty());
+        assert!(mismatched_files.is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 //! # qbfrt (qBittorrent fastresume tool)
 //! Command line tool for working with qBittorrent's fastresume data. Supports the
-//! experimental SQLite database and (soon) traditional .fastresume files.
+//! experimental SQLite database and traditional .fastresume files.
 //!
 //! ## Features
 //! With this tool you can:
@@ -9,18 +9,53 @@
 //!       qBittorrent or recheck all of the torrent data
 //!     - Migrate from qBittorrent on Windows to Linux without having to recheck the
 //!       torrent data
+//!     - Convert every path between Windows and Linux in one run with a drive mapping table
+//!     - Translate paths between a host and WSL or a Docker container
+//!     - Normalize paths to NFC/NFD and find or sanitize names Windows does not allow
+//!     - Move the incomplete download directory on its own, or together with the save paths
 //! - Mass update the tracker URLs for torrents in the SQLite database
+//! - Rename the files of many torrents at once by pattern
+//! - Apply many save path and tracker URL replacements in one run from a mapping file
+//! - Preview save path and tracker URL changes as a per-torrent diff with `--dry-run`
+//! - Mass update save paths and tracker URLs in a BT_backup directory of .fastresume files
+//! - List, prune and restore torrents.db backups
+//! - Undo the changes of an earlier run from its undo journal
+//! - Migrate the SQLite database between the schema versions of different qB releases
 //! - Dump the SQLite database to .fastresume files (1:1 re-creation of what qB would
 //!   generate in the BT_Backup directory)
+//! - Load .fastresume files from a BT_backup directory into the SQLite database
+//! - List torrents with their decoded fastresume data as a table, JSON or CSV
+//! - Verify torrent content on disk against the piece hashes in the torrent metadata, or quickly
+//!   check that all torrent files exist with the expected size
 //!
 //! **More functionality to come!**
 //!
+//! ## Library usage
+//! The data structures in `common` can be used on their own to decode qB's fastresume data
+//! (`common::fastresume`), torrents.db rows (`common::database`) and torrent metadata
+//! (`common::metadata`), e.g. to list the files of a torrent without an external tool.
+//!
 //! ## Note
+//! Every task can be restricted to a subset of the torrents with filters such as `--category`
+//! or `--tracker-host`, see `common::filter`.
+//!
 //! You can chain multiple tasks together, such as changing a tracker and a save path at
-//! the same time. `--db-to-fastresume` runs after other commands.
+//! the same time. `--fastresume-to-db` runs before other commands and `--db-to-fastresume`
+//! runs after other commands. `--check-files` and `--verify` run last.
+//!
+//! All modifications of one run happen in a single transaction, which is rolled back if any task
+//! fails. See `storage::Storage`.
+//!
+//! torrents.db databases from older qB releases are supported, reads and writes adapt to the
+//! columns of the detected schema version, and `--migrate-schema` moves a database between
+//! versions. See `db::schema`.
 
 #![warn(missing_docs)]
 
 pub mod common;
 pub mod config;
 pub mod db;
+pub mod storage;
+
+#[cfg(test)]
+mod test_util;
@@ -1,47 +1,273 @@
 use figlet_rs::FIGfont;
 use qbfrt::config::Config;
-use qbfrt::db::{dump_db, save_path, tracker_url, DB};
+use qbfrt::db::{
+    backups, dump_db, instance, list, load_db, mapping, migrate_os, normalize, rename_files,
+    save_path, schema, tracker_url, undo, verify, DB,
+};
+use qbfrt::storage::bt_backup::BtBackupStorage;
+use qbfrt::storage::dry_run::DryRunStorage;
+use qbfrt::storage::journal::JournalStorage;
+use qbfrt::storage::sqlite::SqliteStorage;
+use qbfrt::storage::Storage;
 use std::error::Error;
 use std::process;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    println!("{}", FIGfont::standard().unwrap().convert("qbfrt").unwrap());
-
     let config = Config::build().unwrap_or_else(|err| {
         println!("Problem parsing arguments: {err}");
         process::exit(1);
     });
 
-    DB::backup(&config).unwrap_or_else(|err| {
+    // The list output is meant to be readable by other programs, so nothing else is printed
+    if config.list {
+        run_list(&config);
+        return Ok(());
+    }
+
+    println!("{}", FIGfont::standard().unwrap().convert("qbfrt").unwrap());
+
+    if config.list_backups || config.prune_backups || config.restore_backup.is_some() {
+        run_backup_command(&config);
+        return Ok(());
+    }
+
+    if let Some(version) = config.migrate_schema {
+        schema::migrate_schema(version, &config).unwrap_or_else(|err| {
+            println!("Could not migrate schema: {err}");
+            process::exit(1);
+        });
+        return Ok(());
+    }
+
+    // Checking files and Windows names or dumping fastresume files only reads the storage, so it is
+    // opened read-only like for the list
+    if !config.modifies() {
+        let mut storage = open_read_only(&config);
+        if let Some(normalization) = &config.normalization {
+            normalize::normalize_paths(storage.as_mut(), normalization, &config).unwrap_or_else(
+                |err| {
+                    println!("Could not check paths: {err}");
+                    process::exit(1);
+                },
+            );
+        }
+        run_dump(storage.as_ref(), &config);
+        run_checks(storage.as_ref(), &config);
+        return Ok(());
+    }
+
+    let storage = open_storage(&config);
+
+    // A dry run keeps every change in memory, so chained operations still see each other's changes
+    if config.dry_run {
+        println!("Dry run: nothing will be written");
+        let mut storage = DryRunStorage::new(storage);
+        run_operations(&mut storage, &config);
+        storage.print_diff().unwrap_or_else(|err| {
+            println!("Could not show changes: {err}");
+            process::exit(1);
+        });
+    } else {
+        let mut storage = JournalStorage::new(storage, &config);
+        run_operations(&mut storage, &config);
+    }
+
+    Ok(())
+}
+
+/// Backs up and opens the configured storage backend
+///
+/// In a dry run nothing is written, so no backup is made and the database is opened read-only.
+/// Otherwise qB must not be running, since it would overwrite the changes.
+fn open_storage(config: &Config) -> Box<dyn Storage> {
+    if config.dry_run {
+        return open_read_only(config);
+    }
+
+    instance::check_not_running(config).unwrap_or_else(|err| {
+        println!("{err}");
+        process::exit(1);
+    });
+
+    if config.use_fastresume {
+        BtBackupStorage::backup(config).unwrap_or_else(|err| {
+            println!("Could not backup BT_backup directory: {err}");
+            process::exit(1);
+        });
+
+        return Box::new(BtBackupStorage::open(config).unwrap_or_else(|err| {
+            println!("Could not open BT_backup directory: {err}");
+            process::exit(1);
+        }));
+    }
+
+    DB::backup(config).unwrap_or_else(|err| {
         println!("Could not backup database: {err}");
         process::exit(1);
     });
 
-    let db = DB::connect(&config).unwrap_or_else(|err| {
+    let db = DB::connect(config).unwrap_or_else(|err| {
         println!("Could not connect to database: {err}");
         process::exit(1);
     });
+    Box::new(SqliteStorage::new(db).unwrap_or_else(|err| {
+        println!("Could not read database schema: {err}");
+        process::exit(1);
+    }))
+}
 
-    if let Some(save_path) = &config.save_path {
-        save_path::change_save_path(&db, save_path, &config).unwrap_or_else(|err| {
-            println!("Could not update save paths: {err}");
+/// Runs the configured operations in order
+///
+/// All modifications run in a single transaction, which is only committed if every one of them
+/// succeeds. The dump and the checks are read-only and run after the commit.
+fn run_operations(storage: &mut dyn Storage, config: &Config) {
+    storage.begin().unwrap_or_else(|err| {
+        println!("Could not start transaction: {err}");
+        process::exit(1);
+    });
+
+    if let Err(err) = modify(storage, config) {
+        println!("{err}");
+        storage.rollback().unwrap_or_else(|err| {
+            println!("Could not roll back changes: {err}");
             process::exit(1);
         });
+        println!("All changes were rolled back");
+        process::exit(1);
     }
 
-    if let Some(tracker_url) = &config.tracker_url {
-        tracker_url::change_tracker_url(&db, tracker_url, &config).unwrap_or_else(|err| {
-            println!("Could not update tracker URLs: {err}");
-            process::exit(1);
-        })
-    }
+    storage.commit().unwrap_or_else(|err| {
+        println!("Could not commit changes: {err}");
+        process::exit(1);
+    });
 
+    run_dump(storage, config);
+    run_checks(storage, config);
+}
+
+/// Dumps the storage to fastresume files in the output directory
+fn run_dump(storage: &dyn Storage, config: &Config) {
     if config.db_to_fastresume {
-        dump_db::to_fastresume(&db, &config).unwrap_or_else(|err| {
+        dump_db::to_fastresume(storage, config).unwrap_or_else(|err| {
             println!("Could not dump database to fastresume files: {err}");
             process::exit(1);
         });
     }
+}
+
+/// Runs the read-only checks
+fn run_checks(storage: &dyn Storage, config: &Config) {
+    if config.check_files {
+        verify::check_torrents(storage, config).unwrap_or_else(|err| {
+            println!("Could not check torrent files: {err}");
+            process::exit(1);
+        });
+    }
+
+    if config.verify {
+        verify::verify_torrents(storage, config).unwrap_or_else(|err| {
+            println!("Could not verify torrents: {err}");
+            process::exit(1);
+        });
+    }
+}
+
+/// Runs the operations that modify the storage, stopping at the first error
+fn modify(storage: &mut dyn Storage, config: &Config) -> Result<(), String> {
+    if let Some(run_id) = &config.undo {
+        undo::undo_run(storage, run_id, config)
+            .map_err(|err| format!("Could not undo run {run_id}: {err}"))?;
+    }
+
+    if config.fastresume_to_db {
+        load_db::from_fastresume(storage, config)
+            .map_err(|err| format!("Could not load fastresume files into database: {err}"))?;
+    }
+
+    if let Some(save_path) = &config.save_path {
+        save_path::change_save_path(storage, save_path, config)
+            .map_err(|err| format!("Could not update save paths: {err}"))?;
+    }
+
+    if let Some(os_migration) = &config.os_migration {
+        migrate_os::migrate_os(storage, os_migration, config)
+            .map_err(|err| format!("Could not migrate paths: {err}"))?;
+    }
+
+    if let Some(tracker_url) = &config.tracker_url {
+        tracker_url::change_tracker_url(storage, tracker_url, config)
+            .map_err(|err| format!("Could not update tracker URLs: {err}"))?;
+    }
+
+    if let Some(mapping) = &config.mapping {
+        mapping::apply_mapping(storage, mapping, config)
+            .map_err(|err| format!("Could not apply mapping file: {err}"))?;
+    }
+
+    if let Some(file_rename) = &config.file_rename {
+        rename_files::rename_files(storage, file_rename, config)
+            .map_err(|err| format!("Could not rename files: {err}"))?;
+    }
+
+    if let Some(normalization) = &config.normalization {
+        normalize::normalize_paths(storage, normalization, config)
+            .map_err(|err| format!("Could not normalize paths: {err}"))?;
+    }
 
     Ok(())
 }
+
+/// Lists, prunes or restores torrents.db backups
+fn run_backup_command(config: &Config) {
+    if config.list_backups {
+        backups::list_backups(config).unwrap_or_else(|err| {
+            println!("Could not list backups: {err}");
+            process::exit(1);
+        });
+    }
+
+    if config.prune_backups {
+        backups::prune_backups(config).unwrap_or_else(|err| {
+            println!("Could not prune backups: {err}");
+            process::exit(1);
+        });
+    }
+
+    if let Some(backup) = &config.restore_backup {
+        backups::restore_backup(backup, config).unwrap_or_else(|err| {
+            println!("Could not restore backup: {err}");
+            process::exit(1);
+        });
+    }
+}
+
+/// Opens the configured storage backend without backing up or locking anything
+///
+/// Errors go to stderr, so they do not end up in the list output.
+fn open_read_only(config: &Config) -> Box<dyn Storage> {
+    if config.use_fastresume {
+        return Box::new(BtBackupStorage::open(config).unwrap_or_else(|err| {
+            eprintln!("Could not open BT_backup directory: {err}");
+            process::exit(1);
+        }));
+    }
+
+    let db = DB::connect_read_only(config).unwrap_or_else(|err| {
+        eprintln!("Could not connect to database: {err}");
+        process::exit(1);
+    });
+    Box::new(SqliteStorage::new(db).unwrap_or_else(|err| {
+        eprintln!("Could not read database schema: {err}");
+        process::exit(1);
+    }))
+}
+
+/// Lists the torrents without modifying or backing up anything
+fn run_list(config: &Config) {
+    let storage = open_read_only(config);
+
+    list::list_torrents(storage.as_ref(), config).unwrap_or_else(|err| {
+        eprintln!("Could not list torrents: {err}");
+        process::exit(1);
+    });
+}
@@ -0,0 +1,569 @@
+//! BT_backup directory storage backend
+
+use crate::common::database::DatabaseRow;
+use crate::common::fastresume::Fastresume;
+use crate::config::Config;
+use crate::storage::Storage;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Storage backend for the traditional BT_backup directory of .fastresume files
+///
+/// Each torrent is stored as a `<hash>.fastresume` file, with its metadata in a `<hash>.torrent`
+/// file next to it. The queue order is kept in a separate `queue` file.
+///
+/// Within a transaction updated and removed torrents are staged in memory and only written on
+/// commit.
+pub struct BtBackupStorage {
+    dir_path: PathBuf,
+    queue: Vec<String>,
+    // Staged torrents by hash, `None` if the torrent was removed
+    staged: Option<BTreeMap<String, Option<DatabaseRow>>>,
+}
+
+impl BtBackupStorage {
+    /// Opens the BT_backup directory, see `Config::bt_backup_directory`
+    ///
+    /// ## Example
+    /// ```rs
+    /// let storage = BtBackupStorage::open(&config).unwrap_or_else(|err| {
+    ///     println!("Could not open BT_backup directory: {err}");
+    ///     process::exit(1);
+    /// });
+    /// ```
+    pub fn open(config: &Config) -> Result<BtBackupStorage, Box<dyn Error>> {
+        let dir_path = config.bt_backup_directory();
+        if !dir_path.is_dir() {
+            return Err(format!("{:?} is not a directory", dir_path.display()).into());
+        }
+
+        // qB keeps the queue order in a separate file, one torrent hash per line
+        let queue = fs::read_to_string(dir_path.join("queue"))
+            .unwrap_or_default()
+            .lines()
+            .map(|hash| hash.trim().to_string())
+            .collect();
+
+        Ok(BtBackupStorage {
+            dir_path,
+            queue,
+            staged: None,
+        })
+    }
+
+    /// Creates a timestamped copy of the BT_backup directory before modification
+    ///
+    /// The copy is saved next to the BT_backup directory, or in config.backup_directory if set.
+    ///
+    /// ## Examples
+    /// ```rs
+    /// BtBackupStorage::backup(&config).unwrap_or_else(|err| {
+    ///     println!("Could not backup BT_backup directory: {err}");
+    ///     process::exit(1);
+    /// });
+    ///```
+    /// ## Disabling
+    /// Automatic backups can be disabled by passing `--disable-backup` or `-d`
+    /// ```bash
+    /// qbfrt --use-fastresume --disable-backup
+    /// ```
+    ///
+    /// ## Verbose output
+    /// If verbose output is enabled with `--verbose` or `-v` it will then output the path to the backup.
+    /// In the case where backup is disabled it will output that instead.
+    pub fn backup(config: &Config) -> Result<(), Box<dyn Error>> {
+        if !config.disable_backup {
+            println!("Creating BT_backup backup...");
+
+            let bt_backup_dir = config.bt_backup_directory();
+            let datetime = chrono::offset::Local::now().format("%Y%m%d%H%M%S");
+            let mut backup_name = bt_backup_dir.file_name().unwrap_or_default().to_os_string();
+            backup_name.push(format!("-{datetime}.bak"));
+            let backup_dir = match &config.backup_directory {
+                Some(dir) => Path::new(dir).join(backup_name),
+                None => bt_backup_dir.with_file_name(backup_name),
+            };
+
+            fs::create_dir_all(&backup_dir)?;
+            for entry in fs::read_dir(&bt_backup_dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    fs::copy(entry.path(), backup_dir.join(entry.file_name()))?;
+                }
+            }
+
+            if config.verbose {
+                println!("Backup saved to: {:?}", backup_dir.display());
+            }
+        } else if config.verbose {
+            println!("BT_backup backup disabled");
+        }
+
+        Ok(())
+    }
+
+    /// Reads a .fastresume/.torrent file pair into a database row
+    fn read(&self, fastresume_file: &Path) -> Result<DatabaseRow, Box<dyn Error>> {
+        let torrent_id = fastresume_file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let resume_data = serde_bencode::from_bytes::<Fastresume>(&fs::read(fastresume_file)?)?;
+
+        // Torrents added by magnet link have no .torrent file until the metadata is downloaded
+        let torrent_file = fastresume_file.with_extension("torrent");
+        let metadata = match torrent_file.exists() {
+            true => Some(fs::read(torrent_file)?),
+            false => None,
+        };
+
+        let queue_position = self
+            .queue
+            .iter()
+            .position(|hash| *hash == torrent_id)
+            .map_or(-1, |position| position as i64);
+
+        DatabaseRow::from_fastresume(torrent_id, resume_data, metadata, queue_position)
+    }
+
+    /// Writes the .fastresume file for a torrent to a temporary file
+    ///
+    /// Returns the temporary file and the .fastresume file it should replace. A missing .torrent
+    /// file is written directly, since it can not replace anything.
+    fn write_tmp(&self, torrent: &DatabaseRow) -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
+        let fastresume_file = self
+            .dir_path
+            .join(format!("{}.fastresume", torrent.torrent_id));
+        let torrent_file = fastresume_file.with_extension("torrent");
+        if let Some(metadata) = torrent.metadata.as_ref().filter(|_| !torrent_file.exists()) {
+            fs::write(torrent_file, metadata)?;
+        }
+
+        let mut resume_data = torrent.to_fastresume()?;
+        if fastresume_file.exists() {
+            let bytes = fs::read(&fastresume_file)?;
+            let original = serde_bencode::from_bytes(&bytes)?;
+            let unchanged = DatabaseRow::from_fastresume(
+                torrent.torrent_id.clone(),
+                serde_bencode::from_bytes(&bytes)?,
+                None,
+                -1,
+            )?
+            .to_fastresume()?;
+            keep_present_keys(&mut resume_data, original, unchanged);
+        }
+
+        let tmp_file = fastresume_file.with_extension("fastresume.tmp");
+        fs::write(&tmp_file, serde_bencode::to_bytes(&resume_data)?)?;
+
+        Ok((tmp_file, fastresume_file))
+    }
+
+    /// Deletes the .fastresume and .torrent files for a torrent, if they exist
+    fn delete_files(&self, torrent_id: &str) -> Result<(), Box<dyn Error>> {
+        for extension in ["fastresume", "torrent"] {
+            let file = self.dir_path.join(format!("{torrent_id}.{extension}"));
+            if file.exists() {
+                fs::remove_file(file)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Limits the `qBt-*` keys of a rewritten .fastresume file to those that are needed
+///
+/// `DatabaseRow::to_fastresume` sets every `qBt-*` key, but qB omits most of them until they are
+/// changed from their default. A key the original file did not have is only added if an edit
+/// changed its value, compared to `unchanged`, the original file passed through
+/// `DatabaseRow::to_fastresume`. Keys without a database column to come from, such as the download
+/// path of a torrent in "AutoTMM" mode, keep their original value.
+fn keep_present_keys(resume_data: &mut Fastresume, original: Fastresume, unchanged: Fastresume) {
+    fn keep<T: PartialEq>(value: &mut Option<T>, original: Option<T>, unchanged: Option<T>) {
+        match original {
+            Some(original) => *value = Some(value.take().unwrap_or(original)),
+            None if *value == unchanged => *value = None,
+            None => {}
+        }
+    }
+
+    keep(
+        &mut resume_data.qbt_category,
+        original.qbt_category,
+        unchanged.qbt_category,
+    );
+    keep(
+        &mut resume_data.qbt_content_layout,
+        original.qbt_content_layout,
+        unchanged.qbt_content_layout,
+    );
+    keep(
+        &mut resume_data.qbt_download_path,
+        original.qbt_download_path,
+        unchanged.qbt_download_path,
+    );
+    keep(
+        &mut resume_data.qbt_first_last_piece_priority,
+        original.qbt_first_last_piece_priority,
+        unchanged.qbt_first_last_piece_priority,
+    );
+    keep(
+        &mut resume_data.qbt_inactive_seeding_time_limit,
+        original.qbt_inactive_seeding_time_limit,
+        unchanged.qbt_inactive_seeding_time_limit,
+    );
+    keep(
+        &mut resume_data.qbt_name,
+        original.qbt_name,
+        unchanged.qbt_name,
+    );
+    keep(
+        &mut resume_data.qbt_ratio_limit,
+        original.qbt_ratio_limit,
+        unchanged.qbt_ratio_limit,
+    );
+    keep(
+        &mut resume_data.qbt_save_path,
+        original.qbt_save_path,
+        unchanged.qbt_save_path,
+    );
+    keep(
+        &mut resume_data.qbt_seed_status,
+        original.qbt_seed_status,
+        unchanged.qbt_seed_status,
+    );
+    keep(
+        &mut resume_data.qbt_seeding_time_limit,
+        original.qbt_seeding_time_limit,
+        unchanged.qbt_seeding_time_limit,
+    );
+    keep(
+        &mut resume_data.qbt_share_limit_action,
+        original.qbt_share_limit_action,
+        unchanged.qbt_share_limit_action,
+    );
+    keep(
+        &mut resume_data.qbt_stop_condition,
+        original.qbt_stop_condition,
+        unchanged.qbt_stop_condition,
+    );
+    keep(
+        &mut resume_data.qbt_tags,
+        original.qbt_tags,
+        unchanged.qbt_tags,
+    );
+}
+
+impl Storage for BtBackupStorage {
+    fn torrents(&self) -> Result<Vec<DatabaseRow>, Box<dyn Error>> {
+        let mut fastresume_files = Vec::new();
+        for entry in fs::read_dir(&self.dir_path)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "fastresume") {
+                fastresume_files.push(path);
+            }
+        }
+        fastresume_files.sort();
+
+        let staged = self.staged.clone().unwrap_or_default();
+        let mut torrents = Vec::new();
+        for fastresume_file in &fastresume_files {
+            let torrent_id = fastresume_file
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if staged.contains_key(&torrent_id) {
+                continue;
+            }
+
+            let torrent = self
+                .read(fastresume_file)
+                .map_err(|err| format!("could not read {:?}: {err}", fastresume_file.display()))?;
+            torrents.push(torrent);
+        }
+
+        // Staged torrents replace their .fastresume file, or are new in the current transaction
+        torrents.extend(staged.into_values().flatten());
+        torrents.sort_by(|a, b| a.torrent_id.cmp(&b.torrent_id));
+
+        Ok(torrents)
+    }
+
+    fn torrent(&self, torrent_id: &str) -> Result<Option<DatabaseRow>, Box<dyn Error>> {
+        if let Some(torrent) = self
+            .staged
+            .as_ref()
+            .and_then(|staged| staged.get(torrent_id))
+        {
+            return Ok(torrent.clone());
+        }
+
+        let fastresume_file = self.dir_path.join(format!("{torrent_id}.fastresume"));
+        match fastresume_file.exists() {
+            true => Ok(Some(self.read(&fastresume_file)?)),
+            false => Ok(None),
+        }
+    }
+
+    /// Writes the .fastresume file for a torrent, or stages it within a transaction
+    ///
+    /// The data is written to a temporary file first, which then replaces the original file. This
+    /// way a failed write can not leave a truncated .fastresume file behind. Existing .torrent
+    /// files and the queue are left untouched.
+    fn update(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>> {
+        if let Some(staged) = &mut self.staged {
+            staged.insert(torrent.torrent_id.clone(), Some(torrent.clone()));
+            return Ok(());
+        }
+
+        let (tmp_file, fastresume_file) = self.write_tmp(torrent)?;
+        fs::rename(tmp_file, fastresume_file)?;
+
+        Ok(())
+    }
+
+    /// Writes the .fastresume and .torrent files for a torrent, or stages them within a transaction
+    ///
+    /// An existing .torrent file is kept, since the metadata of a torrent never changes. The
+    /// queue is left untouched.
+    fn insert(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>> {
+        self.update(torrent)
+    }
+
+    /// Deletes the .fastresume and .torrent files for a torrent, or stages it within a transaction
+    ///
+    /// The queue is left untouched, qB drops unknown hashes from it on the next start.
+    fn remove(&mut self, torrent_id: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(staged) = &mut self.staged {
+            staged.insert(torrent_id.to_string(), None);
+            return Ok(());
+        }
+
+        self.delete_files(torrent_id)
+    }
+
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.staged.is_some() {
+            return Err("a transaction is already active".into());
+        }
+        self.staged = Some(BTreeMap::new());
+
+        Ok(())
+    }
+
+    /// Writes all staged .fastresume files and deletes the removed ones
+    ///
+    /// Every file is written to a temporary file before any original is replaced, so a failed
+    /// write leaves all .fastresume files untouched. Only the final renames and deletions can be
+    /// interrupted.
+    fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+        let staged = self.staged.take().ok_or("no transaction is active")?;
+
+        let mut renames = Vec::new();
+        for torrent in staged.values().flatten() {
+            match self.write_tmp(torrent) {
+                Ok(rename) => renames.push(rename),
+                Err(err) => {
+                    for (tmp_file, _) in renames {
+                        fs::remove_file(tmp_file)?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        for (tmp_file, fastresume_file) in renames {
+            fs::rename(tmp_file, fastresume_file)?;
+        }
+
+        for (torrent_id, torrent) in &staged {
+            if torrent.is_none() {
+                self.delete_files(torrent_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), Box<dyn Error>> {
+        self.staged.take().ok_or("no transaction is active")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, MAGNET, MAGNET_ID, SEEDING, SEEDING_ID, TORRENT};
+    use serde_bencode::value::Value;
+
+    #[test]
+    fn round_trip() {
+        let dir = test_util::bt_backup_dir();
+        let mut storage = BtBackupStorage::open(&test_util::bt_backup_config(&dir, &[])).unwrap();
+
+        let torrents = storage.torrents().unwrap();
+        assert_eq!(torrents.len(), 2);
+        assert_eq!(torrents[0].torrent_id, MAGNET_ID);
+        assert_eq!(torrents[0].queue_position, 0);
+        assert_eq!(torrents[1].torrent_id, SEEDING_ID);
+        assert_eq!(torrents[1].queue_position, 1);
+        assert_eq!(torrents[1].metadata.as_deref(), Some(TORRENT));
+
+        let mut torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        torrent.category = Some(String::from("archive"));
+        storage.update(&torrent).unwrap();
+
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.category.as_deref(), Some("archive"));
+        assert_eq!(torrent.tags.as_deref(), Some("hd,keep"));
+        assert_eq!(
+            fs::read(dir.path().join(format!("{SEEDING_ID}.torrent"))).unwrap(),
+            TORRENT
+        );
+    }
+
+    #[test]
+    fn unchanged_update_keeps_files_identical() {
+        let dir = test_util::bt_backup_dir();
+        let mut storage = BtBackupStorage::open(&test_util::bt_backup_config(&dir, &[])).unwrap();
+
+        for torrent in storage.torrents().unwrap() {
+            storage.update(&torrent).unwrap();
+        }
+
+        let read = |torrent_id| fs::read(dir.path().join(format!("{torrent_id}.fastresume")));
+        assert_eq!(read(SEEDING_ID).unwrap(), SEEDING);
+        assert_eq!(read(MAGNET_ID).unwrap(), MAGNET);
+    }
+
+    #[test]
+    fn commit_writes_staged_torrents() {
+        let dir = test_util::bt_backup_dir();
+        let mut storage = BtBackupStorage::open(&test_util::bt_backup_config(&dir, &[])).unwrap();
+
+        storage.begin().unwrap();
+        let mut torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        torrent.category = Some(String::from("archive"));
+        storage.update(&torrent).unwrap();
+
+        // Staged changes are visible, but nothing is written before the commit
+        let torrents = storage.torrents().unwrap();
+        assert_eq!(torrents[1].category.as_deref(), Some("archive"));
+        let read = |torrent_id| fs::read(dir.path().join(format!("{torrent_id}.fastresume")));
+        assert_eq!(read(SEEDING_ID).unwrap(), SEEDING);
+        assert!(storage.begin().is_err());
+
+        storage.commit().unwrap();
+        let storage = BtBackupStorage::open(&test_util::bt_backup_config(&dir, &[])).unwrap();
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.category.as_deref(), Some("archive"));
+        assert!(!dir
+            .path()
+            .join(format!("{SEEDING_ID}.fastresume.tmp"))
+            .exists());
+    }
+
+    #[test]
+    fn rollback_discards_staged_torrents() {
+        let dir = test_util::bt_backup_dir();
+        let mut storage = BtBackupStorage::open(&test_util::bt_backup_config(&dir, &[])).unwrap();
+
+        storage.begin().unwrap();
+        let mut torrent = storage.torrent(MAGNET_ID).unwrap().unwrap();
+        torrent.category = Some(String::from("archive"));
+        storage.update(&torrent).unwrap();
+        storage.rollback().unwrap();
+
+        let torrent = storage.torrent(MAGNET_ID).unwrap().unwrap();
+        assert_eq!(torrent.category, None);
+        assert_eq!(
+            fs::read(dir.path().join(format!("{MAGNET_ID}.fastresume"))).unwrap(),
+            MAGNET
+        );
+        assert!(storage.rollback().is_err());
+    }
+
+    #[test]
+    fn absent_qbt_keys_stay_absent() {
+        let dir = test_util::bt_backup_dir();
+        let fastresume_file = dir.path().join(format!("{SEEDING_ID}.fastresume"));
+        let keys = |file: &Path| match serde_bencode::from_bytes(&fs::read(file).unwrap()) {
+            Ok(Value::Dict(dict)) => dict,
+            _ => panic!("not a dictionary"),
+        };
+        let mut resume_data = keys(&fastresume_file);
+        resume_data.remove(b"qBt-ratioLimit".as_slice());
+        resume_data.remove(b"qBt-stopCondition".as_slice());
+        fs::write(
+            &fastresume_file,
+            serde_bencode::to_bytes(&Value::Dict(resume_data)).unwrap(),
+        )
+        .unwrap();
+
+        let mut storage = BtBackupStorage::open(&test_util::bt_backup_config(&dir, &[])).unwrap();
+        let mut torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        torrent.target_save_path = Some(String::from("/mnt/tv"));
+        storage.update(&torrent).unwrap();
+
+        let resume_data = keys(&fastresume_file);
+        assert!(!resume_data.contains_key(b"qBt-ratioLimit".as_slice()));
+        assert!(!resume_data.contains_key(b"qBt-stopCondition".as_slice()));
+        assert_eq!(
+            resume_data.get(b"qBt-savePath".as_slice()),
+            Some(&Value::Bytes(b"/mnt/tv".to_vec()))
+        );
+    }
+
+    #[test]
+    fn corrupt_fastresume_files_are_an_error() {
+        let dir = test_util::bt_backup_dir();
+        let fastresume_file = dir.path().join(format!("{MAGNET_ID}.fastresume"));
+        fs::write(&fastresume_file, &MAGNET[..MAGNET.len() / 2]).unwrap();
+        let storage = BtBackupStorage::open(&test_util::bt_backup_config(&dir, &[])).unwrap();
+
+        let error = storage.torrents().unwrap_err().to_string();
+
+        assert!(
+            error.starts_with(&format!("could not read {:?}", fastresume_file.display())),
+            "{error}"
+        );
+        assert!(storage.torrent(MAGNET_ID).is_err());
+        assert!(storage.torrent(SEEDING_ID).unwrap().is_some());
+    }
+
+    #[test]
+    fn changed_qbt_keys_are_added() {
+        let dir = test_util::bt_backup_dir();
+        let fastresume_file = dir.path().join(format!("{MAGNET_ID}.fastresume"));
+        let Ok(Value::Dict(mut resume_data)) = serde_bencode::from_bytes(MAGNET) else {
+            panic!("not a dictionary");
+        };
+        resume_data.remove(b"qBt-category".as_slice());
+        resume_data.remove(b"qBt-tags".as_slice());
+        fs::write(
+            &fastresume_file,
+            serde_bencode::to_bytes(&Value::Dict(resume_data)).unwrap(),
+        )
+        .unwrap();
+
+        let mut storage = BtBackupStorage::open(&test_util::bt_backup_config(&dir, &[])).unwrap();
+        let mut torrent = storage.torrent(MAGNET_ID).unwrap().unwrap();
+        torrent.category = Some(String::from("archive"));
+        storage.update(&torrent).unwrap();
+
+        let Ok(Value::Dict(resume_data)) =
+            serde_bencode::from_bytes(&fs::read(&fastresume_file).unwrap())
+        else {
+            panic!("not a dictionary");
+        };
+        assert_eq!(
+            resume_data.get(b"qBt-category".as_slice()),
+            Some(&Value::Bytes(b"archive".to_vec()))
+        );
+        assert!(!resume_data.contains_key(b"qBt-tags".as_slice()));
+    }
+}
@@ -0,0 +1,384 @@
+//! Dry-run storage wrapper
+
+use crate::common::database::DatabaseRow;
+use crate::common::fastresume::Fastresume;
+use crate::storage::Storage;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// Storage wrapper that keeps all updates in memory instead of writing them
+///
+/// Reads return the updated torrents, so chained operations behave exactly as they would in a
+/// real run. Nothing is ever written to the wrapped storage. Once all operations have run,
+/// `DryRunStorage::print_diff` shows what would have changed.
+pub struct DryRunStorage {
+    inner: Box<dyn Storage>,
+    // State of each changed torrent before and after the changes, `None` if it does not exist
+    originals: BTreeMap<String, Option<DatabaseRow>>,
+    updates: BTreeMap<String, Option<DatabaseRow>>,
+}
+
+impl DryRunStorage {
+    /// Wraps a storage backend
+    ///
+    /// ## Example
+    /// ```rs
+    /// let mut storage = DryRunStorage::new(Box::new(SqliteStorage::new(db)?));
+    /// save_path::change_save_path(&mut storage, save_path, &config)?;
+    /// storage.print_diff();
+    /// ```
+    pub fn new(inner: Box<dyn Storage>) -> DryRunStorage {
+        DryRunStorage {
+            inner,
+            originals: BTreeMap::new(),
+            updates: BTreeMap::new(),
+        }
+    }
+
+    /// Prints a per-torrent diff of every field that would change, followed by the totals
+    ///
+    /// Shows the `target_save_path`, `download_path`, the libtorrent `save_path`, the renamed files
+    /// in `mapped_files` and the tracker tiers of each updated torrent.
+    pub fn print_diff(&self) -> Result<(), Box<dyn Error>> {
+        let mut num_torrents_changed = 0;
+        let mut num_save_paths_changed = 0;
+        let mut num_mapped_files_changed = 0;
+        let mut num_trackers_changed = 0;
+
+        for (torrent_id, updated) in &self.updates {
+            let (original, updated) = match (&self.originals[torrent_id], updated) {
+                (Some(original), Some(updated)) => (original, updated),
+                (None, None) => continue,
+                (original, _) => {
+                    println!("Dry run: {torrent_id}");
+                    match original {
+                        Some(_) => println!("- removed"),
+                        None => println!("+ added"),
+                    }
+                    num_torrents_changed += 1;
+                    continue;
+                }
+            };
+            let diff = diff_torrent(original, updated)?;
+            if diff.lines.is_empty() {
+                continue;
+            }
+            num_torrents_changed += 1;
+            num_save_paths_changed += diff.save_path_changed as usize;
+            num_mapped_files_changed += diff.mapped_files_changed as usize;
+            num_trackers_changed += diff.trackers_changed as usize;
+
+            println!("Dry run: {torrent_id}");
+            for line in diff.lines {
+                println!("{line}");
+            }
+        }
+
+        match num_torrents_changed {
+            0 => println!("Dry run: no torrents would be updated"),
+            1 => println!("Dry run: 1 torrent would be updated"),
+            _ => println!(
+                "Dry run: {} torrents would be updated",
+                num_torrents_changed
+            ),
+        }
+        println!(
+            "Dry run: {} save paths, {} file lists and {} tracker lists would change, nothing was \
+             written",
+            num_save_paths_changed, num_mapped_files_changed, num_trackers_changed
+        );
+
+        Ok(())
+    }
+}
+
+/// Before/after lines of an updated torrent, and which kinds of values changed
+#[derive(Default)]
+struct TorrentDiff {
+    lines: Vec<String>,
+    save_path_changed: bool,
+    mapped_files_changed: bool,
+    trackers_changed: bool,
+}
+
+/// Compares the paths, renamed files and trackers of a torrent before and after the changes
+fn diff_torrent(
+    original: &DatabaseRow,
+    updated: &DatabaseRow,
+) -> Result<TorrentDiff, Box<dyn Error>> {
+    let original_resume: Fastresume = serde_bencode::from_bytes(&original.libtorrent_resume_data)?;
+    let updated_resume: Fastresume = serde_bencode::from_bytes(&updated.libtorrent_resume_data)?;
+
+    let mut diff = TorrentDiff::default();
+    let lines = &mut diff.lines;
+    diff_field(
+        lines,
+        "target_save_path",
+        original.target_save_path.as_deref().unwrap_or_default(),
+        updated.target_save_path.as_deref().unwrap_or_default(),
+    );
+    diff_field(
+        lines,
+        "download_path",
+        original.download_path.as_deref().unwrap_or_default(),
+        updated.download_path.as_deref().unwrap_or_default(),
+    );
+    diff_field(
+        lines,
+        "save_path",
+        &String::from_utf8_lossy(&original_resume.save_path),
+        &String::from_utf8_lossy(&updated_resume.save_path),
+    );
+    diff.save_path_changed = !lines.is_empty();
+
+    let original_files = original_resume.mapped_files.unwrap_or_default();
+    let updated_files = updated_resume.mapped_files.unwrap_or_default();
+    if original_files != updated_files {
+        diff.mapped_files_changed = true;
+        for index in 0..original_files.len().max(updated_files.len()) {
+            diff_field(
+                lines,
+                &format!("mapped_files {index}"),
+                original_files
+                    .get(index)
+                    .map(String::as_str)
+                    .unwrap_or_default(),
+                updated_files
+                    .get(index)
+                    .map(String::as_str)
+                    .unwrap_or_default(),
+            );
+        }
+    }
+
+    if original_resume.trackers != updated_resume.trackers {
+        diff.trackers_changed = true;
+        for (tier, trackers) in original_resume.trackers.iter().enumerate() {
+            lines.push(format!("- trackers tier {tier}: {}", trackers.join(", ")));
+        }
+        for (tier, trackers) in updated_resume.trackers.iter().enumerate() {
+            lines.push(format!("+ trackers tier {tier}: {}", trackers.join(", ")));
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Adds a before/after pair of lines if the value changed
+fn diff_field(lines: &mut Vec<String>, name: &str, original: &str, updated: &str) {
+    if original != updated {
+        lines.push(format!("- {name}: {original}"));
+        lines.push(format!("+ {name}: {updated}"));
+    }
+}
+
+impl DryRunStorage {
+    /// Remembers the original state of a torrent before its first change
+    fn record_original(&mut self, torrent_id: &str) -> Result<(), Box<dyn Error>> {
+        if !self.originals.contains_key(torrent_id) {
+            let original = self.inner.torrent(torrent_id)?;
+            self.originals.insert(torrent_id.to_string(), original);
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for DryRunStorage {
+    fn torrents(&self) -> Result<Vec<DatabaseRow>, Box<dyn Error>> {
+        let mut torrents: Vec<DatabaseRow> = self
+            .inner
+            .torrents()?
+            .into_iter()
+            .filter(|torrent| !self.updates.contains_key(&torrent.torrent_id))
+            .collect();
+
+        // Changed torrents replace the originals, removed torrents are left out
+        torrents.extend(self.updates.values().flatten().cloned());
+        torrents.sort_by(|a, b| a.torrent_id.cmp(&b.torrent_id));
+
+        Ok(torrents)
+    }
+
+    fn torrent(&self, torrent_id: &str) -> Result<Option<DatabaseRow>, Box<dyn Error>> {
+        match self.updates.get(torrent_id) {
+            Some(updated) => Ok(updated.clone()),
+            None => self.inner.torrent(torrent_id),
+        }
+    }
+
+    fn update(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>> {
+        self.record_original(&torrent.torrent_id)?;
+        self.updates
+            .insert(torrent.torrent_id.clone(), Some(torrent.clone()));
+
+        Ok(())
+    }
+
+    fn insert(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>> {
+        self.update(torrent)
+    }
+
+    fn remove(&mut self, torrent_id: &str) -> Result<(), Box<dyn Error>> {
+        self.record_original(torrent_id)?;
+        self.updates.insert(torrent_id.to_string(), None);
+
+        Ok(())
+    }
+
+    // Nothing is ever written, so there is no transaction to manage
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::rename_files::{self, FileRename};
+    use crate::db::{save_path, tracker_url};
+    use crate::storage::bt_backup::BtBackupStorage;
+    use crate::test_util::{self, MAGNET_ID, SEEDING, SEEDING_ID, TORRENT};
+    use std::fs;
+
+    #[test]
+    fn updates_are_read_back_but_never_written() {
+        let dir = test_util::bt_backup_dir();
+        let config = test_util::bt_backup_config(
+            &dir,
+            &[
+                "--use-fastresume",
+                "--dry-run",
+                "--old-path",
+                "/data/",
+                "--new-path",
+                "/mnt/",
+                "--use-unix-sep",
+                "--old-tracker",
+                "tracker.example.org",
+                "--new-tracker",
+                "tracker.example.net",
+            ],
+        );
+        let inner = BtBackupStorage::open(&config).unwrap();
+        let mut storage = DryRunStorage::new(Box::new(inner));
+
+        let save_path = config.save_path.as_ref().unwrap();
+        save_path::change_save_path(&mut storage, save_path, &config).unwrap();
+        let tracker_url = config.tracker_url.as_ref().unwrap();
+        tracker_url::change_tracker_url(&mut storage, tracker_url, &config).unwrap();
+
+        // Chained operations see the earlier updates
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        let resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data).unwrap();
+        assert_eq!(resume_data.save_path, b"/mnt/tv");
+        assert_eq!(
+            resume_data.trackers[0],
+            ["http://tracker.example.net:6969/announce"]
+        );
+        assert_eq!(storage.torrents().unwrap().len(), 2);
+        assert_eq!(storage.originals.len(), 2);
+        assert_eq!(
+            storage.originals[SEEDING_ID]
+                .as_ref()
+                .unwrap()
+                .target_save_path
+                .as_deref(),
+            Some("/data/tv")
+        );
+        storage.print_diff().unwrap();
+
+        // The BT_backup directory is left untouched
+        let fastresume = fs::read(dir.path().join(format!("{SEEDING_ID}.fastresume"))).unwrap();
+        assert_eq!(fastresume, SEEDING);
+        let inner = BtBackupStorage::open(&config).unwrap();
+        for torrent_id in [SEEDING_ID, MAGNET_ID] {
+            let torrent = inner.torrent(torrent_id).unwrap().unwrap();
+            assert_eq!(torrent.target_save_path.as_deref(), Some("/data/tv"));
+        }
+    }
+
+    #[test]
+    fn dry_run_can_not_be_combined_with_conversions() {
+        let error = Config::from_args(&["--dry-run", "--db-to-fastresume"]).unwrap_err();
+        assert_eq!(
+            error,
+            "--dry-run can not be combined with --db-to-fastresume or --fastresume-to-db"
+        );
+    }
+
+    /// Returns the diff of the seeding torrent after the changes of a dry run
+    fn seeding_diff(storage: &DryRunStorage) -> TorrentDiff {
+        let original = storage.originals[SEEDING_ID].as_ref().unwrap();
+        let updated = storage.updates[SEEDING_ID].as_ref().unwrap();
+        diff_torrent(original, updated).unwrap()
+    }
+
+    #[test]
+    fn diff_shows_every_tracker_tier() {
+        let config = Config::from_args(&[
+            "--dry-run",
+            "--old-tracker",
+            "backup.example.org",
+            "--new-tracker",
+            "backup.example.net",
+        ])
+        .unwrap();
+        let mut storage = DryRunStorage::new(Box::new(test_util::loaded_storage()));
+
+        let tracker_url = config.tracker_url.as_ref().unwrap();
+        tracker_url::change_tracker_url(&mut storage, tracker_url, &config).unwrap();
+
+        let diff = seeding_diff(&storage);
+        assert!(diff.trackers_changed);
+        assert!(!diff.save_path_changed && !diff.mapped_files_changed);
+        assert_eq!(
+            diff.lines,
+            [
+                "- trackers tier 0: http://tracker.example.org:6969/announce",
+                "- trackers tier 1: udp://backup.example.org:1337/announce",
+                "+ trackers tier 0: http://tracker.example.org:6969/announce",
+                "+ trackers tier 1: udp://backup.example.net:1337/announce",
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_shows_only_renamed_files() {
+        let config = Config::from_args(&["--dry-run"]).unwrap();
+        let mut storage = DryRunStorage::new(Box::new(test_util::loaded_storage()));
+
+        let file_rename = FileRename::new(r"^(.*)\.mkv$", "$1.mp4", "/", true).unwrap();
+        rename_files::rename_files(&mut storage, &file_rename, &config).unwrap();
+
+        let diff = seeding_diff(&storage);
+        assert!(diff.mapped_files_changed);
+        assert!(!diff.save_path_changed && !diff.trackers_changed);
+        assert_eq!(
+            diff.lines,
+            [
+                "- mapped_files 0: ",
+                "+ mapped_files 0: Show.S01/Show.S01E01.mp4"
+            ]
+        );
+    }
+
+    #[test]
+    fn unchanged_torrents_have_no_diff() {
+        let torrent = test_util::torrent(SEEDING_ID, SEEDING, Some(TORRENT));
+        let diff = diff_torrent(&torrent, &torrent.clone()).unwrap();
+
+        assert!(diff.lines.is_empty());
+        assert!(!diff.save_path_changed && !diff.mapped_files_changed && !diff.trackers_changed);
+    }
+}
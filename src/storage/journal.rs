@@ -0,0 +1,323 @@
+//! Undo journal storage wrapper
+
+use crate::common::database::DatabaseRow;
+use crate::config::Config;
+use crate::storage::Storage;
+use serde_bencode::value::Value;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the columns holding the keys of the `libtorrent_resume_data` blob
+const RESUME_DATA_PREFIX: &str = "libtorrent_resume_data.";
+
+/// Columns of a torrent by name, absent if NULL
+///
+/// The `libtorrent_resume_data` blob is split into one column per key, e.g.
+/// `libtorrent_resume_data.save_path`. This way a change to a single key does not conflict with
+/// the statistics qB keeps updating in the same blob.
+pub type Columns = BTreeMap<String, Value>;
+
+/// Changes made to the torrents during a single run
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Journal {
+    /// Run id, the time the run started as `YYYYMMDDHHMMSS`
+    pub run_id: String,
+    /// Storage mode the run modified, either "torrents.db" or "BT_backup"
+    pub storage: String,
+    /// Changed torrents
+    pub torrents: Vec<JournalEntry>,
+}
+
+/// Changes made to a single torrent
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalEntry {
+    /// Torrent hash
+    pub torrent_id: String,
+    /// Columns before the run, `None` if the torrent was added
+    ///
+    /// If the torrent existed before and after the run only the changed columns are kept.
+    pub before: Option<Columns>,
+    /// Columns after the run, `None` if the torrent was removed
+    ///
+    /// If the torrent existed before and after the run only the changed columns are kept.
+    pub after: Option<Columns>,
+}
+
+impl Journal {
+    /// Reads the journal of a run from the journal directory
+    ///
+    /// ## Example
+    /// ```rs
+    /// let journal = Journal::load("20240101120000", &config)?;
+    /// println!("{} torrents were changed", journal.torrents.len());
+    /// ```
+    pub fn load(run_id: &str, config: &Config) -> Result<Journal, Box<dyn Error>> {
+        let journal_file = journal_file(&config.journal_directory(), run_id);
+        let bytes = fs::read(&journal_file)
+            .map_err(|err| format!("could not read {:?}: {err}", journal_file.display()))?;
+
+        Ok(serde_bencode::from_bytes(&bytes)?)
+    }
+}
+
+/// Returns the storage mode recorded in a journal
+pub fn storage_mode(config: &Config) -> &'static str {
+    match config.use_fastresume {
+        true => "BT_backup",
+        false => "torrents.db",
+    }
+}
+
+/// Returns the path of the journal file for a run
+fn journal_file(journal_dir: &Path, run_id: &str) -> PathBuf {
+    journal_dir.join(format!("{run_id}.journal"))
+}
+
+/// Splits a torrent into its columns, see `Columns`
+///
+/// ## Example
+/// ```rs
+/// let columns = journal::columns(&torrent)?;
+/// println!("{:?}", columns.get("libtorrent_resume_data.save_path"));
+/// ```
+pub fn columns(torrent: &DatabaseRow) -> Result<Columns, Box<dyn Error>> {
+    let Value::Dict(row) = serde_bencode::from_bytes(&serde_bencode::to_bytes(torrent)?)? else {
+        return Err("torrent is not a dictionary".into());
+    };
+
+    let mut columns = Columns::new();
+    for (name, value) in row {
+        let name = String::from_utf8(name)?;
+        match (name.as_str(), value) {
+            ("libtorrent_resume_data", Value::Bytes(resume_data)) => {
+                let Value::Dict(resume_data) = serde_bencode::from_bytes(&resume_data)? else {
+                    return Err("libtorrent_resume_data is not a dictionary".into());
+                };
+                for (key, value) in resume_data {
+                    let key = String::from_utf8(key)?;
+                    columns.insert(format!("{RESUME_DATA_PREFIX}{key}"), value);
+                }
+            }
+            (_, value) => {
+                columns.insert(name, value);
+            }
+        }
+    }
+
+    Ok(columns)
+}
+
+/// Applies column values to a torrent, `None` sets a column to NULL or removes a resume data key
+///
+/// Without a torrent to start from, the torrent is built from the given columns alone.
+pub fn apply_columns(
+    torrent: Option<&DatabaseRow>,
+    changes: &BTreeMap<String, Option<Value>>,
+) -> Result<DatabaseRow, Box<dyn Error>> {
+    let mut columns = match torrent {
+        Some(torrent) => columns(torrent)?,
+        None => Columns::new(),
+    };
+    for (name, value) in changes {
+        match value {
+            Some(value) => columns.insert(name.clone(), value.clone()),
+            None => columns.remove(name),
+        };
+    }
+
+    let mut row = BTreeMap::new();
+    let mut resume_data = BTreeMap::new();
+    for (name, value) in columns {
+        match name.strip_prefix(RESUME_DATA_PREFIX) {
+            Some(key) => resume_data.insert(key.as_bytes().to_vec(), value),
+            None => row.insert(name.into_bytes(), value),
+        };
+    }
+    // The row id is assigned by the database, so any value will do for a new torrent
+    row.entry(b"id".to_vec()).or_insert(Value::Int(0));
+    row.insert(
+        b"libtorrent_resume_data".to_vec(),
+        Value::Bytes(serde_bencode::to_bytes(&Value::Dict(
+            resume_data.into_iter().collect(),
+        ))?),
+    );
+
+    Ok(serde_bencode::from_bytes(&serde_bencode::to_bytes(
+        &Value::Dict(row.into_iter().collect()),
+    )?)?)
+}
+
+/// Storage wrapper that records every change in an undo journal
+///
+/// The state of each torrent is remembered before its first change. On commit the changed
+/// columns are written to a `<run id>.journal` file in the journal directory, which
+/// `undo::undo_run` can revert later.
+pub struct JournalStorage {
+    inner: Box<dyn Storage>,
+    run_id: String,
+    storage: &'static str,
+    journal_dir: PathBuf,
+    originals: BTreeMap<String, Option<DatabaseRow>>,
+}
+
+impl JournalStorage {
+    /// Wraps a storage backend, using the current time as the run id
+    ///
+    /// ## Example
+    /// ```rs
+    /// let mut storage = JournalStorage::new(Box::new(SqliteStorage::new(db)?), &config);
+    /// storage.begin()?;
+    /// save_path::change_save_path(&mut storage, save_path, &config)?;
+    /// storage.commit()?;
+    /// ```
+    pub fn new(inner: Box<dyn Storage>, config: &Config) -> JournalStorage {
+        let journal_dir = config.journal_directory();
+
+        // Two runs within the same second get a numbered run id
+        let datetime = chrono::offset::Local::now().format("%Y%m%d%H%M%S");
+        let mut run_id = datetime.to_string();
+        let mut number = 1;
+        while journal_file(&journal_dir, &run_id).exists() {
+            number += 1;
+            run_id = format!("{datetime}-{number}");
+        }
+
+        JournalStorage {
+            inner,
+            run_id,
+            storage: storage_mode(config),
+            journal_dir,
+            originals: BTreeMap::new(),
+        }
+    }
+
+    /// Remembers the original state of a torrent before its first change
+    fn record_original(&mut self, torrent_id: &str) -> Result<(), Box<dyn Error>> {
+        if !self.originals.contains_key(torrent_id) {
+            let original = self.inner.torrent(torrent_id)?;
+            self.originals.insert(torrent_id.to_string(), original);
+        }
+
+        Ok(())
+    }
+
+    /// Builds the journal entries for all torrents changed in the current transaction
+    fn entries(&self) -> Result<Vec<JournalEntry>, Box<dyn Error>> {
+        let mut entries = Vec::new();
+        for (torrent_id, original) in &self.originals {
+            let before = original.as_ref().map(columns).transpose()?;
+            let after = self
+                .inner
+                .torrent(torrent_id)?
+                .as_ref()
+                .map(columns)
+                .transpose()?;
+
+            let (before, after) = match (before, after) {
+                (Some(before), Some(after)) => {
+                    let changed: Vec<String> = before
+                        .keys()
+                        .chain(after.keys())
+                        .filter(|name| before.get(*name) != after.get(*name))
+                        .cloned()
+                        .collect();
+                    if changed.is_empty() {
+                        continue;
+                    }
+                    let keep_changed = |columns: Columns| -> Columns {
+                        columns
+                            .into_iter()
+                            .filter(|(name, _)| changed.contains(name))
+                            .collect()
+                    };
+                    (Some(keep_changed(before)), Some(keep_changed(after)))
+                }
+                (None, None) => continue,
+                (before, after) => (before, after),
+            };
+
+            entries.push(JournalEntry {
+                torrent_id: torrent_id.clone(),
+                before,
+                after,
+            });
+        }
+
+        Ok(entries)
+    }
+}
+
+impl Storage for JournalStorage {
+    fn torrents(&self) -> Result<Vec<DatabaseRow>, Box<dyn Error>> {
+        self.inner.torrents()
+    }
+
+    fn torrent(&self, torrent_id: &str) -> Result<Option<DatabaseRow>, Box<dyn Error>> {
+        self.inner.torrent(torrent_id)
+    }
+
+    fn update(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>> {
+        self.record_original(&torrent.torrent_id)?;
+        self.inner.update(torrent)
+    }
+
+    fn insert(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>> {
+        self.record_original(&torrent.torrent_id)?;
+        self.inner.insert(torrent)
+    }
+
+    fn remove(&mut self, torrent_id: &str) -> Result<(), Box<dyn Error>> {
+        self.record_original(torrent_id)?;
+        self.inner.remove(torrent_id)
+    }
+
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        self.originals.clear();
+        self.inner.begin()
+    }
+
+    /// Writes the journal, then commits the wrapped storage
+    ///
+    /// The journal is removed again if the commit fails, so it never describes changes that were
+    /// not made.
+    fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+        let entries = self.entries()?;
+        if entries.is_empty() {
+            return self.inner.commit();
+        }
+
+        let num_torrents = entries.len();
+        let journal = Journal {
+            run_id: self.run_id.clone(),
+            storage: self.storage.to_string(),
+            torrents: entries,
+        };
+        fs::create_dir_all(&self.journal_dir)?;
+        let journal_file = journal_file(&self.journal_dir, &self.run_id);
+        fs::write(&journal_file, serde_bencode::to_bytes(&journal)?)?;
+
+        if let Err(err) = self.inner.commit() {
+            fs::remove_file(&journal_file)?;
+            return Err(err);
+        }
+
+        match num_torrents {
+            1 => println!("Journal: run {} changed 1 torrent", self.run_id),
+            _ => println!(
+                "Journal: run {} changed {num_torrents} torrents",
+                self.run_id
+            ),
+        }
+        println!("Journal: revert it with --undo {}", self.run_id);
+
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), Box<dyn Error>> {
+        self.originals.clear();
+        self.inner.rollback()
+    }
+}
@@ -0,0 +1,167 @@
+//! qbfrt storage module
+//!
+//! qB can store its fastresume data either in the experimental torrents.db SQLite database or in
+//! the traditional BT_backup directory of .fastresume files. The `Storage` trait abstracts over
+//! both, so every operation works on either storage mode.
+//!
+//! All modifications of one run happen inside a single transaction. Nothing is written until
+//! `Storage::commit` is called, so a failing operation can not leave half of the torrents
+//! migrated.
+//!
+//! Wrappers add behaviour on top of either backend: `dry_run::DryRunStorage` keeps all changes
+//! in memory and `journal::JournalStorage` records them in an undo journal.
+//!
+//! Torrents are exchanged as `DatabaseRow`s regardless of the storage mode. For the BT_backup
+//! directory the `qBt-*` keys of each .fastresume file are mapped to their database columns when
+//! reading and mapped back when writing.
+//!
+//! ## Examples and Usage
+//! ### Opening a storage backend
+//! ```rs
+//! let mut storage = BtBackupStorage::open(&config).unwrap_or_else(|err| {
+//!     println!("Could not open BT_backup directory: {err}");
+//!     process::exit(1);
+//! });
+//! storage.begin()?;
+//! match save_path::change_save_path(&mut storage, save_path, &config) {
+//!     Ok(()) => storage.commit()?,
+//!     Err(_) => storage.rollback()?,
+//! }
+//! ```
+
+use crate::common::database::DatabaseRow;
+use std::error::Error;
+
+pub mod bt_backup;
+pub mod dry_run;
+pub mod journal;
+pub mod sqlite;
+
+/// A qB fastresume data store
+pub trait Storage {
+    /// Returns all torrents in the store
+    ///
+    /// A .fastresume file or database row that can not be decoded is an error, so the transaction
+    /// is rolled back instead of silently leaving the torrent unmigrated.
+    fn torrents(&self) -> Result<Vec<DatabaseRow>, Box<dyn Error>>;
+
+    /// Returns the torrent with the given hash, if it exists
+    fn torrent(&self, torrent_id: &str) -> Result<Option<DatabaseRow>, Box<dyn Error>>;
+
+    /// Writes a modified torrent back to the store
+    ///
+    /// The torrent is matched on its hash, `torrent_id`.
+    fn update(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>>;
+
+    /// Adds a torrent to the store, replacing any torrent with the same hash
+    fn insert(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>>;
+
+    /// Removes the torrent with the given hash from the store, if it exists
+    fn remove(&mut self, torrent_id: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Starts a transaction
+    ///
+    /// Updates made after this are only visible to this storage until `Storage::commit` is
+    /// called.
+    fn begin(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Writes all updates made since `Storage::begin` and ends the transaction
+    fn commit(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Discards all updates made since `Storage::begin` and ends the transaction
+    fn rollback(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::fastresume::Fastresume;
+    use crate::config::Config;
+    use crate::db::{rename_files, save_path, tracker_url};
+    use crate::storage::bt_backup::BtBackupStorage;
+    use crate::test_util::{self, SEEDING_ID};
+    use std::fs;
+
+    /// Rewrites the save path and the trackers, then fails to rename the files of a torrent whose
+    /// metadata is broken
+    fn run_failing_operations(storage: &mut dyn Storage, config: &Config) {
+        storage.begin().unwrap();
+
+        save_path::change_save_path(storage, config.save_path.as_ref().unwrap(), config).unwrap();
+        tracker_url::change_tracker_url(storage, config.tracker_url.as_ref().unwrap(), config)
+            .unwrap();
+        let mut torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.target_save_path.as_deref(), Some("/mnt/tv"));
+        torrent.metadata = Some(b"not bencode".to_vec());
+        storage.update(&torrent).unwrap();
+
+        let file_rename = config.file_rename.as_ref().unwrap();
+        assert!(rename_files::rename_files(storage, file_rename, config).is_err());
+        storage.rollback().unwrap();
+    }
+
+    /// Asserts that neither the save path nor the trackers of the seeding torrent changed
+    fn assert_unchanged(storage: &dyn Storage) {
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        let resume_data: Fastresume =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data).unwrap();
+        assert_eq!(torrent.target_save_path.as_deref(), Some("/data/tv"));
+        assert_eq!(resume_data.save_path, b"/data/tv");
+        assert_eq!(
+            resume_data.trackers[0],
+            ["http://tracker.example.org:6969/announce"]
+        );
+    }
+
+    fn config(dir: &tempfile::TempDir, args: &[&str]) -> Config {
+        let mut all_args = vec![
+            "--old-path",
+            "/data/",
+            "--new-path",
+            "/mnt/",
+            "--use-unix-sep",
+            "--old-tracker",
+            "tracker.example.org",
+            "--new-tracker",
+            "tracker.example.net",
+            "--old-file-name",
+            "\\.mkv$",
+            "--new-file-name",
+            ".mp4",
+        ];
+        all_args.extend(args);
+        test_util::bt_backup_config(dir, &all_args)
+    }
+
+    #[test]
+    fn failed_operation_rolls_back_the_database() {
+        let dir = test_util::bt_backup_dir();
+        let config = config(&dir, &[]);
+        let mut storage = test_util::loaded_storage();
+
+        run_failing_operations(&mut storage, &config);
+
+        assert_unchanged(&storage);
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.metadata.as_deref(), Some(test_util::TORRENT));
+    }
+
+    #[test]
+    fn failed_operation_rolls_back_the_bt_backup_directory() {
+        let dir = test_util::bt_backup_dir();
+        let config = config(&dir, &["--use-fastresume"]);
+        let mut storage = BtBackupStorage::open(&config).unwrap();
+
+        run_failing_operations(&mut storage, &config);
+
+        assert_unchanged(&BtBackupStorage::open(&config).unwrap());
+        assert_eq!(
+            fs::read(dir.path().join(format!("{SEEDING_ID}.fastresume"))).unwrap(),
+            test_util::SEEDING
+        );
+        assert_eq!(
+            fs::read(dir.path().join(format!("{SEEDING_ID}.torrent"))).unwrap(),
+            test_util::TORRENT
+        );
+    }
+}
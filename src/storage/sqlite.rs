@@ -0,0 +1,377 @@
+//! torrents.db SQLite database storage backend
+
+use crate::common::database::DatabaseRow;
+use crate::db::schema::{Schema, ADDED_COLUMNS};
+use crate::storage::Storage;
+use rusqlite::{named_params, Connection, ToSql};
+use serde_bencode::value::Value;
+use serde_rusqlite::from_rows;
+use std::borrow::Cow;
+use std::error::Error;
+
+/// Columns qbfrt writes, besides the `torrent_id` key
+const WRITABLE_COLUMNS: [&str; 18] = [
+    "queue_position",
+    "name",
+    "category",
+    "tags",
+    "target_save_path",
+    "download_path",
+    "content_layout",
+    "ratio_limit",
+    "seeding_time_limit",
+    "inactive_seeding_time_limit",
+    "share_limit_action",
+    "has_outer_pieces_priority",
+    "has_seed_status",
+    "operating_mode",
+    "stopped",
+    "stop_condition",
+    "libtorrent_resume_data",
+    "metadata",
+];
+
+/// Storage backend for the torrents.db SQLite database
+///
+/// The statements are built from the columns the database actually has, so databases with an
+/// older schema version are read and written without touching columns they lack. A database
+/// downgraded to schema version 1 keeps the `download_path` in the resume data, see
+/// `schema::migrate_schema`. It is read into and written from `DatabaseRow::download_path`, so
+/// path rewrites also change it.
+pub struct SqliteStorage {
+    db: Connection,
+    schema: Schema,
+    columns: Vec<&'static str>,
+    download_path_key: Option<&'static str>,
+    update_sql: String,
+    insert_sql: String,
+}
+
+impl SqliteStorage {
+    /// Wraps an open database connection, see `DB::connect`
+    ///
+    /// Fails if the schema version of the database is not supported, see `Schema::read`.
+    ///
+    /// ## Example
+    /// ```rs
+    /// let storage = SqliteStorage::new(DB::connect(&config)?)?;
+    /// println!("Schema version {}", storage.schema().version);
+    /// ```
+    pub fn new(db: Connection) -> Result<SqliteStorage, Box<dyn Error>> {
+        let schema = Schema::read(&db)?;
+        let missing = schema.missing_columns();
+        if !missing.is_empty() {
+            eprintln!(
+                "Warning: torrents.db has schema version {}, but lacks the columns {missing:?}",
+                schema.version
+            );
+        }
+
+        let columns: Vec<&'static str> = WRITABLE_COLUMNS
+            .into_iter()
+            .filter(|column| schema.has_column(column))
+            .collect();
+        let download_path_key = ADDED_COLUMNS
+            .iter()
+            .find(|column| column.name == "download_path" && !schema.has_column(column.name))
+            .map(|column| column.resume_data_key);
+
+        let assignments = |value: &dyn Fn(&str) -> String| {
+            columns
+                .iter()
+                .map(|column| format!("{column} = {}", value(column)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let update_sql = format!(
+            "UPDATE torrents SET {} WHERE torrent_id = :torrent_id",
+            assignments(&|column| format!(":{column}"))
+        );
+        let insert_sql = format!(
+            "INSERT INTO torrents (torrent_id, {}) VALUES (:torrent_id, {}) \
+             ON CONFLICT (torrent_id) DO UPDATE SET {}",
+            columns.join(", "),
+            columns
+                .iter()
+                .map(|column| format!(":{column}"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            assignments(&|column| format!("excluded.{column}"))
+        );
+
+        Ok(SqliteStorage {
+            db,
+            schema,
+            columns,
+            download_path_key,
+            update_sql,
+            insert_sql,
+        })
+    }
+
+    /// Returns the underlying database connection
+    pub fn connection(&self) -> &Connection {
+        &self.db
+    }
+
+    /// Returns the schema of the database
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Returns the statement parameters for a torrent, limited to the columns of the database
+    fn params<'a>(&self, torrent: &'a DatabaseRow) -> Vec<(String, &'a dyn ToSql)> {
+        let values: [&dyn ToSql; 18] = [
+            &torrent.queue_position,
+            &torrent.name,
+            &torrent.category,
+            &torrent.tags,
+            &torrent.target_save_path,
+            &torrent.download_path,
+            &torrent.content_layout,
+            &torrent.ratio_limit,
+            &torrent.seeding_time_limit,
+            &torrent.inactive_seeding_time_limit,
+            &torrent.share_limit_action,
+            &torrent.has_outer_pieces_priority,
+            &torrent.has_seed_status,
+            &torrent.operating_mode,
+            &torrent.stopped,
+            &torrent.stop_condition,
+            &torrent.libtorrent_resume_data,
+            &torrent.metadata,
+        ];
+
+        let mut params: Vec<(String, &dyn ToSql)> =
+            vec![(String::from(":torrent_id"), &torrent.torrent_id)];
+        for (column, value) in WRITABLE_COLUMNS.into_iter().zip(values) {
+            if self.columns.contains(&column) {
+                params.push((format!(":{column}"), value));
+            }
+        }
+
+        params
+    }
+
+    /// Moves a `download_path` stashed in the resume data into the row
+    fn unstash_download_path(
+        &self,
+        mut torrent: DatabaseRow,
+    ) -> Result<DatabaseRow, Box<dyn Error>> {
+        let Some(key) = self.download_path_key else {
+            return Ok(torrent);
+        };
+        let Value::Dict(mut resume_data) =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?
+        else {
+            return Err(format!(
+                "libtorrent_resume_data of {} is not a dictionary",
+                torrent.torrent_id
+            )
+            .into());
+        };
+
+        if let Some(Value::Bytes(download_path)) = resume_data.remove(key.as_bytes()) {
+            torrent.download_path = Some(String::from_utf8(download_path)?);
+            torrent.libtorrent_resume_data = serde_bencode::to_bytes(&Value::Dict(resume_data))?;
+        }
+
+        Ok(torrent)
+    }
+
+    /// Stashes the `download_path` of a row in the resume data, if the database lacks the column
+    fn stash_download_path<'a>(
+        &self,
+        torrent: &'a DatabaseRow,
+    ) -> Result<Cow<'a, DatabaseRow>, Box<dyn Error>> {
+        let (Some(key), Some(download_path)) = (self.download_path_key, &torrent.download_path)
+        else {
+            return Ok(Cow::Borrowed(torrent));
+        };
+        let Value::Dict(mut resume_data) =
+            serde_bencode::from_bytes(&torrent.libtorrent_resume_data)?
+        else {
+            return Err(format!(
+                "libtorrent_resume_data of {} is not a dictionary",
+                torrent.torrent_id
+            )
+            .into());
+        };
+
+        resume_data.insert(
+            key.as_bytes().to_vec(),
+            Value::Bytes(download_path.clone().into_bytes()),
+        );
+        let mut torrent = torrent.clone();
+        torrent.libtorrent_resume_data = serde_bencode::to_bytes(&Value::Dict(resume_data))?;
+
+        Ok(Cow::Owned(torrent))
+    }
+
+    /// Runs a cached statement with the parameters of a torrent
+    fn execute(&self, sql: &str, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>> {
+        let torrent = self.stash_download_path(torrent)?;
+        let params = self.params(&torrent);
+        let params: Vec<(&str, &dyn ToSql)> = params
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        self.db.prepare_cached(sql)?.execute(params.as_slice())?;
+
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn torrents(&self) -> Result<Vec<DatabaseRow>, Box<dyn Error>> {
+        let mut stmt = self.db.prepare("SELECT * FROM torrents")?;
+        let mut torrents = Vec::new();
+        for torrent in from_rows::<DatabaseRow>(stmt.query([])?) {
+            torrents.push(self.unstash_download_path(torrent?)?);
+        }
+
+        Ok(torrents)
+    }
+
+    fn torrent(&self, torrent_id: &str) -> Result<Option<DatabaseRow>, Box<dyn Error>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT * FROM torrents WHERE torrent_id = :torrent_id")?;
+        let mut torrents =
+            from_rows::<DatabaseRow>(stmt.query(named_params! {":torrent_id": torrent_id})?);
+
+        match torrents.next().transpose()? {
+            Some(torrent) => Ok(Some(self.unstash_download_path(torrent)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn update(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>> {
+        self.execute(&self.update_sql, torrent)
+    }
+
+    fn insert(&mut self, torrent: &DatabaseRow) -> Result<(), Box<dyn Error>> {
+        self.execute(&self.insert_sql, torrent)
+    }
+
+    fn remove(&mut self, torrent_id: &str) -> Result<(), Box<dyn Error>> {
+        let mut delete_stmt = self
+            .db
+            .prepare_cached("DELETE FROM torrents WHERE torrent_id = :torrent_id")?;
+        delete_stmt.execute(named_params! {":torrent_id": torrent_id})?;
+
+        Ok(())
+    }
+
+    /// Starts an immediate transaction, so no other connection can write until it ends
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        self.db.execute_batch("BEGIN IMMEDIATE")?;
+
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+        self.db.execute_batch("COMMIT")?;
+
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), Box<dyn Error>> {
+        self.db.execute_batch("ROLLBACK")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, MAGNET, MAGNET_ID, SEEDING, SEEDING_ID, TORRENT};
+
+    #[test]
+    fn round_trip() {
+        let mut storage = test_util::loaded_storage();
+
+        let mut torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.category.as_deref(), Some("tv"));
+        assert_eq!(torrent.target_save_path.as_deref(), Some("/data/tv"));
+        assert_eq!(torrent.metadata.as_deref(), Some(TORRENT));
+        assert!(storage.torrent("0000").unwrap().is_none());
+
+        torrent.target_save_path = Some(String::from("/mnt/tv"));
+        storage.update(&torrent).unwrap();
+
+        let torrents = storage.torrents().unwrap();
+        assert_eq!(torrents.len(), 2);
+        let updated = torrents
+            .iter()
+            .find(|updated| updated.torrent_id == SEEDING_ID)
+            .unwrap();
+        assert_eq!(updated.target_save_path.as_deref(), Some("/mnt/tv"));
+        assert_eq!(
+            updated.libtorrent_resume_data,
+            torrent.libtorrent_resume_data
+        );
+    }
+
+    #[test]
+    fn magnet_links_keep_null_metadata() {
+        let mut storage = SqliteStorage::new(test_util::database()).unwrap();
+
+        storage
+            .insert(&test_util::torrent(MAGNET_ID, MAGNET, None))
+            .unwrap();
+
+        let torrent = storage.torrent(MAGNET_ID).unwrap().unwrap();
+        assert_eq!(torrent.metadata, None);
+        let null_metadata: bool = storage
+            .connection()
+            .query_row("SELECT metadata IS NULL FROM torrents", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(null_metadata);
+    }
+
+    #[test]
+    fn undecodable_rows_are_an_error() {
+        let storage = test_util::loaded_storage();
+        storage
+            .connection()
+            .execute("UPDATE torrents SET queue_position = 'first'", [])
+            .unwrap();
+
+        assert!(storage.torrents().is_err());
+    }
+
+    #[test]
+    fn rollback_discards_updates() {
+        let mut storage = test_util::loaded_storage();
+
+        storage.begin().unwrap();
+        let mut torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        torrent.target_save_path = Some(String::from("/mnt/tv"));
+        storage.update(&torrent).unwrap();
+        storage.rollback().unwrap();
+
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.target_save_path.as_deref(), Some("/data/tv"));
+    }
+
+    #[test]
+    fn skips_missing_columns() {
+        let mut storage = SqliteStorage::new(test_util::database_v1()).unwrap();
+        assert!(!storage.columns.contains(&"download_path"));
+
+        storage
+            .insert(&test_util::torrent(SEEDING_ID, SEEDING, Some(TORRENT)))
+            .unwrap();
+
+        let torrent = storage.torrent(SEEDING_ID).unwrap().unwrap();
+        assert_eq!(torrent.download_path, None);
+        assert_eq!(torrent.stop_condition, "None");
+        assert_eq!(torrent.inactive_seeding_time_limit, -2);
+        assert_eq!(torrent.category.as_deref(), Some("tv"));
+        assert_eq!(torrent.metadata.as_deref(), Some(TORRENT));
+    }
+}
@@ -0,0 +1,214 @@
+//! Fixtures shared by the unit tests
+
+use crate::common::database::DatabaseRow;
+use crate::common::fastresume::Fastresume;
+use crate::config::Config;
+use crate::db::load_db;
+use crate::storage::sqlite::SqliteStorage;
+use rusqlite::Connection;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Hash of the seeding torrent
+pub const SEEDING_ID: &str = "8aa3beb1cc516fceca8e5dad0b9ef8efee118e7b";
+/// .fastresume file of a seeding torrent with one renamed file and unknown keys
+pub const SEEDING: &[u8] =
+    include_bytes!("../tests/fixtures/8aa3beb1cc516fceca8e5dad0b9ef8efee118e7b.fastresume");
+/// .torrent file of the seeding torrent, two files in a `Show.S01` folder
+pub const TORRENT: &[u8] =
+    include_bytes!("../tests/fixtures/8aa3beb1cc516fceca8e5dad0b9ef8efee118e7b.torrent");
+/// .fastresume file of the seeding torrent while it was still downloading, with unfinished pieces
+pub const DOWNLOADING: &[u8] = include_bytes!("../tests/fixtures/downloading.fastresume");
+/// Hash of the torrent added by magnet link
+pub const MAGNET_ID: &str = "4ec486702bd5354308f383487bf9c973c0a62c95";
+/// .fastresume file of a torrent added by magnet link, without metadata
+pub const MAGNET: &[u8] =
+    include_bytes!("../tests/fixtures/4ec486702bd5354308f383487bf9c973c0a62c95.fastresume");
+
+/// Builds a database row from a .fastresume file
+pub fn torrent(torrent_id: &str, fastresume: &[u8], metadata: Option<&[u8]>) -> DatabaseRow {
+    let resume_data = serde_bencode::from_bytes(fastresume).unwrap();
+    let metadata = metadata.map(|metadata| metadata.to_vec());
+    DatabaseRow::from_fastresume(torrent_id.to_string(), resume_data, metadata, -1).unwrap()
+}
+
+/// Creates an empty in-memory torrents.db with the schema of qB 5.0
+pub fn database() -> Connection {
+    let db = Connection::open_in_memory().unwrap();
+    create_schema(&db);
+
+    db
+}
+
+/// Creates an empty in-memory torrents.db with schema version 1, before any columns were added
+pub fn database_v1() -> Connection {
+    let db = Connection::open_in_memory().unwrap();
+    db.execute_batch(
+        "CREATE TABLE meta (id INTEGER PRIMARY KEY, key TEXT NOT NULL UNIQUE, value BLOB);
+         INSERT INTO meta (key, value) VALUES ('version', 1);
+         CREATE TABLE torrents (id INTEGER PRIMARY KEY, torrent_id BLOB NOT NULL UNIQUE, \
+         queue_position INTEGER NOT NULL DEFAULT -1, name TEXT, category TEXT, tags TEXT, \
+         target_save_path TEXT, content_layout TEXT NOT NULL, ratio_limit INTEGER NOT NULL, \
+         seeding_time_limit INTEGER NOT NULL, has_outer_pieces_priority INTEGER NOT NULL, \
+         has_seed_status INTEGER NOT NULL, operating_mode TEXT NOT NULL, \
+         stopped INTEGER NOT NULL, libtorrent_resume_data BLOB NOT NULL, metadata BLOB);",
+    )
+    .unwrap();
+
+    db
+}
+
+/// Creates the tables of qB 5.0 in an empty database
+fn create_schema(db: &Connection) {
+    db.execute_batch(
+        "CREATE TABLE meta (id INTEGER PRIMARY KEY, key TEXT NOT NULL UNIQUE, value BLOB);
+         INSERT INTO meta (key, value) VALUES ('version', 8);
+         CREATE TABLE torrents (id INTEGER PRIMARY KEY, torrent_id BLOB NOT NULL UNIQUE, \
+         queue_position INTEGER NOT NULL DEFAULT -1, name TEXT, category TEXT, tags TEXT, \
+         target_save_path TEXT, download_path TEXT, content_layout TEXT NOT NULL, \
+         ratio_limit INTEGER NOT NULL, seeding_time_limit INTEGER NOT NULL, \
+         inactive_seeding_time_limit INTEGER NOT NULL DEFAULT -2, \
+         has_outer_pieces_priority INTEGER NOT NULL, has_seed_status INTEGER NOT NULL, \
+         operating_mode TEXT NOT NULL, stopped INTEGER NOT NULL, \
+         stop_condition TEXT NOT NULL DEFAULT `None`, libtorrent_resume_data BLOB NOT NULL, \
+         metadata BLOB, ssl_certificate TEXT, ssl_private_key TEXT, ssl_dh_params TEXT, \
+         share_limit_action TEXT NOT NULL DEFAULT `Default`);
+         CREATE INDEX torrents_torrent_id_INDEX ON torrents (torrent_id);",
+    )
+    .unwrap();
+}
+
+/// Creates a BT_backup directory with the seeding torrent and the magnet link
+///
+/// The magnet link comes first in the queue.
+pub fn bt_backup_dir() -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(format!("{SEEDING_ID}.fastresume")), SEEDING).unwrap();
+    fs::write(dir.path().join(format!("{SEEDING_ID}.torrent")), TORRENT).unwrap();
+    fs::write(dir.path().join(format!("{MAGNET_ID}.fastresume")), MAGNET).unwrap();
+    fs::write(
+        dir.path().join("queue"),
+        format!("{MAGNET_ID}\n{SEEDING_ID}\n"),
+    )
+    .unwrap();
+
+    dir
+}
+
+/// Returns the configuration for running against a BT_backup directory, with additional arguments
+pub fn bt_backup_config(dir: &TempDir, args: &[&str]) -> Config {
+    let mut all_args = vec!["--input-dir", dir.path().to_str().unwrap()];
+    all_args.extend(args);
+    Config::from_args(&all_args).unwrap()
+}
+
+/// Creates an in-memory torrents.db with the torrents of `bt_backup_dir` loaded into it
+pub fn loaded_storage() -> SqliteStorage {
+    let dir = bt_backup_dir();
+    let mut storage = SqliteStorage::new(database()).unwrap();
+    load_db::from_fastresume(&mut storage, &bt_backup_config(&dir, &[])).unwrap();
+
+    storage
+}
+
+/// Piece length of the torrents built by `content_torrent`
+/// Creates a qB config directory with a torrents.db holding the torrents of `bt_backup_dir`
+pub fn qb_dir() -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Connection::open(dir.path().join("torrents.db")).unwrap();
+    create_schema(&db);
+
+    let bt_backup_dir = bt_backup_dir();
+    let mut storage = SqliteStorage::new(db).unwrap();
+    load_db::from_fastresume(&mut storage, &bt_backup_config(&bt_backup_dir, &[])).unwrap();
+
+    dir
+}
+
+/// Returns the configuration for running against a `qb_dir`, with additional arguments
+pub fn qb_config(dir: &TempDir, args: &[&str]) -> Config {
+    let mut all_args = vec!["--config-dir", dir.path().to_str().unwrap()];
+    all_args.extend(args);
+    Config::from_args(&all_args).unwrap()
+}
+
+/// Piece length of a `content_torrent`
+pub const PIECE_LENGTH: usize = 16384;
+
+/// Builds a completed multi-file torrent in a `Content` folder, with piece hashes of the given files
+///
+/// The files are not written, so each test can leave some of them out or change them.
+pub fn content_torrent(save_path: &Path, files: &[(&str, &[u8])]) -> DatabaseRow {
+    let content: Vec<u8> = files
+        .iter()
+        .flat_map(|(_, data)| data.iter().copied())
+        .collect();
+    let pieces: Vec<u8> = content
+        .chunks(PIECE_LENGTH)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+    let dict = |entries: Vec<(&str, Value)>| {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    };
+    let files = files
+        .iter()
+        .map(|(path, data)| {
+            dict(vec![
+                ("length", Value::Int(data.len() as i64)),
+                (
+                    "path",
+                    Value::List(
+                        path.split('/')
+                            .map(|name| Value::Bytes(name.as_bytes().to_vec()))
+                            .collect(),
+                    ),
+                ),
+            ])
+        })
+        .collect();
+    let metadata = dict(vec![(
+        "info",
+        dict(vec![
+            ("files", Value::List(files)),
+            ("name", Value::Bytes(b"Content".to_vec())),
+            ("piece length", Value::Int(PIECE_LENGTH as i64)),
+            ("pieces", Value::Bytes(pieces)),
+        ]),
+    )]);
+
+    let mut resume_data: Fastresume = serde_bencode::from_bytes(SEEDING).unwrap();
+    resume_data.save_path = save_path.to_str().unwrap().as_bytes().to_vec();
+    resume_data.mapped_files = None;
+    resume_data.pieces = vec![1; content.len().div_ceil(PIECE_LENGTH)];
+    DatabaseRow::from_fastresume(
+        SEEDING_ID.to_string(),
+        resume_data,
+        Some(serde_bencode::to_bytes(&metadata).unwrap()),
+        -1,
+    )
+    .unwrap()
+}
+
+/// Returns `length` bytes of test content, different for each seed
+pub fn data(length: usize, seed: u8) -> Vec<u8> {
+    (0..length)
+        .map(|index| (index % 251) as u8 ^ seed.wrapping_mul(97))
+        .collect()
+}
+
+/// Writes the files of a `content_torrent` to its save path
+pub fn write_content(save_path: &Path, files: &[(&str, &[u8])]) {
+    for (path, data) in files {
+        let path = save_path.join("Content").join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+}